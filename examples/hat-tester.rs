@@ -749,8 +749,9 @@ fn scan(
     match result {
         ScanResponse::OK(results) => {
             let mut match_addr = false;
-            for addr in Regex::new("[0-9]{1,3}").unwrap().find_iter(&results) {
-                let addr = addr.as_str();
+            let re = Regex::new("<Address>([0-9]{1,3})</Address>").unwrap();
+            for addr in re.captures_iter(&results) {
+                let addr = &addr[1];
                 if log {
                     print!("{} ", addr);
                 }
@@ -0,0 +1,52 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Responses for the operations served by the router rather than the
+//! generated mbus_api server.  These follow the same pattern as mbus_api's
+//! responses, but carry structured documents which the router renders as
+//! XML or JSON.
+
+use crate::data;
+
+#[derive(Debug, PartialEq)]
+pub enum ScanSecondaryResponse {
+    OK(data::MBusScan),
+    BadRequest(String),
+    NotFound(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SelectResponse {
+    OK(data::MBusSelect),
+    BadRequest(String),
+    NotFound(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SetAddressResponse {
+    OK(data::MBusSetAddress),
+    BadRequest(String),
+    NotFound(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SwitchBaudrateResponse {
+    OK(data::MBusSwitchBaudrate),
+    BadRequest(String),
+    NotFound(String),
+}
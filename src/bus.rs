@@ -0,0 +1,874 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Native M-Bus master operations, built on the serial link layer.
+
+use mbus_api::models;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use log::debug;
+
+use crate::frame::{self, Frame};
+use crate::records::{self, VariableData};
+use crate::serial::{self, Serial};
+
+// Highest primary address a slave can be configured with
+pub const PRIMARY_ADDRESS_MAX: u8 = 250;
+
+// Secondary addresses: 8 ID digits, then manufacturer, version and medium
+pub const SECONDARY_LEN: usize = 16;
+const SECONDARY_ID_DIGITS: usize = 8;
+pub const SECONDARY_WILDCARD: &str = "FFFFFFFFFFFFFFFF";
+
+// CI field for selecting a slave by secondary address
+const CI_SELECT: u8 = 0x52;
+
+// CI field for sending data to a slave
+const CI_DATA_SEND: u8 = 0x51;
+
+// Data record writing a new primary address: 8 bit integer, VIF bus address
+const DIF_INT8: u8 = 0x01;
+const VIF_BUS_ADDRESS: u8 = 0x7A;
+
+// CI fields to switch a slave's baudrate, 300 to 38400 baud
+const CI_BAUDRATE_300: u8 = 0xB8;
+const BAUDRATES: [u32; 8] = [300, 600, 1200, 2400, 4800, 9600, 19200, 38400];
+
+/// The outcome of probing a single address.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Probe {
+    /// A single slave acknowledged
+    Ack,
+    /// Something answered, but the response was corrupt - most likely more
+    /// than one slave answered at once
+    Collision(String),
+    /// Nothing answered
+    None,
+}
+
+/// Lets a bus operation running on another thread be abandoned.  The bus
+/// checks it before each transaction, so a cancelled operation stops once
+/// any exchange in progress has completed.
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Returns a guard which cancels the operation when dropped.
+    pub fn guard(&self) -> CancelGuard {
+        CancelGuard(self.clone())
+    }
+}
+
+pub struct CancelGuard(Cancel);
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// An M-Bus master on a serial device.
+pub struct Bus {
+    serial: Serial,
+    cancel: Cancel,
+}
+
+impl Bus {
+    pub fn open<P: AsRef<Path>>(path: P, baudrate: &models::Baudrate) -> Result<Bus, String> {
+        let serial = Serial::open(path, baudrate).map_err(|e| e.to_string())?;
+        Ok(Bus {
+            serial,
+            cancel: Cancel::default(),
+        })
+    }
+
+    /// Stop sending frames once `cancel` is cancelled.
+    pub fn set_cancel(&mut self, cancel: Cancel) {
+        self.cancel = cancel;
+    }
+
+    // Send a frame and receive the response, unless cancelled
+    fn send_recv(&mut self, frame: &Frame) -> Result<Frame, serial::Error> {
+        if self.cancel.is_cancelled() {
+            return Err(serial::Error::Cancelled);
+        }
+        self.serial.send_recv(frame)
+    }
+
+    // Turn the result of an exchange expecting a single character ACK into
+    // a Probe.  Only an inability to use the serial port is an error.
+    fn probe(rsp: Result<Frame, serial::Error>) -> Result<Probe, String> {
+        match rsp {
+            Ok(Frame::Ack) => Ok(Probe::Ack),
+            Ok(f) => Ok(Probe::Collision(format!("Unexpected response: {:?}", f))),
+            Err(serial::Error::Timeout) => Ok(Probe::None),
+            Err(serial::Error::Frame(e)) => Ok(Probe::Collision(e)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Send SND_NKE to a primary address, to see whether a slave is there.
+    pub fn ping(&mut self, address: u8) -> Result<Probe, String> {
+        debug!("Ping primary address {}", address);
+        let rsp = self.send_recv(&Frame::Short {
+            control: frame::C_SND_NKE,
+            address,
+        });
+        Bus::probe(rsp)
+    }
+
+    // Send REQ_UD2 and check the response is an RSP_UD
+    fn request(&mut self, address: u8) -> Result<Frame, serial::Error> {
+        self.request_fcb(address, false)
+    }
+
+    // Send REQ_UD2 with the given frame count bit, and check the response is
+    // an RSP_UD
+    fn request_fcb(&mut self, address: u8, fcb: bool) -> Result<Frame, serial::Error> {
+        let control = if fcb {
+            frame::C_REQ_UD2 | frame::C_FCB
+        } else {
+            frame::C_REQ_UD2
+        };
+        let rsp = self.send_recv(&Frame::Short { control, address })?;
+        match rsp.control() {
+            Some(c) if c & frame::C_RSP_MASK == frame::C_RSP_UD => Ok(rsp),
+            _ => Err(serial::Error::Frame(format!(
+                "Unexpected response: {:?}",
+                rsp
+            ))),
+        }
+    }
+
+    /// Send REQ_UD2 to a primary address and decode the variable data
+    /// response.
+    pub fn request_data(&mut self, address: u8) -> Result<Reply, String> {
+        debug!("Request data from primary address {}", address);
+        let rsp = self.request(address).map_err(|e| e.to_string())?;
+        Reply::decode(&rsp)
+    }
+
+    /// Read up to `max_frames` frames of data from a slave at a primary or
+    /// secondary address, for as long as it says more records follow.  The
+    /// frame count bit is toggled for each frame after the first, so the
+    /// slave moves on to its next frame.
+    ///
+    /// Only a failure to get anything from the slave is an error - if a
+    /// later frame is missing or corrupt, it is returned as a failed
+    /// MultiFrame, and no more frames are requested.
+    pub fn request_frames(
+        &mut self,
+        address: &str,
+        max_frames: usize,
+    ) -> Result<Vec<MultiFrame>, String> {
+        let target = self.address(address)?;
+        if target != frame::ADDRESS_NETWORK_LAYER {
+            // Reset the slave's frame count bit, and start from its first
+            // frame
+            self.ping(target)?;
+        }
+
+        let mut frames: Vec<MultiFrame> = Vec::new();
+        let mut fcb = true;
+        while frames.len() < max_frames {
+            debug!("Request frame {} from address {}", frames.len(), address);
+            let reply = match self.request_fcb(target, fcb) {
+                Ok(rsp) => Reply::decode(&rsp),
+                Err(e @ serial::Error::Io(_)) | Err(e @ serial::Error::Cancelled) => {
+                    return Err(e.to_string())
+                }
+                Err(e) if frames.is_empty() => return Err(e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            let more = match reply {
+                Ok(ref r) => r.data.more_records_follow,
+                Err(_) => false,
+            };
+            frames.push(MultiFrame { fcb, reply });
+            if !more {
+                break;
+            }
+            fcb = !fcb;
+        }
+        Ok(frames)
+    }
+
+    /// Deselect any slave selected by secondary address.
+    pub fn deselect(&mut self) -> Result<(), String> {
+        debug!("Deselect secondary addresses");
+        Bus::probe(self.send_recv(&Frame::Short {
+            control: frame::C_SND_NKE,
+            address: frame::ADDRESS_NETWORK_LAYER,
+        }))
+        .map(|_| ())
+    }
+
+    /// Select the slave(s) matching a secondary address or mask, so they
+    /// can be addressed via the network layer address.
+    pub fn select(&mut self, mask: &str) -> Result<Probe, String> {
+        debug!("Select secondary address {}", mask);
+        let data = pack_secondary(mask)?;
+        let rsp = self.send_recv(&Frame::Long {
+            control: frame::C_SND_UD,
+            address: frame::ADDRESS_NETWORK_LAYER,
+            ci: CI_SELECT,
+            data,
+        });
+        match Bus::probe(rsp)? {
+            // Several simultaneous ACKs can look like one, perhaps followed
+            // by noise, so check the line has gone quiet
+            Probe::Ack if self.serial.discard() => Ok(Probe::Collision(
+                "Data received after acknowledgement".to_string(),
+            )),
+            probe => Ok(probe),
+        }
+    }
+
+    /// Resolve a primary or secondary address to the link layer address to
+    /// send to.  A secondary address is selected first, and must be
+    /// acknowledged by a single slave.
+    pub fn address(&mut self, address: &str) -> Result<u8, String> {
+        if address.len() != SECONDARY_LEN {
+            return address
+                .parse::<u8>()
+                .map_err(|_| format!("Invalid primary address: {}", address));
+        }
+        match self.select(address)? {
+            Probe::Ack => Ok(frame::ADDRESS_NETWORK_LAYER),
+            Probe::None => Err(format!("No slave selected by {}", address)),
+            Probe::Collision(e) => Err(format!("Collision selecting {}: {}", address, e)),
+        }
+    }
+
+    /// Ask a slave to switch baudrate, then switch the port to the new
+    /// baudrate and check the slave answers a data request at it.
+    pub fn switch_baudrate(&mut self, address: &str, bps: u32) -> Result<Reply, String> {
+        let ci = match BAUDRATES.iter().position(|b| *b == bps) {
+            Some(ii) => CI_BAUDRATE_300 + ii as u8,
+            None => return Err(format!("Unsupported baudrate: {}", bps)),
+        };
+        let target = self.address(address)?;
+        debug!("Switch address {} to {} baud", address, bps);
+        let rsp = self.send_recv(&Frame::Control {
+            control: frame::C_SND_UD,
+            address: target,
+            ci,
+        });
+        match Bus::probe(rsp)? {
+            Probe::Ack => (),
+            Probe::None => return Err("Slave did not acknowledge baudrate switch".to_string()),
+            Probe::Collision(e) => return Err(format!("Collision switching baudrate: {}", e)),
+        }
+
+        self.serial.set_bps(bps).map_err(|e| e.to_string())?;
+        // Reselect at the new baudrate, in case the slave forgot
+        let target = self.address(address)?;
+        self.request(target)
+            .map_err(|e| format!("No response at {} baud: {}", bps, e))
+            .and_then(|rsp| Reply::decode(&rsp))
+    }
+
+    /// Select a slave by secondary address and write a new primary address
+    /// to it, then check it answers a data request at that address.
+    pub fn set_primary_address(&mut self, secondary: &str, address: u8) -> Result<Reply, String> {
+        if address > PRIMARY_ADDRESS_MAX {
+            return Err(format!("Invalid primary address: {}", address));
+        }
+        let target = self.address(secondary)?;
+        debug!("Set primary address of {} to {}", secondary, address);
+        let rsp = self.send_recv(&Frame::Long {
+            control: frame::C_SND_UD,
+            address: target,
+            ci: CI_DATA_SEND,
+            data: vec![DIF_INT8, VIF_BUS_ADDRESS, address],
+        });
+        match Bus::probe(rsp)? {
+            Probe::Ack => (),
+            Probe::None => return Err("Slave did not acknowledge new address".to_string()),
+            Probe::Collision(e) => return Err(format!("Collision setting address: {}", e)),
+        }
+
+        let reply = self
+            .request(address)
+            .map_err(|e| format!("No response at address {}: {}", address, e))
+            .and_then(|rsp| Reply::decode(&rsp))?;
+        match reply.data.header.secondary_address() {
+            Some(ref s) if !s.eq_ignore_ascii_case(secondary) => {
+                Err(format!("Slave {} answered at address {}", s, address))
+            }
+            _ => Ok(reply),
+        }
+    }
+
+    /// Select by secondary address (or mask) and, if anything acknowledges,
+    /// request data from the selected slave to identify it.
+    pub fn probe_secondary(&mut self, mask: &str) -> Result<SecondaryProbe, String> {
+        match self.select(mask)? {
+            Probe::None => return Ok(SecondaryProbe::None),
+            Probe::Collision(e) => return Ok(SecondaryProbe::Collision(e)),
+            Probe::Ack => (),
+        }
+        match self.request(frame::ADDRESS_NETWORK_LAYER) {
+            Ok(rsp) => match Reply::decode(&rsp) {
+                Ok(reply) => Ok(SecondaryProbe::Single(reply)),
+                Err(e) => Ok(SecondaryProbe::Collision(e)),
+            },
+            Err(serial::Error::Timeout) => Ok(SecondaryProbe::None),
+            Err(serial::Error::Frame(e)) => Ok(SecondaryProbe::Collision(e)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Find all slaves whose secondary addresses match `mask`, where 'F'
+    /// is a wildcard, calling `on_probe` with each mask probed and its
+    /// outcome.  Wildcard ID digits are narrowed down one at a time wherever
+    /// more than one slave answers.
+    pub fn scan_secondary_with(
+        &mut self,
+        mask: &str,
+        on_probe: &mut dyn FnMut(&str, &SecondaryProbe),
+    ) -> Result<Vec<SecondaryFound>, String> {
+        let mut mask = mask.to_ascii_uppercase().into_bytes();
+        pack_secondary(&String::from_utf8_lossy(&mask))?;
+        self.deselect()?;
+        let mut found = Vec::new();
+        self.search(&mut mask, &mut found, on_probe)?;
+        Ok(found)
+    }
+
+    fn search(
+        &mut self,
+        mask: &mut [u8],
+        found: &mut Vec<SecondaryFound>,
+        on_probe: &mut dyn FnMut(&str, &SecondaryProbe),
+    ) -> Result<(), String> {
+        let mask_s = String::from_utf8_lossy(mask).to_string();
+        let probe = self.probe_secondary(&mask_s)?;
+        on_probe(&mask_s, &probe);
+        match probe {
+            SecondaryProbe::None => (),
+            SecondaryProbe::Single(reply) => {
+                debug!("Found slave matching {}", mask_s);
+                found.push(SecondaryFound::Slave(reply));
+            }
+            SecondaryProbe::Collision(e) => {
+                match mask[..SECONDARY_ID_DIGITS].iter().position(|c| *c == b'F') {
+                    Some(pos) => {
+                        for digit in b'0'..=b'9' {
+                            mask[pos] = digit;
+                            self.search(mask, found, on_probe)?;
+                        }
+                        mask[pos] = b'F';
+                    }
+                    // Nothing left to narrow down - slaves with the same ID
+                    None => found.push(SecondaryFound::Collision {
+                        mask: mask_s,
+                        error: e,
+                    }),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A decoded RSP_UD, with the link layer fields it arrived in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    /// The slave's primary address
+    pub address: u8,
+    pub control: u8,
+    pub data: VariableData,
+}
+
+impl Reply {
+    fn decode(rsp: &Frame) -> Result<Reply, String> {
+        Ok(Reply {
+            address: rsp.address().unwrap_or_default(),
+            control: rsp.control().unwrap_or_default(),
+            data: records::decode(rsp)?,
+        })
+    }
+}
+
+/// One frame of a multi-frame data request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiFrame {
+    /// The frame count bit the frame was requested with
+    pub fcb: bool,
+    /// The decoded frame, or why it couldn't be received or decoded
+    pub reply: Result<Reply, String>,
+}
+
+/// The outcome of selecting and requesting data by secondary address.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SecondaryProbe {
+    Single(Reply),
+    Collision(String),
+    None,
+}
+
+/// A result from a secondary address scan.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SecondaryFound {
+    Slave(Reply),
+    /// More than one slave matches a mask with no wildcard ID digits left
+    Collision {
+        mask: String,
+        error: String,
+    },
+}
+
+/// Check a 16 character secondary address, or mask with 'F' wildcards, and
+/// pack it into the 8 bytes sent in a select frame: ID (BCD, LSB first),
+/// manufacturer, version and medium.
+pub fn pack_secondary(address: &str) -> Result<Vec<u8>, String> {
+    let err = || format!("Invalid secondary address: {}", address);
+    if address.len() != SECONDARY_LEN || !address.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(err());
+    }
+    let id = &address[..SECONDARY_ID_DIGITS];
+    if !id
+        .chars()
+        .all(|c| c.is_ascii_digit() || c == 'F' || c == 'f')
+    {
+        return Err(err());
+    }
+    let byte = |ii: usize| u8::from_str_radix(&address[ii * 2..ii * 2 + 2], 16).map_err(|_| err());
+    Ok(vec![
+        byte(3)?,
+        byte(2)?,
+        byte(1)?,
+        byte(0)?,
+        byte(4)?,
+        byte(5)?,
+        byte(6)?,
+        byte(7)?,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::pty::openpty;
+    use nix::unistd;
+    use std::fs;
+    use std::os::unix::io::RawFd;
+    use std::thread::{self, JoinHandle};
+
+    // A bus on the slave side of a pseudo-terminal, with meters on the
+    // master side answering each frame the bus sends with the bytes
+    // `respond` returns.  The meters return the frames they were sent once
+    // the bus is closed.
+    fn bus<F>(bps: u32, respond: F) -> (Bus, JoinHandle<Vec<Frame>>)
+    where
+        F: FnMut(&Frame) -> Vec<u8> + Send + 'static,
+    {
+        let pty = openpty(None, None).unwrap();
+        let path = fs::read_link(format!("/proc/self/fd/{}", pty.slave)).unwrap();
+        let serial = Serial::open_bps(&path, bps).unwrap();
+        unistd::close(pty.slave).unwrap();
+        let meters = thread::spawn(move || meters(pty.master, respond));
+        let bus = Bus {
+            serial,
+            cancel: Cancel::default(),
+        };
+        (bus, meters)
+    }
+
+    fn meters<F: FnMut(&Frame) -> Vec<u8>>(master: RawFd, mut respond: F) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while let Some(frame) = recv(master) {
+            let rsp = respond(&frame);
+            frames.push(frame);
+            if !rsp.is_empty() {
+                unistd::write(master, &rsp).unwrap();
+            }
+        }
+        unistd::close(master).unwrap();
+        frames
+    }
+
+    // The next frame the bus sends, or None once it is closed
+    fn recv(master: RawFd) -> Option<Frame> {
+        let mut buf = Vec::new();
+        loop {
+            let mut byte = [0u8];
+            match unistd::read(master, &mut byte) {
+                Ok(1) => buf.push(byte[0]),
+                _ => return None,
+            }
+            if frame::length(&buf).unwrap() == Some(buf.len()) {
+                return Some(Frame::decode(&buf).unwrap());
+            }
+        }
+    }
+
+    // Close the bus, returning the frames it sent
+    fn sent(bus: Bus, meters: JoinHandle<Vec<Frame>>) -> Vec<Frame> {
+        drop(bus);
+        meters.join().unwrap()
+    }
+
+    // A corrupt frame header, as when several slaves answer at once
+    const GARBLED: &[u8] = &[frame::START_LONG, 0x03, 0x04, frame::START_LONG];
+
+    // A data response from a slave, with no records
+    fn rsp_ud(address: u8, secondary: &str) -> Vec<u8> {
+        let mut data = pack_secondary(secondary).unwrap();
+        data.extend(&[0x01, 0x00, 0x00, 0x00]);
+        Frame::Long {
+            control: frame::C_RSP_UD,
+            address,
+            ci: 0x72,
+            data,
+        }
+        .encode()
+        .unwrap()
+    }
+
+    // Whether a packed secondary address matches a packed mask, with 'F'
+    // digits matching anything
+    fn selects(mask: &[u8], address: &[u8]) -> bool {
+        mask.iter()
+            .zip(address)
+            .all(|(m, a)| [0xF0, 0x0F].iter().all(|n| m & n == *n || m & n == a & n))
+    }
+
+    // Slaves with these secondary addresses, which can be selected and asked
+    // for data through the network layer address
+    fn secondary(addresses: &'static [&'static str]) -> impl FnMut(&Frame) -> Vec<u8> + Send {
+        let mut selected = Vec::new();
+        move |frame| match frame {
+            Frame::Short {
+                control: frame::C_SND_NKE,
+                address: frame::ADDRESS_NETWORK_LAYER,
+            } => {
+                selected.clear();
+                vec![frame::ACK]
+            }
+            Frame::Long {
+                ci: CI_SELECT,
+                data,
+                ..
+            } => {
+                selected = addresses
+                    .iter()
+                    .filter(|a| selects(data, &pack_secondary(a).unwrap()))
+                    .collect::<Vec<_>>();
+                match selected.len() {
+                    0 => Vec::new(),
+                    _ => vec![frame::ACK],
+                }
+            }
+            Frame::Short {
+                control,
+                address: frame::ADDRESS_NETWORK_LAYER,
+            } if control & !frame::C_FCB == frame::C_REQ_UD2 => match selected.len() {
+                0 => Vec::new(),
+                1 => rsp_ud(0, selected[0]),
+                _ => GARBLED.to_vec(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    fn select(mask: &str) -> Frame {
+        Frame::Long {
+            control: frame::C_SND_UD,
+            address: frame::ADDRESS_NETWORK_LAYER,
+            ci: CI_SELECT,
+            data: pack_secondary(mask).unwrap(),
+        }
+    }
+
+    fn snd_nke(address: u8) -> Frame {
+        Frame::Short {
+            control: frame::C_SND_NKE,
+            address,
+        }
+    }
+
+    #[test]
+    fn ping() {
+        let (mut bus, meters) = bus(2400, |frame| match frame.address() {
+            Some(1) => vec![frame::ACK],
+            // A short frame with a bad checksum
+            Some(2) => vec![frame::START_SHORT, 0x08, 0x02, 0x00, frame::STOP],
+            _ => Vec::new(),
+        });
+        assert_eq!(bus.ping(1), Ok(Probe::Ack));
+        match bus.ping(2) {
+            Ok(Probe::Collision(e)) => assert!(e.starts_with("Checksum mismatch"), "{}", e),
+            other => panic!("Expected collision, got {:?}", other),
+        }
+        assert_eq!(bus.ping(3), Ok(Probe::None));
+
+        // Nothing is sent once cancelled
+        let cancel = Cancel::default();
+        bus.set_cancel(cancel.clone());
+        cancel.cancel();
+        assert!(bus.ping(1).is_err());
+        assert_eq!(sent(bus, meters), vec![snd_nke(1), snd_nke(2), snd_nke(3)]);
+    }
+
+    #[test]
+    fn scan_secondary_narrows_collisions() {
+        let (mut bus, meters) = bus(38400, secondary(&["1111111124400107", "2222222224400107"]));
+        let mut probes = Vec::new();
+        let found = bus
+            .scan_secondary_with(SECONDARY_WILDCARD, &mut |mask, probe| {
+                probes.push((mask.to_string(), probe.clone()))
+            })
+            .unwrap();
+
+        let slaves: Vec<_> = found
+            .iter()
+            .map(|found| match found {
+                SecondaryFound::Slave(reply) => reply.data.header.secondary_address().unwrap(),
+                other => panic!("Expected a slave, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(slaves, vec!["1111111124400107", "2222222224400107"]);
+
+        // The first ID digit is narrowed down, after which there is only
+        // one slave per mask
+        assert_eq!(probes.len(), 11);
+        assert_eq!(probes[0].0, SECONDARY_WILDCARD);
+        assert!(matches!(probes[0].1, SecondaryProbe::Collision(_)));
+        for (digit, (mask, probe)) in probes[1..].iter().enumerate() {
+            assert_eq!(*mask, format!("{}FFFFFFFFFFFFFFF", digit));
+            match digit {
+                1 | 2 => assert!(matches!(probe, SecondaryProbe::Single(_)), "{}", mask),
+                _ => assert_eq!(*probe, SecondaryProbe::None, "{}", mask),
+            }
+        }
+
+        let sent = sent(bus, meters);
+        assert_eq!(sent[0], snd_nke(frame::ADDRESS_NETWORK_LAYER));
+        assert_eq!(sent[1], select(SECONDARY_WILDCARD));
+    }
+
+    #[test]
+    fn scan_secondary_same_id() {
+        let (mut bus, meters) = bus(38400, secondary(&["1234567824400107", "1234567824400207"]));
+        let mut probes = 0;
+        let found = bus
+            .scan_secondary_with("12345678ffffffff", &mut |_, _| probes += 1)
+            .unwrap();
+        // No wildcard ID digits to narrow down
+        assert_eq!(probes, 1);
+        match &found[..] {
+            [SecondaryFound::Collision { mask, .. }] => assert_eq!(mask, "12345678FFFFFFFF"),
+            other => panic!("Expected a collision, got {:?}", other),
+        }
+        sent(bus, meters);
+    }
+
+    #[test]
+    fn scan_secondary_invalid_mask() {
+        let (mut bus, meters) = bus(38400, secondary(&[]));
+        assert!(bus
+            .scan_secondary_with("1234567A24400107", &mut |_, _| ())
+            .is_err());
+        assert!(bus.scan_secondary_with("FFFFFFFF", &mut |_, _| ()).is_err());
+        assert_eq!(sent(bus, meters), vec![]);
+    }
+
+    #[test]
+    fn secondary_addresses() {
+        assert_eq!(
+            pack_secondary("1234567824400107"),
+            Ok(vec![0x78, 0x56, 0x34, 0x12, 0x24, 0x40, 0x01, 0x07])
+        );
+        assert_eq!(pack_secondary(SECONDARY_WILDCARD), Ok(vec![0xFF; 8]));
+        assert_eq!(
+            pack_secondary("1234567f2440ffff"),
+            Ok(vec![0x7F, 0x56, 0x34, 0x12, 0x24, 0x40, 0xFF, 0xFF])
+        );
+        for invalid in &[
+            "",
+            "123456782440010",
+            "12345678244001070",
+            "1234567A24400107",
+            "123456782440010G",
+            "+234567824400107",
+        ] {
+            assert!(pack_secondary(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn select_detects_collisions() {
+        let (mut bus, meters) = bus(2400, |frame| match frame.data().first() {
+            Some(0x01) => vec![frame::ACK],
+            // Several slaves acknowledging a little apart
+            Some(0x02) => vec![frame::ACK, frame::ACK],
+            Some(0x03) => GARBLED.to_vec(),
+            _ => Vec::new(),
+        });
+        assert_eq!(bus.select("00000001FFFFFFFF"), Ok(Probe::Ack));
+        assert_eq!(
+            bus.select("00000002FFFFFFFF"),
+            Ok(Probe::Collision(
+                "Data received after acknowledgement".to_string()
+            ))
+        );
+        assert!(matches!(
+            bus.select("00000003FFFFFFFF"),
+            Ok(Probe::Collision(_))
+        ));
+        assert_eq!(bus.select("00000004FFFFFFFF"), Ok(Probe::None));
+        assert!(bus.select("0000000AFFFFFFFF").is_err());
+        assert_eq!(
+            sent(bus, meters),
+            vec![
+                select("00000001FFFFFFFF"),
+                select("00000002FFFFFFFF"),
+                select("00000003FFFFFFFF"),
+                select("00000004FFFFFFFF"),
+            ]
+        );
+    }
+
+    #[test]
+    fn address() {
+        let (mut bus, meters) = bus(38400, secondary(&["1234567824400107"]));
+        assert_eq!(bus.address("5"), Ok(5));
+        assert!(bus.address("256").is_err());
+        assert!(bus.address("abc").is_err());
+        assert_eq!(
+            bus.address("1234567824400107"),
+            Ok(frame::ADDRESS_NETWORK_LAYER)
+        );
+        assert!(bus.address("1234567724400107").is_err());
+        // Primary addresses need nothing sent
+        assert_eq!(sent(bus, meters).len(), 2);
+    }
+
+    fn req_ud2(address: u8) -> Frame {
+        Frame::Short {
+            control: frame::C_REQ_UD2,
+            address,
+        }
+    }
+
+    #[test]
+    fn switch_baudrate() {
+        let (mut bus, meters) = bus(2400, |frame| match frame {
+            Frame::Control { address: 5, .. } => vec![frame::ACK],
+            Frame::Short { address: 5, .. } => rsp_ud(5, "1234567824400107"),
+            _ => Vec::new(),
+        });
+        let reply = bus.switch_baudrate("5", 9600).unwrap();
+        assert_eq!(reply.address, 5);
+        assert!(bus.switch_baudrate("5", 1234).is_err());
+        assert!(bus.switch_baudrate("6", 300).is_err());
+
+        let switch = |address, ci| Frame::Control {
+            control: frame::C_SND_UD,
+            address,
+            ci,
+        };
+        assert_eq!(
+            sent(bus, meters),
+            vec![switch(5, 0xBD), req_ud2(5), switch(6, 0xB8)]
+        );
+    }
+
+    #[test]
+    fn switch_baudrate_secondary() {
+        let (mut bus, meters) = bus(2400, |frame| match frame {
+            Frame::Long { ci: CI_SELECT, .. } | Frame::Control { .. } => vec![frame::ACK],
+            Frame::Short { .. } => rsp_ud(7, "1234567824400107"),
+            _ => Vec::new(),
+        });
+        let reply = bus.switch_baudrate("1234567824400107", 38400).unwrap();
+        assert_eq!(
+            reply.data.header.secondary_address(),
+            Some("1234567824400107".to_string())
+        );
+
+        // The slave is selected again at the new baudrate
+        let select = select("1234567824400107");
+        let switch = Frame::Control {
+            control: frame::C_SND_UD,
+            address: frame::ADDRESS_NETWORK_LAYER,
+            ci: 0xBF,
+        };
+        assert_eq!(
+            sent(bus, meters),
+            vec![
+                select.clone(),
+                switch,
+                select,
+                req_ud2(frame::ADDRESS_NETWORK_LAYER)
+            ]
+        );
+    }
+
+    // A slave which takes whatever primary address it is sent, and a
+    // different slave already at address 9
+    fn set_address(frame: &Frame) -> Vec<u8> {
+        match frame {
+            Frame::Long { .. } => vec![frame::ACK],
+            Frame::Short { address: 9, .. } => rsp_ud(9, "8765432124400107"),
+            Frame::Short { address, .. } => rsp_ud(*address, "1234567824400107"),
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn set_primary_address() {
+        let (mut bus, meters) = bus(2400, set_address);
+        let reply = bus.set_primary_address("1234567824400107", 8).unwrap();
+        assert_eq!(reply.address, 8);
+        assert_eq!(
+            sent(bus, meters),
+            vec![
+                select("1234567824400107"),
+                Frame::Long {
+                    control: frame::C_SND_UD,
+                    address: frame::ADDRESS_NETWORK_LAYER,
+                    ci: CI_DATA_SEND,
+                    data: vec![DIF_INT8, VIF_BUS_ADDRESS, 8],
+                },
+                req_ud2(8),
+            ]
+        );
+    }
+
+    #[test]
+    fn set_primary_address_checked() {
+        let (mut bus, meters) = bus(2400, set_address);
+        assert!(bus.set_primary_address("1234567824400107", 251).is_err());
+        let e = bus.set_primary_address("1234567824400107", 9).unwrap_err();
+        assert_eq!(e, "Slave 8765432124400107 answered at address 9");
+        assert_eq!(sent(bus, meters).len(), 3);
+    }
+}
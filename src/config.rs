@@ -0,0 +1,823 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! The optional TOML config file, given by --config or CONFIG_FILE.  Each
+//! setting which has an environment variable is only taken from the file if
+//! the variable isn't set, so the environment overrides the file.  Settings
+//! for individual buses are only in the file.
+//!
+//! The file is read again on SIGHUP, or when asked to by the admin endpoint,
+//! applying the settings which can change while running.  The environment
+//! is only ever read after startup.
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::sync::{Mutex, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+
+use mbus_api::models;
+
+use crate::data;
+use crate::http;
+use crate::influx;
+use crate::mqtt;
+use crate::registry;
+use crate::webhooks;
+
+const CONFIG_ARG: &str = "--config";
+const CONFIG_FILE_VAR: &str = "CONFIG_FILE";
+const DEV_PREFIX: &str = "/dev/";
+const BUSES_KEY: &str = "buses";
+
+// Settings read by httpd-util, which only reads the environment, so these
+// are copied into it from the file at startup
+const HTTPD_UTIL: &[&str] = &["SERVER_IP", "SERVER_PORT", "SSL_CERT", "SSL_KEY"];
+// Set for httpd-util to serve HTTPS, if the file gives a certificate
+const HTTPS_VAR: &str = "HTTPS";
+
+// Settings which are only read at startup, so a change to them in the file
+// needs a restart
+const RESTART_REQUIRED: &[&str] = &[
+    "SERVER_IP",
+    "SERVER_PORT",
+    "SSL_CERT",
+    "SSL_KEY",
+    "QUEUE_LENGTH",
+    "QUEUE_RETRY_AFTER",
+    "HAT_GPIO",
+    "METERS_FILE",
+    "HISTORY_FILE",
+    "WEBHOOKS_FILE",
+];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    server: Server,
+    #[serde(default)]
+    libmbus: Libmbus,
+    /// Defaults for all buses
+    #[serde(default)]
+    bus: BusDefaults,
+    #[serde(default)]
+    buses: Vec<Bus>,
+    #[serde(default)]
+    hat: Hat,
+    #[serde(default)]
+    meters: Meters,
+    #[serde(default)]
+    history: History,
+    #[serde(default)]
+    jobs: Jobs,
+    #[serde(default)]
+    mqtt: Mqtt,
+    #[serde(default)]
+    influx: Influx,
+    #[serde(default)]
+    webhooks: Webhooks,
+    #[serde(default)]
+    metrics: Metrics,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Server {
+    ip: Option<String>,
+    port: Option<u16>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    api_token: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Libmbus {
+    path: Option<String>,
+    get: Option<String>,
+    library_path: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BusDefaults {
+    operation_timeout: Option<u64>,
+    queue_length: Option<usize>,
+    queue_retry_after: Option<u64>,
+}
+
+/// Settings for one bus, overriding the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bus {
+    pub device: String,
+    /// Baudrate of meters registered on this bus without one
+    pub baudrate: Option<u32>,
+    pub operation_timeout: Option<u64>,
+    pub poll_retries: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Hat {
+    gpio: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Meters {
+    file: Option<String>,
+    poll_jitter: Option<u64>,
+    poll_retries: Option<u32>,
+    poll_retry_delay: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct History {
+    file: Option<String>,
+    retention: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Jobs {
+    retention: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Mqtt {
+    broker: Option<String>,
+    port: Option<u16>,
+    client_id: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    ca_file: Option<String>,
+    topic: Option<String>,
+    event_topic: Option<String>,
+    qos: Option<u8>,
+    retain: Option<bool>,
+    discovery: Option<bool>,
+    discovery_prefix: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Influx {
+    url: Option<String>,
+    token: Option<String>,
+    measurement: Option<String>,
+    batch_size: Option<usize>,
+    batch_interval: Option<u64>,
+    retries: Option<u32>,
+    retry_delay: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Webhooks {
+    file: Option<String>,
+    timeout: Option<u64>,
+    retries: Option<u32>,
+    retry_delay: Option<u64>,
+    dead_letters: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Metrics {
+    record_values: Option<bool>,
+}
+
+lazy_static! {
+    /// The config file's current settings, empty if there isn't one
+    static ref CONFIG: RwLock<Config> = RwLock::new(Config::default());
+    /// Settings for individual buses, by device
+    static ref BUSES: RwLock<BTreeMap<String, Bus>> = RwLock::new(BTreeMap::new());
+    /// The config file loaded at startup, if any
+    static ref PATH: Mutex<Option<String>> = Mutex::new(None);
+    /// Held for the whole of a reload, so reloads don't interleave
+    static ref RELOAD: Mutex<()> = Mutex::new(());
+}
+
+fn string<T: ToString>(value: &Option<T>) -> Option<String> {
+    value.as_ref().map(|v| v.to_string())
+}
+
+impl Config {
+    // Each setting in the file, with its environment variable and key
+    fn settings(&self) -> Vec<(&'static str, &'static str, Option<String>)> {
+        vec![
+            ("SERVER_IP", "server.ip", string(&self.server.ip)),
+            ("SERVER_PORT", "server.port", string(&self.server.port)),
+            ("SSL_CERT", "server.tls_cert", string(&self.server.tls_cert)),
+            ("SSL_KEY", "server.tls_key", string(&self.server.tls_key)),
+            (
+                "API_TOKEN",
+                "server.api_token",
+                string(&self.server.api_token),
+            ),
+            ("LIBMBUS_PATH", "libmbus.path", string(&self.libmbus.path)),
+            ("LIBMBUS_GET", "libmbus.get", string(&self.libmbus.get)),
+            (
+                "LD_LIBRARY_PATH",
+                "libmbus.library_path",
+                string(&self.libmbus.library_path),
+            ),
+            (
+                "OPERATION_TIMEOUT",
+                "bus.operation_timeout",
+                string(&self.bus.operation_timeout),
+            ),
+            (
+                "QUEUE_LENGTH",
+                "bus.queue_length",
+                string(&self.bus.queue_length),
+            ),
+            (
+                "QUEUE_RETRY_AFTER",
+                "bus.queue_retry_after",
+                string(&self.bus.queue_retry_after),
+            ),
+            ("HAT_GPIO", "hat.gpio", string(&self.hat.gpio)),
+            ("METERS_FILE", "meters.file", string(&self.meters.file)),
+            (
+                "POLL_JITTER",
+                "meters.poll_jitter",
+                string(&self.meters.poll_jitter),
+            ),
+            (
+                "POLL_RETRIES",
+                "meters.poll_retries",
+                string(&self.meters.poll_retries),
+            ),
+            (
+                "POLL_RETRY_DELAY",
+                "meters.poll_retry_delay",
+                string(&self.meters.poll_retry_delay),
+            ),
+            ("HISTORY_FILE", "history.file", string(&self.history.file)),
+            (
+                "HISTORY_RETENTION",
+                "history.retention",
+                string(&self.history.retention),
+            ),
+            (
+                "JOB_RETENTION",
+                "jobs.retention",
+                string(&self.jobs.retention),
+            ),
+            ("MQTT_BROKER", "mqtt.broker", string(&self.mqtt.broker)),
+            ("MQTT_PORT", "mqtt.port", string(&self.mqtt.port)),
+            (
+                "MQTT_CLIENT_ID",
+                "mqtt.client_id",
+                string(&self.mqtt.client_id),
+            ),
+            (
+                "MQTT_USERNAME",
+                "mqtt.username",
+                string(&self.mqtt.username),
+            ),
+            (
+                "MQTT_PASSWORD",
+                "mqtt.password",
+                string(&self.mqtt.password),
+            ),
+            ("MQTT_CA_FILE", "mqtt.ca_file", string(&self.mqtt.ca_file)),
+            ("MQTT_TOPIC", "mqtt.topic", string(&self.mqtt.topic)),
+            (
+                "MQTT_EVENT_TOPIC",
+                "mqtt.event_topic",
+                string(&self.mqtt.event_topic),
+            ),
+            ("MQTT_QOS", "mqtt.qos", string(&self.mqtt.qos)),
+            ("MQTT_RETAIN", "mqtt.retain", string(&self.mqtt.retain)),
+            (
+                "MQTT_DISCOVERY",
+                "mqtt.discovery",
+                string(&self.mqtt.discovery),
+            ),
+            (
+                "MQTT_DISCOVERY_PREFIX",
+                "mqtt.discovery_prefix",
+                string(&self.mqtt.discovery_prefix),
+            ),
+            ("INFLUX_URL", "influx.url", string(&self.influx.url)),
+            ("INFLUX_TOKEN", "influx.token", string(&self.influx.token)),
+            (
+                "INFLUX_MEASUREMENT",
+                "influx.measurement",
+                string(&self.influx.measurement),
+            ),
+            (
+                "INFLUX_BATCH_SIZE",
+                "influx.batch_size",
+                string(&self.influx.batch_size),
+            ),
+            (
+                "INFLUX_BATCH_INTERVAL",
+                "influx.batch_interval",
+                string(&self.influx.batch_interval),
+            ),
+            (
+                "INFLUX_RETRIES",
+                "influx.retries",
+                string(&self.influx.retries),
+            ),
+            (
+                "INFLUX_RETRY_DELAY",
+                "influx.retry_delay",
+                string(&self.influx.retry_delay),
+            ),
+            (
+                "WEBHOOKS_FILE",
+                "webhooks.file",
+                string(&self.webhooks.file),
+            ),
+            (
+                "WEBHOOK_TIMEOUT",
+                "webhooks.timeout",
+                string(&self.webhooks.timeout),
+            ),
+            (
+                "WEBHOOK_RETRIES",
+                "webhooks.retries",
+                string(&self.webhooks.retries),
+            ),
+            (
+                "WEBHOOK_RETRY_DELAY",
+                "webhooks.retry_delay",
+                string(&self.webhooks.retry_delay),
+            ),
+            (
+                "WEBHOOK_DEAD_LETTERS",
+                "webhooks.dead_letters",
+                string(&self.webhooks.dead_letters),
+            ),
+            (
+                "METRICS_RECORD_VALUES",
+                "metrics.record_values",
+                string(&self.metrics.record_values),
+            ),
+        ]
+    }
+
+    // The file's value of the setting with this environment variable
+    fn setting(&self, var: &str) -> Option<String> {
+        self.settings()
+            .into_iter()
+            .find(|(v, _, _)| *v == var)
+            .and_then(|(_, _, value)| value)
+    }
+
+    // A setting, from its environment variable or else the file
+    fn var(&self, var: &str) -> Option<String> {
+        env::var(var).ok().or_else(|| self.setting(var))
+    }
+
+    // Check the settings which their types don't, and tidy up bus devices
+    fn check(&mut self) -> Result<(), String> {
+        if self.server.tls_cert.is_some() != self.server.tls_key.is_some() {
+            return Err("server.tls_cert and server.tls_key must be set together".to_string());
+        }
+        if let Some(qos) = self.mqtt.qos {
+            if qos > 2 {
+                return Err(format!("mqtt.qos must be 0, 1 or 2, not {}", qos));
+            }
+        }
+        for (name, value) in &[
+            ("bus.queue_length", self.bus.queue_length),
+            ("influx.batch_size", self.influx.batch_size),
+        ] {
+            if *value == Some(0) {
+                return Err(format!("{} must be at least 1", name));
+            }
+        }
+        let mut devices = Vec::new();
+        for bus in &mut self.buses {
+            if bus.device.starts_with(DEV_PREFIX) {
+                bus.device = bus.device[DEV_PREFIX.len()..].to_string();
+            }
+            if bus.device.is_empty() {
+                return Err("buses.device must be set".to_string());
+            }
+            let path = http::bus_path(&bus.device);
+            if devices.contains(&path) {
+                return Err(format!("Bus {} is configured twice", bus.device));
+            }
+            if let Some(baudrate) = bus.baudrate {
+                baudrate
+                    .to_string()
+                    .parse::<models::Baudrate>()
+                    .map_err(|_| {
+                        format!("Invalid baudrate for bus {}: {}", bus.device, baudrate)
+                    })?;
+            }
+            devices.push(path);
+        }
+        Ok(())
+    }
+}
+
+// The config file given on the command line, or by CONFIG_FILE
+fn path() -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == CONFIG_ARG {
+            return args.next();
+        }
+        if let Some(path) = arg.strip_prefix(&format!("{}=", CONFIG_ARG)) {
+            return Some(path.to_string());
+        }
+    }
+    env::var(CONFIG_FILE_VAR).ok()
+}
+
+/// Read and check a config file.
+pub fn read(path: &str) -> Result<Config, String> {
+    let toml = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let mut config: Config =
+        toml::from_str(&toml).map_err(|e| format!("Invalid config file {}: {}", path, e))?;
+    config
+        .check()
+        .map_err(|e| format!("Invalid config file {}: {}", path, e))?;
+    Ok(config)
+}
+
+/// Load the config file, if there is one, returning its path, then check
+/// the settings from it and the environment.  This must happen at startup,
+/// before any settings are read.
+pub fn load() -> Result<Option<String>, String> {
+    let path = match path() {
+        Some(path) => path,
+        None => {
+            http::check()?;
+            return Ok(None);
+        }
+    };
+    let mut config = read(&path)?;
+    for (var, _, value) in config.settings() {
+        if HTTPD_UTIL.contains(&var) && env::var_os(var).is_none() {
+            if let Some(value) = value {
+                env::set_var(var, value);
+            }
+        }
+    }
+    if config.server.tls_cert.is_some() && env::var_os(HTTPS_VAR).is_none() {
+        env::set_var(HTTPS_VAR, "1");
+    }
+    *BUSES.write().unwrap() = buses(config.buses.drain(..).collect());
+    *CONFIG.write().unwrap() = config;
+    *PATH.lock().unwrap() = Some(path.clone());
+    http::check()?;
+    Ok(Some(path))
+}
+
+/// A setting, from its environment variable or else the config file.
+pub fn var(var: &str) -> Option<String> {
+    CONFIG.read().unwrap().var(var)
+}
+
+fn buses(buses: Vec<Bus>) -> BTreeMap<String, Bus> {
+    buses
+        .into_iter()
+        .map(|bus| (bus.device.clone(), bus))
+        .collect()
+}
+
+// The changes between two versions of the config file, ignoring settings
+// the environment overrides, and the environment variables of the changed
+// settings which can be applied without a restart
+fn changes(
+    old: &Config,
+    old_buses: &BTreeMap<String, Bus>,
+    new: &Config,
+    new_buses: &BTreeMap<String, Bus>,
+) -> (data::MBusReload, Vec<&'static str>) {
+    let mut reload = data::MBusReload::default();
+    let mut changed = Vec::new();
+    for ((var, key, old), (_, _, value)) in old.settings().into_iter().zip(new.settings()) {
+        if env::var_os(var).is_some() || old == value {
+            continue;
+        }
+        if RESTART_REQUIRED.contains(&var) {
+            reload.restart_required.push(key.to_string());
+            continue;
+        }
+        reload.applied.push(key.to_string());
+        changed.push(var);
+    }
+    if old_buses != new_buses {
+        reload.applied.push(BUSES_KEY.to_string());
+    }
+    (reload, changed)
+}
+
+// Whether the MQTT connection is set up from a changed setting
+fn restart_mqtt(changed: &[&str]) -> bool {
+    changed.iter().any(|var| var.starts_with("MQTT_"))
+}
+
+// Whether the InfluxDB export is set up from a changed setting
+fn restart_influx(changed: &[&str]) -> bool {
+    changed.contains(&"INFLUX_URL")
+}
+
+/// Read the config file loaded at startup again, and apply the changes to
+/// it which don't need a restart, then reload the meters and webhooks.  The
+/// environment still overrides the file.  Fails, changing nothing, if the
+/// config file can't be read or is invalid.
+pub fn reload() -> Result<data::MBusReload, String> {
+    let _reloading = RELOAD.lock().unwrap();
+    let path = match *PATH.lock().unwrap() {
+        Some(ref path) => path.clone(),
+        None => return Err("No config file was loaded".to_string()),
+    };
+    let mut config = read(&path)?;
+    let buses = buses(config.buses.drain(..).collect());
+    let (mut reload, changed) = {
+        let mut old = CONFIG.write().unwrap();
+        // Meters pick up their bus's baudrate when loaded, so the buses are
+        // replaced first
+        let mut old_buses = BUSES.write().unwrap();
+        let changes = changes(&old, &old_buses, &config, &buses);
+        *old_buses = buses;
+        *old = config;
+        changes
+    };
+    http::reload();
+
+    // Problems with the rest don't undo what has already been applied, so
+    // are reported alongside it
+    reload.meters = match registry::reload() {
+        Ok(count) => count,
+        Err(e) => {
+            reload.errors.push(e);
+            registry::list().len()
+        }
+    };
+    reload.webhooks = match webhooks::load() {
+        Ok(count) => count,
+        Err(e) => {
+            reload.errors.push(e);
+            webhooks::list().len()
+        }
+    };
+    // The connections are only set up when started
+    if restart_mqtt(&changed) {
+        if let Err(e) = mqtt::start() {
+            reload.errors.push(format!("Failed to restart MQTT: {}", e));
+        }
+    }
+    if restart_influx(&changed) {
+        if let Err(e) = influx::start() {
+            reload
+                .errors
+                .push(format!("Failed to restart InfluxDB export: {}", e));
+        }
+    }
+
+    info!("Reloaded config from {}", path);
+    for key in &reload.restart_required {
+        warn!("Restart to apply the change to {}", key);
+    }
+    for e in &reload.errors {
+        warn!("{}", e);
+    }
+    Ok(reload)
+}
+
+/// Reload the config file whenever a SIGHUP is received.
+pub fn start() -> Result<(), String> {
+    let mut hangups =
+        signal(SignalKind::hangup()).map_err(|e| format!("Failed to handle SIGHUP: {}", e))?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if let Err(e) = reload() {
+                warn!("Failed to reload config: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The settings for a bus, if it has any.  A bus configured by one name
+/// for its device, like serial0, applies when it's used by another, like
+/// ttyAMA0.
+pub fn bus(device: &str) -> Option<Bus> {
+    let device = device.strip_prefix(DEV_PREFIX).unwrap_or(device);
+    let buses = BUSES.read().unwrap();
+    if let Some(bus) = buses.get(device) {
+        return Some(bus.clone());
+    }
+    let path = http::bus_path(device);
+    buses
+        .values()
+        .find(|bus| http::bus_path(&bus.device) == path)
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn parse(toml: &str) -> Result<Config, String> {
+        let mut config: Config = toml::from_str(toml).map_err(|e| e.to_string())?;
+        config.check()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn settings() {
+        let config = parse(
+            r#"
+            [server]
+            port = 8443
+            [bus]
+            queue_length = 4
+            [mqtt]
+            retain = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.setting("SERVER_PORT"), Some("8443".to_string()));
+        assert_eq!(config.setting("QUEUE_LENGTH"), Some("4".to_string()));
+        assert_eq!(config.setting("MQTT_RETAIN"), Some("true".to_string()));
+        assert_eq!(config.setting("MQTT_QOS"), None);
+        assert_eq!(config.setting("NO_SUCH_VAR"), None);
+    }
+
+    #[test]
+    fn unknown_keys() {
+        assert!(parse("[server]\nport = 8080\nhost = \"localhost\"").is_err());
+        assert!(parse("[serverr]\nport = 8080").is_err());
+        assert!(parse("[[buses]]\ndevice = \"ttyAMA0\"\nbaud = 2400").is_err());
+        assert!(parse("[mqtt]\nqos = \"1\"").is_err());
+    }
+
+    #[test]
+    fn checks() {
+        assert!(parse("[mqtt]\nqos = 2").is_ok());
+        assert!(parse("[mqtt]\nqos = 3").is_err());
+        assert!(parse("[bus]\nqueue_length = 0").is_err());
+        assert!(parse("[influx]\nbatch_size = 0").is_err());
+        assert!(parse("[server]\ntls_cert = \"cert.pem\"").is_err());
+        assert!(parse("[server]\ntls_key = \"key.pem\"").is_err());
+        assert!(parse("[server]\ntls_cert = \"cert.pem\"\ntls_key = \"key.pem\"").is_ok());
+    }
+
+    #[test]
+    fn buses() {
+        let config = parse(
+            r#"
+            [[buses]]
+            device = "/dev/ttyAMA0"
+            baudrate = 9600
+            [[buses]]
+            device = "ttyUSB0"
+            "#,
+        )
+        .unwrap();
+        let devices: Vec<_> = config.buses.iter().map(|bus| bus.device.as_str()).collect();
+        assert_eq!(devices, vec!["ttyAMA0", "ttyUSB0"]);
+
+        let duplicate = "[[buses]]\ndevice = \"ttyAMA0\"\n[[buses]]\ndevice = \"/dev/ttyAMA0\"";
+        assert!(parse(duplicate).is_err());
+        assert!(parse("[[buses]]\ndevice = \"\"").is_err());
+        assert!(parse("[[buses]]\ndevice = \"ttyAMA0\"\nbaudrate = 1234").is_err());
+    }
+
+    // Read a config file written to a temporary file
+    fn file(toml: &str) -> Config {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "mbus-httpd-{}-{}.toml",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = env::temp_dir().join(name);
+        fs::write(&path, toml).unwrap();
+        let config = read(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        config.unwrap()
+    }
+
+    fn diff(old: &str, new: &str) -> (data::MBusReload, Vec<&'static str>) {
+        let mut old = file(old);
+        let mut new = file(new);
+        let old_buses = super::buses(old.buses.drain(..).collect());
+        let new_buses = super::buses(new.buses.drain(..).collect());
+        changes(&old, &old_buses, &new, &new_buses)
+    }
+
+    #[test]
+    fn reload_changes() {
+        let old = r#"
+            [server]
+            port = 8080
+            [bus]
+            queue_length = 8
+            operation_timeout = 60
+            [meters]
+            poll_jitter = 5
+            [mqtt]
+            broker = "localhost"
+            [[buses]]
+            device = "ttyAMA0"
+            baudrate = 2400
+            "#;
+        let (reload, changed) = diff(old, old);
+        assert_eq!(reload, data::MBusReload::default());
+        assert!(changed.is_empty());
+
+        let new = r#"
+            [server]
+            port = 8081
+            [bus]
+            queue_length = 16
+            operation_timeout = 120
+            [mqtt]
+            broker = "localhost"
+            qos = 1
+            [influx]
+            url = "http://localhost:8086/write?db=mbus"
+            [[buses]]
+            device = "ttyAMA0"
+            baudrate = 9600
+            "#;
+        let (reload, changed) = diff(old, new);
+        assert_eq!(
+            reload.applied,
+            vec![
+                "bus.operation_timeout",
+                "meters.poll_jitter",
+                "mqtt.qos",
+                "influx.url",
+                "buses",
+            ]
+        );
+        assert_eq!(
+            reload.restart_required,
+            vec!["server.port", "bus.queue_length"]
+        );
+        assert_eq!(
+            changed,
+            vec!["OPERATION_TIMEOUT", "POLL_JITTER", "MQTT_QOS", "INFLUX_URL"]
+        );
+        assert!(restart_mqtt(&changed));
+        assert!(restart_influx(&changed));
+    }
+
+    #[test]
+    fn reload_restarts() {
+        let (_, changed) = diff("[mqtt]\nretain = true", "[influx]\ntoken = \"t\"");
+        assert!(restart_mqtt(&changed));
+        assert!(!restart_influx(&changed));
+
+        let (reload, changed) = diff("", "[[buses]]\ndevice = \"ttyUSB0\"");
+        assert_eq!(reload.applied, vec!["buses"]);
+        assert!(!restart_mqtt(&changed));
+        assert!(!restart_influx(&changed));
+    }
+
+    #[test]
+    fn reload_skips_env() {
+        env::set_var("WEBHOOK_TIMEOUT", "10");
+        let (reload, changed) = diff("[webhooks]\ntimeout = 5", "[webhooks]\ntimeout = 20");
+        env::remove_var("WEBHOOK_TIMEOUT");
+        assert_eq!(reload, data::MBusReload::default());
+        assert!(changed.is_empty());
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let config = parse("[libmbus]\nget = \"from-file\"").unwrap();
+        assert_eq!(config.var("LIBMBUS_GET"), Some("from-file".to_string()));
+        env::set_var("LIBMBUS_GET", "from-env");
+        assert_eq!(config.var("LIBMBUS_GET"), Some("from-env".to_string()));
+        env::remove_var("LIBMBUS_GET");
+        assert_eq!(config.var("LIBMBUS_GET"), Some("from-file".to_string()));
+    }
+}
@@ -0,0 +1,970 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Structured M-Bus response documents, using the same element names as
+//! libmbus' XML output so they can be rendered as either XML or JSON.
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::records;
+
+const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MBusData {
+    #[serde(rename = "SlaveInformation")]
+    pub slave_information: SlaveInformation,
+    #[serde(rename = "DataRecord", default)]
+    pub data_records: Vec<DataRecord>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SlaveInformation {
+    #[serde(rename = "Id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "Manufacturer", skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(rename = "Version", skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(rename = "ProductName", skip_serializing_if = "Option::is_none")]
+    pub product_name: Option<String>,
+    #[serde(rename = "Medium", skip_serializing_if = "Option::is_none")]
+    pub medium: Option<String>,
+    #[serde(rename = "AccessNumber", skip_serializing_if = "Option::is_none")]
+    pub access_number: Option<String>,
+    #[serde(rename = "Status", skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(rename = "Signature", skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl MBusData {
+    /// An ID for the slave which was read at `address` - its secondary
+    /// address if that can be worked out, otherwise its ID or the address.
+    pub fn secondary_id(&self, address: &str) -> String {
+        let info = &self.slave_information;
+        info.secondary_address()
+            .or_else(|| info.id.clone())
+            .unwrap_or_else(|| address.to_string())
+    }
+}
+
+impl SlaveInformation {
+    /// The 16 hex digit secondary address of the slave, if its header gave
+    /// enough to work it out.
+    pub fn secondary_address(&self) -> Option<String> {
+        let medium = self.medium.as_ref()?;
+        let header = records::SlaveInformation {
+            id: self.id.clone(),
+            manufacturer: self.manufacturer.clone(),
+            version: self.version.as_ref()?.parse::<u8>().ok(),
+            medium: (0..=0xFF).find(|m| records::medium_name(*m) == medium),
+            access_number: 0,
+            status: 0,
+            signature: 0,
+        };
+        header.secondary_address()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DataRecord {
+    // Attributes in libmbus' XML
+    #[serde(rename = "id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "frame", skip_serializing_if = "Option::is_none")]
+    pub frame: Option<String>,
+
+    #[serde(rename = "Function", skip_serializing_if = "Option::is_none")]
+    pub function: Option<String>,
+    #[serde(rename = "StorageNumber", skip_serializing_if = "Option::is_none")]
+    pub storage_number: Option<String>,
+    #[serde(rename = "Tariff", skip_serializing_if = "Option::is_none")]
+    pub tariff: Option<String>,
+    #[serde(rename = "Device", skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(rename = "Unit", skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(rename = "Value", skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(rename = "Timestamp", skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+}
+
+impl From<&records::VariableData> for MBusData {
+    /// Describe natively decoded data the way libmbus does.
+    fn from(data: &records::VariableData) -> Self {
+        let header = &data.header;
+        let slave_information = SlaveInformation {
+            id: header.id.clone(),
+            manufacturer: header.manufacturer.clone(),
+            version: header.version.map(|v| v.to_string()),
+            product_name: None,
+            medium: header.medium_name().map(|m| m.to_string()),
+            access_number: Some(header.access_number.to_string()),
+            status: Some(format!("{:02X}", header.status)),
+            signature: Some(format!("{:04X}", header.signature)),
+        };
+        let data_records = data
+            .records
+            .iter()
+            .enumerate()
+            .map(|(ii, record)| DataRecord {
+                id: Some(ii.to_string()),
+                function: Some(record.function.name().to_string()),
+                storage_number: Some(record.storage_number.to_string()),
+                tariff: Some(record.tariff.to_string()).filter(|_| record.tariff != 0),
+                device: Some(record.subunit.to_string()).filter(|_| record.subunit != 0),
+                unit: Some(record_unit(record)),
+                value: record_value(record),
+                ..Default::default()
+            })
+            .collect();
+        MBusData {
+            slave_information,
+            data_records,
+        }
+    }
+}
+
+// The prefix libmbus gives a unit for a VIF's exponent
+fn unit_prefix(exponent: i32) -> String {
+    match exponent {
+        0 => "".to_string(),
+        -3 => "m".to_string(),
+        -6 => "my".to_string(),
+        1 => "10 ".to_string(),
+        2 => "100 ".to_string(),
+        3 => "k".to_string(),
+        4 => "10 k".to_string(),
+        5 => "100 k".to_string(),
+        6 => "M".to_string(),
+        9 => "G".to_string(),
+        exponent => format!("1e{} ", exponent),
+    }
+}
+
+// A record's quantity and unit, with the exponent in the unit as libmbus
+// gives it, e.g. "Energy (10 Wh)", so the value is left raw
+fn record_unit(record: &records::DataRecord) -> String {
+    if record.unit.is_empty() {
+        return record.quantity.clone();
+    }
+    let prefix = unit_prefix(record.exponent);
+    // So "m m^3" doesn't read as cubic millimetres
+    let space =
+        if !prefix.is_empty() && !prefix.ends_with(' ') && record.unit.contains(&['^', ' '][..]) {
+            " "
+        } else {
+            ""
+        };
+    format!("{} ({}{}{})", record.quantity, prefix, space, record.unit)
+}
+
+// A record's raw value, as libmbus gives it
+fn record_value(record: &records::DataRecord) -> Option<String> {
+    match &record.value {
+        records::Value::None => None,
+        records::Value::Integer(i) => Some(i.to_string()),
+        records::Value::Real(r) => Some(r.to_string()),
+        records::Value::Date(d) => Some(d.format("%Y-%m-%d").to_string()),
+        records::Value::DateTime(dt) => Some(dt.format("%Y-%m-%dT%H:%M:%S").to_string()),
+        records::Value::String(s) => Some(s.clone()),
+        records::Value::Bytes(b) => Some(b.iter().map(|b| format!("{:02X}", b)).collect()),
+    }
+}
+
+/// The frames read from a slave by a multi-frame data request.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MBusMultiReply {
+    #[serde(rename = "Frame", default)]
+    pub frames: Vec<MultiReplyFrame>,
+    /// Set if the last frame was received and said no more records follow,
+    /// rather than the read stopping at maxframes or on an error
+    #[serde(rename = "Complete")]
+    pub complete: bool,
+}
+
+impl MBusMultiReply {
+    /// The data from all the frames as a single reading, with the data
+    /// records numbered across the frames.
+    pub fn merged(&self) -> MBusData {
+        let mut all = MBusData::default();
+        for (ii, data) in self
+            .frames
+            .iter()
+            .filter_map(|f| f.data.as_ref())
+            .enumerate()
+        {
+            if ii == 0 {
+                all.slave_information = data.slave_information.clone();
+            }
+            for record in &data.data_records {
+                all.data_records.push(DataRecord {
+                    id: Some(all.data_records.len().to_string()),
+                    ..record.clone()
+                });
+            }
+        }
+        all
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MultiReplyFrame {
+    #[serde(rename = "Index")]
+    pub index: usize,
+    /// The frame count bit the frame was requested with
+    #[serde(rename = "Fcb")]
+    pub fcb: bool,
+    /// The slave flagged (DIF 0x1F) that it has more records to send
+    #[serde(rename = "MoreRecordsFollow")]
+    pub more_records_follow: bool,
+    /// FRAME_OK if the frame was received and decoded, otherwise FRAME_ERROR
+    #[serde(rename = "Status")]
+    pub status: String,
+    #[serde(rename = "Error", skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(rename = "MBusData", skip_serializing_if = "Option::is_none")]
+    pub data: Option<MBusData>,
+}
+
+pub const FRAME_OK: &str = "OK";
+pub const FRAME_ERROR: &str = "Error";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MBusScan {
+    #[serde(rename = "Slave", default)]
+    pub slaves: Vec<ScanSlave>,
+}
+
+/// A slave which answered during a primary or secondary address scan.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScanSlave {
+    /// Primary address.  For a secondary scan, the address the slave
+    /// reported, or none for an unresolved collision.
+    #[serde(rename = "Address", skip_serializing_if = "Option::is_none")]
+    pub address: Option<u8>,
+    /// When the slave answered, RFC 3339
+    #[serde(rename = "Time")]
+    pub time: String,
+    // From the slave's response to a follow-up REQ_UD2, if it gave one
+    #[serde(rename = "SecondaryAddress", skip_serializing_if = "Option::is_none")]
+    pub secondary_address: Option<String>,
+    #[serde(rename = "Id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "Manufacturer", skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(rename = "Medium", skip_serializing_if = "Option::is_none")]
+    pub medium: Option<String>,
+    /// Set if the response was corrupt, e.g. because several slaves share
+    /// the address
+    #[serde(rename = "Collision", skip_serializing_if = "Option::is_none")]
+    pub collision: Option<String>,
+}
+
+impl ScanSlave {
+    /// Fill in the slave's identity from its variable data header.
+    pub fn identify(&mut self, header: &records::SlaveInformation) {
+        self.secondary_address = header.secondary_address();
+        self.id = header.id.clone();
+        self.manufacturer = header.manufacturer.clone();
+        self.medium = header.medium_name().map(|m| m.to_string());
+    }
+}
+
+/// The result of selecting a slave by secondary address.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MBusSelect {
+    #[serde(rename = "SecondaryAddress")]
+    pub secondary_address: String,
+    /// One of SELECT_SINGLE, SELECT_NONE or SELECT_COLLISION
+    #[serde(rename = "Result")]
+    pub result: String,
+    #[serde(rename = "Collision", skip_serializing_if = "Option::is_none")]
+    pub collision: Option<String>,
+}
+
+pub const SELECT_SINGLE: &str = "Single";
+pub const SELECT_NONE: &str = "None";
+pub const SELECT_COLLISION: &str = "Collision";
+
+/// The result of asking a slave to switch baudrate.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MBusSwitchBaudrate {
+    #[serde(rename = "Address")]
+    pub address: String,
+    #[serde(rename = "Baudrate")]
+    pub baudrate: String,
+    #[serde(rename = "NewBaudrate")]
+    pub new_baudrate: String,
+    /// RESULT_SUCCESS if the slave answered at the new baudrate, otherwise
+    /// RESULT_FAILURE
+    #[serde(rename = "Result")]
+    pub result: String,
+    #[serde(rename = "Error", skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub const RESULT_SUCCESS: &str = "Success";
+pub const RESULT_FAILURE: &str = "Failure";
+
+/// The result of setting a slave's primary address.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MBusSetAddress {
+    #[serde(rename = "SecondaryAddress")]
+    pub secondary_address: String,
+    /// The new primary address
+    #[serde(rename = "Address")]
+    pub address: u8,
+    /// RESULT_SUCCESS if the slave answered at the new address, otherwise
+    /// RESULT_FAILURE
+    #[serde(rename = "Result")]
+    pub result: String,
+    #[serde(rename = "Error", skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The outcome of probing one address during a scan, streamed to clients
+/// as the scan goes.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScanProbe {
+    /// The primary address probed, for a primary scan
+    #[serde(rename = "Address", skip_serializing_if = "Option::is_none")]
+    pub address: Option<u8>,
+    /// The address mask probed, for a secondary scan
+    #[serde(rename = "SecondaryAddress", skip_serializing_if = "Option::is_none")]
+    pub secondary_address: Option<String>,
+    /// One of PROBE_NONE, PROBE_RESPONSE or PROBE_COLLISION
+    #[serde(rename = "Result")]
+    pub result: String,
+    /// Describes the corrupt response, for a collision
+    #[serde(rename = "Error", skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub const PROBE_NONE: &str = "None";
+pub const PROBE_RESPONSE: &str = "Response";
+pub const PROBE_COLLISION: &str = "Collision";
+
+/// A request to run an operation as a background job.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JobRequest {
+    /// One of the JOB_ operations
+    #[serde(rename = "Operation")]
+    pub operation: String,
+    #[serde(rename = "Device")]
+    pub device: String,
+    #[serde(rename = "Baudrate")]
+    pub baudrate: String,
+    /// Slave address, for get and getMulti
+    #[serde(rename = "Address", default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Maximum frames to read, for getMulti
+    #[serde(rename = "MaxFrames", default, skip_serializing_if = "Option::is_none")]
+    pub max_frames: Option<i32>,
+    /// Secondary address mask, for scan-secondary
+    #[serde(rename = "Mask", default, skip_serializing_if = "Option::is_none")]
+    pub mask: Option<String>,
+    /// "interactive" (the default) or "scheduled"
+    #[serde(rename = "Priority", default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<String>,
+}
+
+pub const JOB_SCAN: &str = "scan";
+pub const JOB_SCAN_SECONDARY: &str = "scan-secondary";
+pub const JOB_GET: &str = "get";
+pub const JOB_GET_MULTI: &str = "getMulti";
+
+/// How far a job has got.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JobProgress {
+    /// Primary addresses, or secondary address masks, probed so far
+    #[serde(rename = "AddressesProbed")]
+    pub addresses_probed: u32,
+    #[serde(rename = "DevicesFound")]
+    pub devices_found: u32,
+}
+
+/// The document a job produced - the same as the equivalent request
+/// would have returned.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum JobResult {
+    Scan(MBusScan),
+    Data(MBusData),
+    MultiReply(MBusMultiReply),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MBusJob {
+    #[serde(rename = "Id")]
+    pub id: String,
+    #[serde(rename = "Request")]
+    pub request: JobRequest,
+    /// One of the JOB_ states
+    #[serde(rename = "State")]
+    pub state: String,
+    /// When the job was created, started and finished, RFC 3339
+    #[serde(rename = "Created")]
+    pub created: String,
+    #[serde(rename = "Started", skip_serializing_if = "Option::is_none")]
+    pub started: Option<String>,
+    #[serde(rename = "Finished", skip_serializing_if = "Option::is_none")]
+    pub finished: Option<String>,
+    #[serde(rename = "Progress")]
+    pub progress: JobProgress,
+    #[serde(rename = "Result", skip_serializing_if = "Option::is_none")]
+    pub result: Option<JobResult>,
+    #[serde(rename = "Error", skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub const JOB_QUEUED: &str = "Queued";
+pub const JOB_RUNNING: &str = "Running";
+pub const JOB_COMPLETED: &str = "Completed";
+pub const JOB_FAILED: &str = "Failed";
+pub const JOB_CANCELLED: &str = "Cancelled";
+
+/// A meter in the registry, so it can be read by name.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Meter {
+    #[serde(rename = "Name", default)]
+    pub name: String,
+    #[serde(rename = "Device")]
+    pub device: String,
+    /// Defaults to the baudrate of the meter's bus in the config file
+    #[serde(rename = "Baudrate", default)]
+    pub baudrate: String,
+    /// Primary or secondary address
+    #[serde(rename = "Address")]
+    pub address: String,
+    /// Where the meter is, e.g. building and floor
+    #[serde(rename = "Tags", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    /// AES-128 key for encrypted data, as 32 hex digits.  Never returned
+    /// by the API.
+    #[serde(rename = "Key", default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Poll the meter every this many seconds
+    #[serde(
+        rename = "PollInterval",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub poll_interval: Option<u64>,
+    /// Or poll the meter on a cron schedule, with seconds, e.g.
+    /// "0 */15 * * * *"
+    #[serde(
+        rename = "PollSchedule",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub poll_schedule: Option<String>,
+}
+
+impl Meter {
+    /// A copy which is safe to return to clients.
+    pub fn redacted(&self) -> Meter {
+        Meter {
+            key: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// How background polling of a meter is going.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MBusPollStatus {
+    #[serde(rename = "Meter")]
+    pub meter: String,
+    /// When the meter will next be polled, RFC 3339
+    #[serde(rename = "NextPoll", skip_serializing_if = "Option::is_none")]
+    pub next_poll: Option<String>,
+    #[serde(rename = "LastReading", skip_serializing_if = "Option::is_none")]
+    pub last_reading: Option<MBusData>,
+    #[serde(rename = "LastReadingTime", skip_serializing_if = "Option::is_none")]
+    pub last_reading_time: Option<String>,
+    #[serde(rename = "LastError", skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(rename = "LastErrorTime", skip_serializing_if = "Option::is_none")]
+    pub last_error_time: Option<String>,
+    /// Polls which have failed since the last successful one
+    #[serde(rename = "Failures")]
+    pub failures: u32,
+}
+
+/// A value read from a meter, as kept in the reading history.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    /// When the meter was read, RFC 3339
+    #[serde(rename = "Time")]
+    pub time: String,
+    /// The index of the data record in the meter's response
+    #[serde(rename = "Id")]
+    pub id: u32,
+    #[serde(rename = "Function", skip_serializing_if = "Option::is_none")]
+    pub function: Option<String>,
+    #[serde(rename = "StorageNumber", skip_serializing_if = "Option::is_none")]
+    pub storage_number: Option<String>,
+    #[serde(rename = "Unit", skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(rename = "Value", skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MBusHistory {
+    #[serde(rename = "Meter")]
+    pub meter: String,
+    #[serde(rename = "Record", default)]
+    pub records: Vec<HistoryRecord>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MBusMeters {
+    #[serde(rename = "Meter", default)]
+    pub meters: Vec<Meter>,
+}
+
+/// A URL which is sent events as they happen.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Webhook {
+    /// Assigned when the webhook is registered
+    #[serde(rename = "Id", default)]
+    pub id: String,
+    #[serde(rename = "Url")]
+    pub url: String,
+    /// Key deliveries are signed with, using HMAC-SHA256.  Never returned by
+    /// the API.
+    #[serde(rename = "Secret", default, skip_serializing_if = "String::is_empty")]
+    pub secret: String,
+    /// Events to send, or all of them if empty
+    #[serde(rename = "Events", default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<String>,
+}
+
+impl Webhook {
+    /// A copy which is safe to return to clients.
+    pub fn redacted(&self) -> Webhook {
+        Webhook {
+            secret: String::new(),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MBusWebhooks {
+    #[serde(rename = "Webhook", default)]
+    pub webhooks: Vec<Webhook>,
+}
+
+/// What is posted to a webhook.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WebhookEvent {
+    #[serde(rename = "Id")]
+    pub id: String,
+    #[serde(rename = "Event")]
+    pub event: String,
+    /// When the event happened, RFC 3339
+    #[serde(rename = "Time")]
+    pub time: String,
+    #[serde(rename = "Data")]
+    pub data: serde_json::Value,
+}
+
+/// An event which couldn't be delivered to a webhook.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeadLetter {
+    #[serde(rename = "Webhook")]
+    pub webhook: String,
+    #[serde(rename = "Url")]
+    pub url: String,
+    #[serde(rename = "Attempts")]
+    pub attempts: u32,
+    /// Why the last attempt failed
+    #[serde(rename = "Error")]
+    pub error: String,
+    /// When the last attempt failed, RFC 3339
+    #[serde(rename = "Failed")]
+    pub failed: String,
+    #[serde(rename = "Payload")]
+    pub payload: WebhookEvent,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MBusDeadLetters {
+    #[serde(rename = "DeadLetter")]
+    pub dead_letters: Vec<DeadLetter>,
+}
+
+/// The outcome of reloading the config file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MBusReload {
+    /// Config file keys whose changes were applied
+    #[serde(rename = "Applied")]
+    pub applied: Vec<String>,
+    /// Config file keys whose changes only take effect on restart
+    #[serde(rename = "RestartRequired")]
+    pub restart_required: Vec<String>,
+    #[serde(rename = "Meters")]
+    pub meters: usize,
+    #[serde(rename = "Webhooks")]
+    pub webhooks: usize,
+    /// Problems applying the changes
+    #[serde(rename = "Errors")]
+    pub errors: Vec<String>,
+}
+
+/// Parse the XML libmbus outputs for a data request.
+pub fn from_xml(xml: &str) -> Result<MBusData, String> {
+    serde_xml_rs::from_str(xml).map_err(|e| format!("Failed to parse M-Bus XML: {}", e))
+}
+
+/// Parse the XML output by scan_to_xml().
+pub fn scan_from_xml(xml: &str) -> Result<MBusScan, String> {
+    serde_xml_rs::from_str(xml).map_err(|e| format!("Failed to parse scan XML: {}", e))
+}
+
+/// Parse the XML output by multi_reply_to_xml().
+pub fn multi_reply_from_xml(xml: &str) -> Result<MBusMultiReply, String> {
+    serde_xml_rs::from_str(xml).map_err(|e| format!("Failed to parse multi reply XML: {}", e))
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Write <name>value</name> on its own line, if there is a value
+fn xml_element<T: ToString>(xml: &mut String, indent: usize, name: &str, value: &Option<T>) {
+    if let Some(value) = value {
+        xml.push_str(&format!(
+            "{:indent$}<{name}>{}</{name}>\n",
+            "",
+            xml_escape(&value.to_string()),
+            indent = indent,
+            name = name
+        ));
+    }
+}
+
+pub fn scan_to_xml(scan: &MBusScan) -> String {
+    let mut xml = XML_HEADER.to_string();
+    xml.push_str("<MBusScan>\n");
+    for slave in &scan.slaves {
+        xml.push_str("    <Slave>\n");
+        xml_element(&mut xml, 8, "Address", &slave.address);
+        xml_element(&mut xml, 8, "Time", &Some(&slave.time));
+        xml_element(&mut xml, 8, "SecondaryAddress", &slave.secondary_address);
+        xml_element(&mut xml, 8, "Id", &slave.id);
+        xml_element(&mut xml, 8, "Manufacturer", &slave.manufacturer);
+        xml_element(&mut xml, 8, "Medium", &slave.medium);
+        xml_element(&mut xml, 8, "Collision", &slave.collision);
+        xml.push_str("    </Slave>\n");
+    }
+    xml.push_str("</MBusScan>\n");
+    xml
+}
+
+// Write an MBusData document's elements, as libmbus would
+fn data_to_xml(xml: &mut String, indent: usize, data: &MBusData) {
+    let info = &data.slave_information;
+    xml.push_str(&format!(
+        "{:indent$}<SlaveInformation>\n",
+        "",
+        indent = indent
+    ));
+    let inner = indent + 4;
+    xml_element(xml, inner, "Id", &info.id);
+    xml_element(xml, inner, "Manufacturer", &info.manufacturer);
+    xml_element(xml, inner, "Version", &info.version);
+    xml_element(xml, inner, "ProductName", &info.product_name);
+    xml_element(xml, inner, "Medium", &info.medium);
+    xml_element(xml, inner, "AccessNumber", &info.access_number);
+    xml_element(xml, inner, "Status", &info.status);
+    xml_element(xml, inner, "Signature", &info.signature);
+    xml.push_str(&format!(
+        "{:indent$}</SlaveInformation>\n",
+        "",
+        indent = indent
+    ));
+    for record in &data.data_records {
+        let id = match &record.id {
+            Some(id) => format!(" id=\"{}\"", xml_escape(id)),
+            None => String::new(),
+        };
+        xml.push_str(&format!(
+            "{:indent$}<DataRecord{}>\n",
+            "",
+            id,
+            indent = indent
+        ));
+        xml_element(xml, inner, "Function", &record.function);
+        xml_element(xml, inner, "StorageNumber", &record.storage_number);
+        xml_element(xml, inner, "Tariff", &record.tariff);
+        xml_element(xml, inner, "Device", &record.device);
+        xml_element(xml, inner, "Unit", &record.unit);
+        xml_element(xml, inner, "Value", &record.value);
+        xml_element(xml, inner, "Timestamp", &record.timestamp);
+        xml.push_str(&format!("{:indent$}</DataRecord>\n", "", indent = indent));
+    }
+}
+
+pub fn multi_reply_to_xml(multi: &MBusMultiReply) -> String {
+    let mut xml = XML_HEADER.to_string();
+    xml.push_str("<MBusMultiReply>\n");
+    for frame in &multi.frames {
+        xml.push_str("    <Frame>\n");
+        xml_element(&mut xml, 8, "Index", &Some(frame.index));
+        xml_element(&mut xml, 8, "Fcb", &Some(frame.fcb));
+        xml_element(
+            &mut xml,
+            8,
+            "MoreRecordsFollow",
+            &Some(frame.more_records_follow),
+        );
+        xml_element(&mut xml, 8, "Status", &Some(&frame.status));
+        xml_element(&mut xml, 8, "Error", &frame.error);
+        if let Some(data) = &frame.data {
+            xml.push_str("        <MBusData>\n");
+            data_to_xml(&mut xml, 12, data);
+            xml.push_str("        </MBusData>\n");
+        }
+        xml.push_str("    </Frame>\n");
+    }
+    xml_element(&mut xml, 4, "Complete", &Some(multi.complete));
+    xml.push_str("</MBusMultiReply>\n");
+    xml
+}
+
+pub fn to_json<T: serde::Serialize>(data: &T) -> Result<String, String> {
+    serde_json::to_string(data).map_err(|e| format!("Failed to serialize JSON: {}", e))
+}
+
+/// Parse a meter posted as JSON.
+pub fn meter_from_json(json: &[u8]) -> Result<Meter, String> {
+    serde_json::from_slice(json).map_err(|e| format!("Invalid meter: {}", e))
+}
+
+/// Parse a webhook posted as JSON.
+pub fn webhook_from_json(json: &[u8]) -> Result<Webhook, String> {
+    serde_json::from_slice(json).map_err(|e| format!("Invalid webhook: {}", e))
+}
+
+/// Parse a job request posted as JSON.
+pub fn job_request_from_json(json: &[u8]) -> Result<JobRequest, String> {
+    serde_json::from_slice(json).map_err(|e| format!("Invalid job request: {}", e))
+}
+
+pub fn select_to_xml(select: &MBusSelect) -> String {
+    let mut xml = XML_HEADER.to_string();
+    xml.push_str("<MBusSelect>\n");
+    xml_element(
+        &mut xml,
+        4,
+        "SecondaryAddress",
+        &Some(&select.secondary_address),
+    );
+    xml_element(&mut xml, 4, "Result", &Some(&select.result));
+    xml_element(&mut xml, 4, "Collision", &select.collision);
+    xml.push_str("</MBusSelect>\n");
+    xml
+}
+
+pub fn switch_baudrate_to_xml(switch: &MBusSwitchBaudrate) -> String {
+    let mut xml = XML_HEADER.to_string();
+    xml.push_str("<MBusSwitchBaudrate>\n");
+    xml_element(&mut xml, 4, "Address", &Some(&switch.address));
+    xml_element(&mut xml, 4, "Baudrate", &Some(&switch.baudrate));
+    xml_element(&mut xml, 4, "NewBaudrate", &Some(&switch.new_baudrate));
+    xml_element(&mut xml, 4, "Result", &Some(&switch.result));
+    xml_element(&mut xml, 4, "Error", &switch.error);
+    xml.push_str("</MBusSwitchBaudrate>\n");
+    xml
+}
+
+pub fn set_address_to_xml(set: &MBusSetAddress) -> String {
+    let mut xml = XML_HEADER.to_string();
+    xml.push_str("<MBusSetAddress>\n");
+    xml_element(
+        &mut xml,
+        4,
+        "SecondaryAddress",
+        &Some(&set.secondary_address),
+    );
+    xml_element(&mut xml, 4, "Address", &Some(set.address));
+    xml_element(&mut xml, 4, "Result", &Some(&set.result));
+    xml_element(&mut xml, 4, "Error", &set.error);
+    xml.push_str("</MBusSetAddress>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The EN 1434-3 example heat meter response, after the CI field
+    const EXAMPLE: &[u8] = &[
+        0x78, 0x56, 0x34, 0x12, 0x24, 0x40, 0x01, 0x07, 0x55, 0x00, 0x00, 0x00, 0x03, 0x13, 0x15,
+        0x31, 0x00, 0xDA, 0x02, 0x3B, 0x13, 0x01, 0x8B, 0x60, 0x04, 0x37, 0x18, 0x02,
+    ];
+
+    fn example() -> MBusData {
+        let vd = records::decode_data(records::CI_RSP_VARIABLE, EXAMPLE).unwrap();
+        MBusData::from(&vd)
+    }
+
+    #[test]
+    fn native_as_libmbus() {
+        let data = example();
+        let info = &data.slave_information;
+        assert_eq!(info.id.as_deref(), Some("12345678"));
+        assert_eq!(info.manufacturer.as_deref(), Some("PAD"));
+        assert_eq!(info.version.as_deref(), Some("1"));
+        assert_eq!(info.medium.as_deref(), Some("Water"));
+        assert_eq!(info.access_number.as_deref(), Some("85"));
+        assert_eq!(info.status.as_deref(), Some("00"));
+        assert_eq!(info.signature.as_deref(), Some("0000"));
+        assert_eq!(data.secondary_id("48"), "1234567824400107");
+
+        let records: Vec<_> = data
+            .data_records
+            .iter()
+            .map(|r| {
+                (
+                    r.id.as_deref().unwrap(),
+                    r.function.as_deref().unwrap(),
+                    r.storage_number.as_deref().unwrap(),
+                    r.tariff.as_deref(),
+                    r.device.as_deref(),
+                    r.unit.as_deref().unwrap(),
+                    r.value.as_deref().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                (
+                    "0",
+                    "Instantaneous value",
+                    "0",
+                    None,
+                    None,
+                    "Volume (m m^3)",
+                    "12565"
+                ),
+                (
+                    "1",
+                    "Maximum value",
+                    "5",
+                    None,
+                    None,
+                    "Volume flow (m m^3/h)",
+                    "113"
+                ),
+                (
+                    "2",
+                    "Instantaneous value",
+                    "0",
+                    Some("2"),
+                    Some("1"),
+                    "Energy (10 Wh)",
+                    "21837"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn unit_prefixes() {
+        assert_eq!(unit_prefix(0), "");
+        assert_eq!(unit_prefix(-3), "m");
+        assert_eq!(unit_prefix(-6), "my");
+        assert_eq!(unit_prefix(1), "10 ");
+        assert_eq!(unit_prefix(3), "k");
+        assert_eq!(unit_prefix(4), "10 k");
+        assert_eq!(unit_prefix(-2), "1e-2 ");
+    }
+
+    #[test]
+    fn multi_reply_round_trip() {
+        let mut escaped = example();
+        escaped.slave_information.product_name = Some("<Meter & \"Co\">".to_string());
+        escaped.data_records[0].value = Some("a < b > 'c'".to_string());
+        let mut multi = MBusMultiReply {
+            frames: vec![
+                MultiReplyFrame {
+                    index: 0,
+                    fcb: true,
+                    more_records_follow: true,
+                    status: FRAME_OK.to_string(),
+                    error: None,
+                    data: Some(escaped),
+                },
+                MultiReplyFrame {
+                    index: 1,
+                    fcb: false,
+                    more_records_follow: false,
+                    status: FRAME_ERROR.to_string(),
+                    error: Some("Checksum mismatch: <0x12> & <0x34>".to_string()),
+                    data: None,
+                },
+            ],
+            complete: false,
+        };
+        let xml = multi_reply_to_xml(&multi);
+        assert!(xml.contains("<ProductName>&lt;Meter &amp; &quot;Co&quot;&gt;</ProductName>"));
+        assert_eq!(multi_reply_from_xml(&xml), Ok(multi.clone()));
+
+        multi.frames.truncate(1);
+        multi.frames[0].more_records_follow = false;
+        multi.complete = true;
+        let xml = multi_reply_to_xml(&multi);
+        assert!(xml.contains("<Complete>true</Complete>"));
+        assert_eq!(multi_reply_from_xml(&xml), Ok(multi));
+    }
+
+    #[test]
+    fn merged() {
+        let frame = |index, data| MultiReplyFrame {
+            index,
+            status: FRAME_OK.to_string(),
+            data: Some(data),
+            ..Default::default()
+        };
+        let multi = MBusMultiReply {
+            frames: vec![frame(0, example()), frame(1, example())],
+            complete: true,
+        };
+        let merged = multi.merged();
+        assert_eq!(merged.slave_information, example().slave_information);
+        let ids: Vec<_> = merged
+            .data_records
+            .iter()
+            .map(|r| r.id.as_deref().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["0", "1", "2", "3", "4", "5"]);
+    }
+}
@@ -0,0 +1,363 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Home Assistant MQTT discovery - describes each data record of a slave as
+//! a Home Assistant sensor, so meters appear in Home Assistant without any
+//! configuration.
+
+use serde_derive::Serialize;
+
+use crate::data::{self, DataRecord};
+use crate::http;
+
+/// A sensor's discovery config.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Config {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    value_template: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_class: Option<&'static str>,
+    device: Device,
+}
+
+/// The meter a sensor belongs to.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Device {
+    identifiers: Vec<String>,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sw_version: Option<String>,
+}
+
+// How Home Assistant should treat a record's value
+struct Sensor {
+    device_class: Option<&'static str>,
+    unit: String,
+    // What the record's value is multiplied by to give the unit
+    factor: f64,
+    state_class: Option<&'static str>,
+}
+
+// Split a unit like "Volume (1e-2  m^3)" into its quantity and unit
+fn split_unit(unit: &str) -> (&str, &str) {
+    match unit.find(" (") {
+        Some(ii) if unit.ends_with(')') => (&unit[..ii], &unit[ii + 2..unit.len() - 1]),
+        _ => (unit, ""),
+    }
+}
+
+// The multiplier given by the prefix of a unit.  libmbus gives raw values,
+// with the VIF's scaling in the unit, e.g. "m m^3" or "10 Wh".
+fn prefix_factor(prefix: &str) -> Option<f64> {
+    prefix.split_whitespace().try_fold(1.0, |factor, token| {
+        let scale = match token {
+            "my" | "u" | "µ" => 1e-6,
+            "m" => 1e-3,
+            "k" => 1e3,
+            "M" => 1e6,
+            "G" => 1e9,
+            "T" => 1e12,
+            token => token.parse::<f64>().ok()?,
+        };
+        Some(factor * scale)
+    })
+}
+
+fn volume_class(medium: Option<&str>) -> &'static str {
+    let medium = medium.unwrap_or("").to_ascii_lowercase();
+    if medium.contains("water") {
+        "water"
+    } else if medium.contains("gas") {
+        "gas"
+    } else {
+        "volume"
+    }
+}
+
+fn sensor(unit: &str, medium: Option<&str>) -> Option<Sensor> {
+    let (_, unit) = split_unit(unit);
+    if unit.is_empty() {
+        return None;
+    }
+    // Base unit, Home Assistant device class and unit, and the factor to
+    // convert between them
+    let known = [
+        ("Wh", Some("energy"), "kWh", 1e-3),
+        ("J", Some("energy"), "MJ", 1e-6),
+        ("m^3", Some(volume_class(medium)), "m³", 1.0),
+        ("W", Some("power"), "W", 1.0),
+        ("deg C", Some("temperature"), "°C", 1.0),
+        ("°C", Some("temperature"), "°C", 1.0),
+        ("deg F", Some("temperature"), "°F", 1.0),
+        ("°F", Some("temperature"), "°F", 1.0),
+        ("K", Some("temperature"), "K", 1.0),
+    ];
+    for (base, device_class, ha_unit, conversion) in known.iter() {
+        if !unit.ends_with(base) {
+            continue;
+        }
+        if let Some(factor) = prefix_factor(&unit[..unit.len() - base.len()]) {
+            let state_class = match *device_class {
+                Some("temperature") | Some("power") => "measurement",
+                _ => "total_increasing",
+            };
+            return Some(Sensor {
+                device_class: *device_class,
+                unit: ha_unit.to_string(),
+                factor: factor * conversion,
+                state_class: Some(state_class),
+            });
+        }
+    }
+    Some(Sensor {
+        device_class: None,
+        unit: unit.to_string(),
+        factor: 1.0,
+        state_class: None,
+    })
+}
+
+// A name for the record which distinguishes it from the slave's others
+fn name(record: &DataRecord, id: &str) -> String {
+    let (quantity, _) = split_unit(record.unit.as_deref().unwrap_or(""));
+    let mut name = if quantity.is_empty() {
+        format!("Record {}", id)
+    } else {
+        quantity.to_string()
+    };
+    match record.function.as_deref() {
+        Some("Instantaneous value") | None => (),
+        Some(function) => name += &format!(" ({})", function.trim_end_matches(" value")),
+    }
+    for (what, value) in &[
+        ("storage", &record.storage_number),
+        ("tariff", &record.tariff),
+    ] {
+        match value.as_deref() {
+            Some("0") | None => (),
+            Some(value) => name += &format!(" {} {}", what, value),
+        }
+    }
+    name
+}
+
+fn device(info: &data::SlaveInformation, secondary_id: &str) -> Device {
+    let medium = info.medium.clone().unwrap_or_else(|| "M-Bus".to_string());
+    Device {
+        identifiers: vec![format!("mbus_{}", secondary_id)],
+        name: format!(
+            "{} meter {}",
+            medium,
+            info.id.as_deref().unwrap_or(secondary_id)
+        ),
+        manufacturer: info.manufacturer.clone(),
+        model: info
+            .product_name
+            .clone()
+            .filter(|name| !name.is_empty())
+            .or_else(|| info.medium.clone()),
+        sw_version: info.version.clone(),
+    }
+}
+
+/// The topic and discovery config for a data record of a reading, which is
+/// published to `state_topic`.
+pub fn config(
+    reading: &data::MBusData,
+    secondary_id: &str,
+    id: &str,
+    record: &DataRecord,
+    state_topic: &str,
+) -> (String, Config) {
+    let info = &reading.slave_information;
+    let sensor = record
+        .unit
+        .as_ref()
+        .and_then(|unit| sensor(unit, info.medium.as_deref()));
+    let value_template = match sensor {
+        Some(ref sensor) if (sensor.factor - 1.0).abs() > f64::EPSILON => {
+            let decimals = (-sensor.factor.log10()).ceil().max(0.0) as usize;
+            format!(
+                "{{{{ (value_json.Value | float * {}) | round({}) }}}}",
+                sensor.factor, decimals
+            )
+        }
+        _ => "{{ value_json.Value }}".to_string(),
+    };
+    let topic = format!(
+        "{}/sensor/mbus_{}/record_{}/config",
+        http::MQTT_DISCOVERY_PREFIX.get(),
+        secondary_id,
+        id
+    );
+    let config = Config {
+        name: name(record, id),
+        unique_id: format!("mbus_{}_{}", secondary_id, id),
+        state_topic: state_topic.to_string(),
+        value_template,
+        device_class: sensor.as_ref().and_then(|s| s.device_class),
+        unit_of_measurement: sensor.as_ref().map(|s| s.unit.clone()),
+        state_class: sensor.as_ref().and_then(|s| s.state_class),
+        device: device(info, secondary_id),
+    };
+    (topic, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(medium: Option<&str>) -> data::MBusData {
+        data::MBusData {
+            slave_information: data::SlaveInformation {
+                id: Some("12345678".to_string()),
+                manufacturer: Some("PAD".to_string()),
+                version: Some("1".to_string()),
+                medium: medium.map(|m| m.to_string()),
+                ..Default::default()
+            },
+            data_records: Vec::new(),
+        }
+    }
+
+    fn record(unit: Option<&str>) -> DataRecord {
+        DataRecord {
+            function: Some("Instantaneous value".to_string()),
+            storage_number: Some("0".to_string()),
+            unit: unit.map(|u| u.to_string()),
+            value: Some("12565".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn discover(unit: Option<&str>, medium: Option<&str>) -> Config {
+        let (_, config) = config(
+            &reading(medium),
+            "1234567824400107",
+            "0",
+            &record(unit),
+            "mbus/ttyAMA0/1234567824400107/0",
+        );
+        config
+    }
+
+    #[test]
+    fn energy() {
+        let config = discover(Some("Energy (10 Wh)"), Some("Heat: Outlet"));
+        assert_eq!(config.name, "Energy");
+        assert_eq!(config.device_class, Some("energy"));
+        assert_eq!(config.unit_of_measurement.as_deref(), Some("kWh"));
+        assert_eq!(config.state_class, Some("total_increasing"));
+        assert_eq!(
+            config.value_template,
+            "{{ (value_json.Value | float * 0.01) | round(2) }}"
+        );
+    }
+
+    #[test]
+    fn volume() {
+        for (medium, class) in &[
+            (Some("Water"), "water"),
+            (Some("Warm water (30C-90C)"), "water"),
+            (Some("Gas"), "gas"),
+            (Some("Heat: Outlet"), "volume"),
+            (None, "volume"),
+        ] {
+            let config = discover(Some("Volume (m m^3)"), *medium);
+            assert_eq!(config.device_class, Some(*class), "{:?}", medium);
+            assert_eq!(config.unit_of_measurement.as_deref(), Some("m³"));
+            assert_eq!(
+                config.value_template,
+                "{{ (value_json.Value | float * 0.001) | round(3) }}"
+            );
+        }
+    }
+
+    #[test]
+    fn temperature() {
+        let config = discover(Some("Flow temperature (deg C)"), Some("Heat: Outlet"));
+        assert_eq!(config.name, "Flow temperature");
+        assert_eq!(config.device_class, Some("temperature"));
+        assert_eq!(config.unit_of_measurement.as_deref(), Some("°C"));
+        assert_eq!(config.state_class, Some("measurement"));
+        assert_eq!(config.value_template, "{{ value_json.Value }}");
+    }
+
+    #[test]
+    fn unknown_unit() {
+        let config = discover(Some("Operating time (days)"), Some("Water"));
+        assert_eq!(config.device_class, None);
+        assert_eq!(config.unit_of_measurement.as_deref(), Some("days"));
+        assert_eq!(config.state_class, None);
+        assert_eq!(config.value_template, "{{ value_json.Value }}");
+
+        // A quantity without a unit
+        let config = discover(Some("Fabrication number"), Some("Water"));
+        assert_eq!(config.name, "Fabrication number");
+        assert_eq!(config.device_class, None);
+        assert_eq!(config.unit_of_measurement, None);
+    }
+
+    #[test]
+    fn missing_unit() {
+        let config = discover(None, None);
+        assert_eq!(config.name, "Record 0");
+        assert_eq!(config.device_class, None);
+        assert_eq!(config.unit_of_measurement, None);
+        assert_eq!(config.value_template, "{{ value_json.Value }}");
+        assert_eq!(config.device.name, "M-Bus meter 12345678");
+        assert_eq!(config.device.model, None);
+    }
+
+    #[test]
+    fn topic_and_device() {
+        let record = DataRecord {
+            function: Some("Maximum value".to_string()),
+            storage_number: Some("5".to_string()),
+            tariff: Some("2".to_string()),
+            ..record(Some("Volume flow (m m^3/h)"))
+        };
+        let (topic, config) = config(
+            &reading(Some("Water")),
+            "1234567824400107",
+            "3",
+            &record,
+            "t",
+        );
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/mbus_1234567824400107/record_3/config"
+        );
+        assert_eq!(config.name, "Volume flow (Maximum) storage 5 tariff 2");
+        assert_eq!(config.unique_id, "mbus_1234567824400107_3");
+        assert_eq!(config.state_topic, "t");
+        assert_eq!(config.device.identifiers, vec!["mbus_1234567824400107"]);
+        assert_eq!(config.device.name, "Water meter 12345678");
+        assert_eq!(config.device.model.as_deref(), Some("Water"));
+    }
+}
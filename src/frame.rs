@@ -0,0 +1,430 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! M-Bus link layer frame codec (EN 13757-2).
+
+// Start and stop bytes
+pub const ACK: u8 = 0xE5;
+pub const START_SHORT: u8 = 0x10;
+pub const START_LONG: u8 = 0x68;
+pub const STOP: u8 = 0x16;
+
+// C-field values (master to slave)
+pub const C_SND_NKE: u8 = 0x40;
+pub const C_SND_UD: u8 = 0x53;
+pub const C_REQ_UD2: u8 = 0x5B;
+pub const C_FCB: u8 = 0x20;
+
+// C-field values (slave to master)
+pub const C_RSP_UD: u8 = 0x08;
+pub const C_RSP_MASK: u8 = 0x4F;
+
+// Special address
+pub const ADDRESS_NETWORK_LAYER: u8 = 0xFD;
+
+// Long frame layout
+const LONG_HEADER_LEN: usize = 4; // 0x68 L L 0x68
+const LONG_FIXED_LEN: usize = 3; // C A CI, counted in L
+const MAX_DATA_LEN: usize = 255 - LONG_FIXED_LEN;
+const SHORT_LEN: usize = 5; // 0x10 C A CS 0x16
+
+/// A single M-Bus link layer frame.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    /// Single character acknowledgement (0xE5)
+    Ack,
+    /// Short frame: 0x10 C A CS 0x16
+    Short { control: u8, address: u8 },
+    /// Control frame: a long frame carrying no user data (L = 3)
+    Control { control: u8, address: u8, ci: u8 },
+    /// Long frame: 0x68 L L 0x68 C A CI data CS 0x16
+    Long {
+        control: u8,
+        address: u8,
+        ci: u8,
+        data: Vec<u8>,
+    },
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |cs, b| cs.wrapping_add(*b))
+}
+
+impl Frame {
+    pub fn control(&self) -> Option<u8> {
+        match self {
+            Frame::Ack => None,
+            Frame::Short { control, .. }
+            | Frame::Control { control, .. }
+            | Frame::Long { control, .. } => Some(*control),
+        }
+    }
+
+    pub fn address(&self) -> Option<u8> {
+        match self {
+            Frame::Ack => None,
+            Frame::Short { address, .. }
+            | Frame::Control { address, .. }
+            | Frame::Long { address, .. } => Some(*address),
+        }
+    }
+
+    pub fn ci(&self) -> Option<u8> {
+        match self {
+            Frame::Ack | Frame::Short { .. } => None,
+            Frame::Control { ci, .. } | Frame::Long { ci, .. } => Some(*ci),
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Frame::Long { data, .. } => data,
+            _ => &[],
+        }
+    }
+
+    /// Encode the frame into the bytes to be sent on the wire.
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        let bytes = match self {
+            Frame::Ack => vec![ACK],
+            Frame::Short { control, address } => vec![
+                START_SHORT,
+                *control,
+                *address,
+                checksum(&[*control, *address]),
+                STOP,
+            ],
+            Frame::Control {
+                control,
+                address,
+                ci,
+            } => encode_long(*control, *address, *ci, &[])?,
+            Frame::Long {
+                control,
+                address,
+                ci,
+                data,
+            } => encode_long(*control, *address, *ci, data)?,
+        };
+        Ok(bytes)
+    }
+
+    /// Decode exactly one frame from `buf`.  The buffer must contain the
+    /// whole frame and nothing else.
+    pub fn decode(buf: &[u8]) -> Result<Frame, String> {
+        let len = match length(buf)? {
+            Some(len) => len,
+            None => return Err(format!("Incomplete frame: {} bytes", buf.len())),
+        };
+        if buf.len() < len {
+            return Err(format!(
+                "Incomplete frame: {} bytes, expected {}",
+                buf.len(),
+                len
+            ));
+        }
+        if buf.len() > len {
+            return Err(format!(
+                "Trailing data after frame: {} bytes, expected {}",
+                buf.len(),
+                len
+            ));
+        }
+
+        match buf[0] {
+            ACK => Ok(Frame::Ack),
+            START_SHORT => {
+                check_stop(buf)?;
+                check_checksum(&buf[1..3], buf[3])?;
+                Ok(Frame::Short {
+                    control: buf[1],
+                    address: buf[2],
+                })
+            }
+            _ => {
+                // length() has already checked the start bytes and L-fields
+                check_stop(buf)?;
+                let body = &buf[LONG_HEADER_LEN..len - 2];
+                check_checksum(body, buf[len - 2])?;
+                let (control, address, ci) = (body[0], body[1], body[2]);
+                if body.len() == LONG_FIXED_LEN {
+                    Ok(Frame::Control {
+                        control,
+                        address,
+                        ci,
+                    })
+                } else {
+                    Ok(Frame::Long {
+                        control,
+                        address,
+                        ci,
+                        data: body[LONG_FIXED_LEN..].to_vec(),
+                    })
+                }
+            }
+        }
+    }
+}
+
+fn encode_long(control: u8, address: u8, ci: u8, data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() > MAX_DATA_LEN {
+        return Err(format!(
+            "Frame data too long: {} bytes, maximum {}",
+            data.len(),
+            MAX_DATA_LEN
+        ));
+    }
+    let l = (data.len() + LONG_FIXED_LEN) as u8;
+    let mut bytes = Vec::with_capacity(LONG_HEADER_LEN + l as usize + 2);
+    bytes.extend_from_slice(&[START_LONG, l, l, START_LONG, control, address, ci]);
+    bytes.extend_from_slice(data);
+    bytes.push(checksum(&bytes[LONG_HEADER_LEN..]));
+    bytes.push(STOP);
+    Ok(bytes)
+}
+
+fn check_stop(buf: &[u8]) -> Result<(), String> {
+    match buf.last() {
+        Some(&STOP) => Ok(()),
+        Some(b) => Err(format!("Invalid stop byte: 0x{:02X}", b)),
+        None => Err("Empty frame".to_string()),
+    }
+}
+
+fn check_checksum(body: &[u8], cs: u8) -> Result<(), String> {
+    let calc = checksum(body);
+    if calc == cs {
+        Ok(())
+    } else {
+        Err(format!(
+            "Checksum mismatch: received 0x{:02X}, calculated 0x{:02X}",
+            cs, calc
+        ))
+    }
+}
+
+/// Work out the total length of the frame starting at `buf[0]` from the
+/// bytes received so far.  Returns `Ok(None)` if more bytes are needed
+/// before the length is known, and an error if what has been received so
+/// far cannot be the start of a valid frame.
+pub fn length(buf: &[u8]) -> Result<Option<usize>, String> {
+    match buf.first() {
+        None => Ok(None),
+        Some(&ACK) => Ok(Some(1)),
+        Some(&START_SHORT) => Ok(Some(SHORT_LEN)),
+        Some(&START_LONG) => {
+            if buf.len() < LONG_HEADER_LEN {
+                return Ok(None);
+            }
+            if buf[1] != buf[2] {
+                return Err(format!(
+                    "L-field mismatch: 0x{:02X} 0x{:02X}",
+                    buf[1], buf[2]
+                ));
+            }
+            if buf[3] != START_LONG {
+                return Err(format!("Invalid second start byte: 0x{:02X}", buf[3]));
+            }
+            let l = buf[1] as usize;
+            if l < LONG_FIXED_LEN {
+                return Err(format!("L-field too short: {}", l));
+            }
+            Ok(Some(LONG_HEADER_LEN + l + 2))
+        }
+        Some(b) => Err(format!("Invalid start byte: 0x{:02X}", b)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SND_NKE to primary address 5
+    const SHORT: &[u8] = &[0x10, 0x40, 0x05, 0x45, 0x16];
+    // SND_UD application reset (CI 0x50) broadcast to all slaves
+    const CONTROL: &[u8] = &[0x68, 0x03, 0x03, 0x68, 0x53, 0xFE, 0x50, 0xA1, 0x16];
+    // SND_UD selecting secondary address 12345678, manufacturer 0x4024,
+    // version 1, medium 7
+    const SELECT: &[u8] = &[
+        0x68, 0x0B, 0x0B, 0x68, 0x53, 0xFD, 0x52, 0x78, 0x56, 0x34, 0x12, 0x24, 0x40, 0x01, 0x07,
+        0x22, 0x16,
+    ];
+    // RSP_UD from primary address 2: the EN 1434-3 example heat meter
+    const RSP_UD: &[u8] = &[
+        0x68, 0x1F, 0x1F, 0x68, 0x08, 0x02, 0x72, 0x78, 0x56, 0x34, 0x12, 0x24, 0x40, 0x01, 0x07,
+        0x55, 0x00, 0x00, 0x00, 0x03, 0x13, 0x15, 0x31, 0x00, 0xDA, 0x02, 0x3B, 0x13, 0x01, 0x8B,
+        0x60, 0x04, 0x37, 0x18, 0x02, 0x18, 0x16,
+    ];
+
+    fn round_trip(bytes: &[u8], expected: Frame) {
+        let frame = Frame::decode(bytes).unwrap();
+        assert_eq!(frame, expected);
+        assert_eq!(frame.encode().unwrap(), bytes);
+        assert_eq!(length(bytes), Ok(Some(bytes.len())));
+    }
+
+    #[test]
+    fn ack() {
+        round_trip(&[ACK], Frame::Ack);
+    }
+
+    #[test]
+    fn short() {
+        round_trip(
+            SHORT,
+            Frame::Short {
+                control: C_SND_NKE,
+                address: 5,
+            },
+        );
+    }
+
+    #[test]
+    fn control() {
+        round_trip(
+            CONTROL,
+            Frame::Control {
+                control: C_SND_UD,
+                address: 0xFE,
+                ci: 0x50,
+            },
+        );
+    }
+
+    #[test]
+    fn long() {
+        round_trip(
+            SELECT,
+            Frame::Long {
+                control: C_SND_UD,
+                address: ADDRESS_NETWORK_LAYER,
+                ci: 0x52,
+                data: vec![0x78, 0x56, 0x34, 0x12, 0x24, 0x40, 0x01, 0x07],
+            },
+        );
+        let frame = Frame::decode(RSP_UD).unwrap();
+        assert_eq!(frame.control(), Some(C_RSP_UD));
+        assert_eq!(frame.address(), Some(2));
+        assert_eq!(frame.ci(), Some(0x72));
+        assert_eq!(frame.data(), &RSP_UD[7..RSP_UD.len() - 2]);
+        assert_eq!(frame.encode().unwrap(), RSP_UD);
+    }
+
+    #[test]
+    fn long_without_data_is_control() {
+        let frame = Frame::Long {
+            control: C_SND_UD,
+            address: 0xFE,
+            ci: 0x50,
+            data: Vec::new(),
+        };
+        assert_eq!(frame.encode().unwrap(), CONTROL);
+    }
+
+    #[test]
+    fn data_too_long() {
+        let frame = Frame::Long {
+            control: C_SND_UD,
+            address: 1,
+            ci: 0x51,
+            data: vec![0; MAX_DATA_LEN + 1],
+        };
+        assert!(frame.encode().is_err());
+    }
+
+    #[test]
+    fn length_needs_header() {
+        assert_eq!(length(&[]), Ok(None));
+        assert_eq!(length(&RSP_UD[..3]), Ok(None));
+        assert_eq!(length(&RSP_UD[..4]), Ok(Some(RSP_UD.len())));
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut bytes = RSP_UD.to_vec();
+        let cs = bytes.len() - 2;
+        bytes[cs] ^= 0x01;
+        let e = Frame::decode(&bytes).unwrap_err();
+        assert!(e.starts_with("Checksum mismatch"), "{}", e);
+
+        let mut bytes = SHORT.to_vec();
+        bytes[3] = 0x00;
+        let e = Frame::decode(&bytes).unwrap_err();
+        assert!(e.starts_with("Checksum mismatch"), "{}", e);
+    }
+
+    #[test]
+    fn bad_stop() {
+        for frame in &[SHORT, CONTROL, RSP_UD] {
+            let mut bytes = frame.to_vec();
+            *bytes.last_mut().unwrap() = 0x17;
+            let e = Frame::decode(&bytes).unwrap_err();
+            assert!(e.starts_with("Invalid stop byte"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn mismatched_l_fields() {
+        let mut bytes = RSP_UD.to_vec();
+        bytes[2] = 0x1E;
+        let e = Frame::decode(&bytes).unwrap_err();
+        assert!(e.starts_with("L-field mismatch"), "{}", e);
+        assert!(length(&bytes[..4]).is_err());
+    }
+
+    #[test]
+    fn bad_second_start() {
+        let mut bytes = CONTROL.to_vec();
+        bytes[3] = 0x10;
+        let e = Frame::decode(&bytes).unwrap_err();
+        assert!(e.starts_with("Invalid second start byte"), "{}", e);
+    }
+
+    #[test]
+    fn l_field_too_short() {
+        let bytes = [0x68, 0x02, 0x02, 0x68, 0x53, 0xFE, 0x51, 0x16];
+        let e = Frame::decode(&bytes).unwrap_err();
+        assert!(e.starts_with("L-field too short"), "{}", e);
+    }
+
+    #[test]
+    fn truncated() {
+        for frame in &[SHORT, CONTROL, RSP_UD] {
+            let e = Frame::decode(&frame[..frame.len() - 1]).unwrap_err();
+            assert!(e.starts_with("Incomplete frame"), "{}", e);
+        }
+        let e = Frame::decode(&RSP_UD[..2]).unwrap_err();
+        assert!(e.starts_with("Incomplete frame"), "{}", e);
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let e = Frame::decode(&[ACK, ACK]).unwrap_err();
+        assert!(e.starts_with("Trailing data"), "{}", e);
+        let mut bytes = SHORT.to_vec();
+        bytes.push(0x00);
+        let e = Frame::decode(&bytes).unwrap_err();
+        assert!(e.starts_with("Trailing data"), "{}", e);
+    }
+
+    #[test]
+    fn bad_start() {
+        let e = Frame::decode(&[0x00]).unwrap_err();
+        assert!(e.starts_with("Invalid start byte"), "{}", e);
+    }
+}
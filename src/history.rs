@@ -0,0 +1,314 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! The reading history - each value read from a registered meter, kept in
+//! an SQLite database in HISTORY_FILE for HISTORY_RETENTION days, so it can
+//! be fetched later, e.g. after a network outage.
+
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use rusqlite::{params, Connection};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::task;
+
+use crate::data::{self, DataRecord, HistoryRecord};
+use crate::http;
+
+// How often old readings are purged
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS readings (
+        meter TEXT NOT NULL,
+        time INTEGER NOT NULL,
+        record INTEGER NOT NULL,
+        function TEXT,
+        storage_number TEXT,
+        unit TEXT,
+        value TEXT
+    );
+    CREATE INDEX IF NOT EXISTS readings_meter_time ON readings (meter, time);
+    CREATE INDEX IF NOT EXISTS readings_time ON readings (time);
+";
+
+struct Store {
+    conn: Connection,
+    purged: Option<Instant>,
+}
+
+lazy_static! {
+    /// The database, once it has been opened
+    static ref STORE: Mutex<Option<Store>> = Mutex::new(None);
+}
+
+fn open() -> Result<Store, String> {
+    let path = Path::new(&*http::HISTORY_FILE);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    let conn = Connection::open(path)
+        .and_then(|conn| conn.execute_batch(SCHEMA).map(|_| conn))
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    info!("Opened reading history {}", path.display());
+    Ok(Store { conn, purged: None })
+}
+
+// Run a database operation on a blocking thread, opening the database if
+// necessary
+async fn with_store<T, F>(op: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Store) -> Result<T, String> + Send + 'static,
+{
+    let handle = task::spawn_blocking(move || {
+        let mut store = STORE.lock().unwrap();
+        if store.is_none() {
+            *store = Some(open()?);
+        }
+        op(store.as_mut().unwrap())
+    });
+    match handle.await {
+        Ok(rsp) => rsp,
+        Err(e) => Err(format!("History operation failed: {}", e)),
+    }
+}
+
+// Remove readings older than HISTORY_RETENTION, at most once every
+// PURGE_INTERVAL
+fn purge(store: &mut Store) -> Result<(), String> {
+    if http::HISTORY_RETENTION.get() == 0
+        || matches!(store.purged, Some(t) if t.elapsed() < PURGE_INTERVAL)
+    {
+        return Ok(());
+    }
+    let cutoff = Utc::now().timestamp() - (http::HISTORY_RETENTION.get() * 24 * 3600) as i64;
+    let count = store
+        .conn
+        .execute("DELETE FROM readings WHERE time < ?1", params![cutoff])
+        .map_err(|e| format!("Failed to purge history: {}", e))?;
+    info!("Purged {} readings from history", count);
+    store.purged = Some(Instant::now());
+    Ok(())
+}
+
+// Add a meter's data records, read at `time`, to the history
+fn insert(store: &mut Store, meter: &str, time: i64, records: &[DataRecord]) -> Result<(), String> {
+    let tx = store
+        .conn
+        .transaction()
+        .map_err(|e| format!("Failed to store reading: {}", e))?;
+    for (ii, record) in records.iter().enumerate() {
+        let id = record
+            .id
+            .as_ref()
+            .and_then(|id| id.parse::<u32>().ok())
+            .unwrap_or(ii as u32);
+        tx.execute(
+            "INSERT INTO readings (meter, time, record, function, storage_number, unit, value)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                meter,
+                time,
+                id,
+                record.function,
+                record.storage_number,
+                record.unit,
+                record.value
+            ],
+        )
+        .map_err(|e| format!("Failed to store reading: {}", e))?;
+    }
+    tx.commit()
+        .map_err(|e| format!("Failed to store reading: {}", e))
+}
+
+// A meter's readings between two times, optionally only for one data
+// record, oldest first
+fn select(
+    store: &Store,
+    meter: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    record: Option<u32>,
+) -> Result<data::MBusHistory, String> {
+    let mut stmt = store
+        .conn
+        .prepare(
+            "SELECT time, record, function, storage_number, unit, value FROM readings
+             WHERE meter = ?1 AND time >= ?2 AND time <= ?3 AND (?4 IS NULL OR record = ?4)
+             ORDER BY time, record",
+        )
+        .map_err(|e| format!("Failed to query history: {}", e))?;
+    let rows = stmt
+        .query_map(
+            params![meter, from.timestamp(), to.timestamp(), record],
+            |row| {
+                let time = Utc.timestamp_opt(row.get(0)?, 0).unwrap();
+                Ok(HistoryRecord {
+                    time: time.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    id: row.get(1)?,
+                    function: row.get(2)?,
+                    storage_number: row.get(3)?,
+                    unit: row.get(4)?,
+                    value: row.get(5)?,
+                })
+            },
+        )
+        .map_err(|e| format!("Failed to query history: {}", e))?;
+    let records = rows
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed to query history: {}", e))?;
+    Ok(data::MBusHistory {
+        meter: meter.to_string(),
+        records,
+    })
+}
+
+/// Add a reading of a meter to the history.  Failures are logged rather
+/// than returned, as the reading itself succeeded.
+pub async fn record(meter: &str, reading: &data::MBusData) {
+    let meter = meter.to_string();
+    let time = Utc::now().timestamp();
+    let records = reading.data_records.clone();
+    let result = with_store(move |store| {
+        insert(store, &meter, time, &records)?;
+        purge(store)
+    })
+    .await;
+    if let Err(e) = result {
+        warn!("{}", e);
+    }
+}
+
+/// A meter's readings between two times, optionally only for one data
+/// record, oldest first.
+pub async fn query(
+    meter: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    record: Option<u32>,
+) -> Result<data::MBusHistory, String> {
+    let meter = meter.to_string();
+    with_store(move |store| select(store, &meter, from, to, record)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> Store {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        Store { conn, purged: None }
+    }
+
+    fn record(id: Option<&str>, value: &str) -> DataRecord {
+        DataRecord {
+            id: id.map(|id| id.to_string()),
+            function: Some("Instantaneous value".to_string()),
+            storage_number: Some("0".to_string()),
+            unit: Some("Volume (m m^3)".to_string()),
+            value: Some(value.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn time(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(secs, 0).unwrap()
+    }
+
+    fn values(history: &data::MBusHistory) -> Vec<(&str, u32, &str)> {
+        history
+            .records
+            .iter()
+            .map(|r| (r.time.as_str(), r.id, r.value.as_deref().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn record_and_query() {
+        let mut store = store();
+        let reading = [record(Some("0"), "100"), record(Some("3"), "3")];
+        insert(&mut store, "hall", 1_600_000_000, &reading).unwrap();
+        // Records are numbered by position if they have no ID
+        let reading = [record(None, "101"), record(None, "4")];
+        insert(&mut store, "hall", 1_600_000_600, &reading).unwrap();
+        insert(&mut store, "kitchen", 1_600_000_300, &[record(None, "7")]).unwrap();
+
+        let history = select(&store, "hall", time(0), time(2_000_000_000), None).unwrap();
+        assert_eq!(history.meter, "hall");
+        assert_eq!(
+            values(&history),
+            vec![
+                ("2020-09-13T12:26:40Z", 0, "100"),
+                ("2020-09-13T12:26:40Z", 3, "3"),
+                ("2020-09-13T12:36:40Z", 0, "101"),
+                ("2020-09-13T12:36:40Z", 1, "4"),
+            ]
+        );
+        assert_eq!(history.records[0].unit.as_deref(), Some("Volume (m m^3)"));
+
+        // The range includes both ends
+        let history = select(
+            &store,
+            "hall",
+            time(1_600_000_000),
+            time(1_600_000_599),
+            None,
+        );
+        assert_eq!(history.unwrap().records.len(), 2);
+        let history = select(
+            &store,
+            "hall",
+            time(1_600_000_001),
+            time(1_600_000_600),
+            Some(0),
+        );
+        assert_eq!(
+            values(&history.unwrap()),
+            vec![("2020-09-13T12:36:40Z", 0, "101")]
+        );
+
+        let history = select(&store, "garage", time(0), time(2_000_000_000), None).unwrap();
+        assert!(history.records.is_empty());
+    }
+
+    #[test]
+    fn purge_old_readings() {
+        let mut store = store();
+        let now = Utc::now().timestamp();
+        let old = now - (http::HISTORY_RETENTION.get() as i64 + 1) * 24 * 3600;
+        insert(&mut store, "hall", old, &[record(None, "1")]).unwrap();
+        insert(&mut store, "hall", now, &[record(None, "2")]).unwrap();
+        purge(&mut store).unwrap();
+        let history = select(&store, "hall", time(0), time(now), None).unwrap();
+        assert_eq!(history.records.len(), 1);
+        assert_eq!(history.records[0].value.as_deref(), Some("2"));
+
+        // Not purged again until PURGE_INTERVAL has passed
+        insert(&mut store, "hall", old, &[record(None, "1")]).unwrap();
+        purge(&mut store).unwrap();
+        let history = select(&store, "hall", time(0), time(now), None).unwrap();
+        assert_eq!(history.records.len(), 2);
+    }
+}
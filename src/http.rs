@@ -18,31 +18,114 @@
 
 #![allow(dead_code)]
 
+use chrono::{SecondsFormat, Utc};
 use mbus_api::models;
 use mbus_api::{
     GetMultiResponse, GetResponse, HatOffResponse, HatOnResponse, HatResponse, MbusApiResponse,
     ScanResponse,
 };
-use std::env;
+use std::collections::HashMap;
 use std::fs;
+use std::net::ToSocketAddrs;
 use std::path::Path;
-use std::process::Command;
-use std::str;
-use std::sync::Mutex;
+use std::str::{self, FromStr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use sysfs_gpio::{Direction, Pin};
+use tokio::process::Command;
+use tokio::{task, time};
 
 use lazy_static::lazy_static;
 use log::info;
 
+use crate::api::{
+    ScanSecondaryResponse, SelectResponse, SetAddressResponse, SwitchBaudrateResponse,
+};
+use crate::bus::{self, Bus, Cancel, Probe, SecondaryFound, SecondaryProbe};
+use crate::config;
+use crate::data;
+use crate::influx;
+use crate::metrics;
+use crate::mqtt;
+use crate::progress::Progress;
+use crate::queue::{self, Queue, Ticket};
+use crate::serial;
+use crate::webhooks;
+
 const LIBMBUS_PATH_VAR: &str = "LIBMBUS_PATH";
 const LIBMBUS_PATH_DEF: &str = "/usr/local/bin/";
 const LIBMBUS_GET_VAR: &str = "LIBMBUS_GET";
 const LIBMBUS_GET_DEF: &str = "mbus-serial-request-data";
-const LIBMBUS_GET_MULTI_VAR: &str = "LIBMBUS_GET_MULTI";
-const LIBMBUS_GET_MULTI_DEF: &str = "mbus-serial-request-data-multi-reply";
-const LIBMBUS_SCAN_VAR: &str = "LIBMBUS_SCAN";
-const LIBMBUS_SCAN_DEF: &str = "mbus-serial-scan";
 const LD_LIBRARY_PATH_VAR: &str = "LD_LIBRARY_PATH";
+const OPERATION_TIMEOUT_VAR: &str = "OPERATION_TIMEOUT";
+const OPERATION_TIMEOUT_DEF: u64 = 300; // seconds
+const QUEUE_LENGTH_VAR: &str = "QUEUE_LENGTH";
+const QUEUE_LENGTH_DEF: usize = 16;
+const QUEUE_RETRY_AFTER_VAR: &str = "QUEUE_RETRY_AFTER";
+const QUEUE_RETRY_AFTER_DEF: u64 = 10; // seconds
+const JOB_RETENTION_VAR: &str = "JOB_RETENTION";
+const JOB_RETENTION_DEF: u64 = 3600; // seconds
+const METERS_FILE_VAR: &str = "METERS_FILE";
+const METERS_FILE_DEF: &str = "/var/lib/mbus-httpd/meters.json";
+const POLL_JITTER_VAR: &str = "POLL_JITTER";
+const POLL_JITTER_DEF: u64 = 30; // seconds
+const POLL_RETRIES_VAR: &str = "POLL_RETRIES";
+const POLL_RETRIES_DEF: u32 = 3;
+const POLL_RETRY_DELAY_VAR: &str = "POLL_RETRY_DELAY";
+const POLL_RETRY_DELAY_DEF: u64 = 10; // seconds
+const HISTORY_FILE_VAR: &str = "HISTORY_FILE";
+const HISTORY_FILE_DEF: &str = "/var/lib/mbus-httpd/history.db";
+const HISTORY_RETENTION_VAR: &str = "HISTORY_RETENTION";
+const HISTORY_RETENTION_DEF: u64 = 90; // days
+const MQTT_BROKER_VAR: &str = "MQTT_BROKER";
+const MQTT_PORT_VAR: &str = "MQTT_PORT";
+const MQTT_PORT_DEF: u16 = 1883;
+const MQTT_CLIENT_ID_VAR: &str = "MQTT_CLIENT_ID";
+const MQTT_CLIENT_ID_DEF: &str = "mbus-httpd";
+const MQTT_USERNAME_VAR: &str = "MQTT_USERNAME";
+const MQTT_PASSWORD_VAR: &str = "MQTT_PASSWORD";
+const MQTT_CA_FILE_VAR: &str = "MQTT_CA_FILE";
+const MQTT_TOPIC_VAR: &str = "MQTT_TOPIC";
+const MQTT_TOPIC_DEF: &str = "mbus/{device}/{secondary_id}/{record}";
+const MQTT_EVENT_TOPIC_VAR: &str = "MQTT_EVENT_TOPIC";
+const MQTT_EVENT_TOPIC_DEF: &str = "mbus/{device}/{event}";
+const MQTT_QOS_VAR: &str = "MQTT_QOS";
+const MQTT_QOS_DEF: u8 = 0;
+const MQTT_RETAIN_VAR: &str = "MQTT_RETAIN";
+const MQTT_RETAIN_DEF: bool = false;
+const MQTT_DISCOVERY_VAR: &str = "MQTT_DISCOVERY";
+const MQTT_DISCOVERY_DEF: bool = false;
+const MQTT_DISCOVERY_PREFIX_VAR: &str = "MQTT_DISCOVERY_PREFIX";
+const MQTT_DISCOVERY_PREFIX_DEF: &str = "homeassistant";
+const METRICS_RECORD_VALUES_VAR: &str = "METRICS_RECORD_VALUES";
+const METRICS_RECORD_VALUES_DEF: bool = false;
+const INFLUX_URL_VAR: &str = "INFLUX_URL";
+const INFLUX_TOKEN_VAR: &str = "INFLUX_TOKEN";
+const INFLUX_MEASUREMENT_VAR: &str = "INFLUX_MEASUREMENT";
+const INFLUX_MEASUREMENT_DEF: &str = "mbus";
+const INFLUX_BATCH_SIZE_VAR: &str = "INFLUX_BATCH_SIZE";
+const INFLUX_BATCH_SIZE_DEF: usize = 100;
+const INFLUX_BATCH_INTERVAL_VAR: &str = "INFLUX_BATCH_INTERVAL";
+const INFLUX_BATCH_INTERVAL_DEF: u64 = 10; // seconds
+const INFLUX_RETRIES_VAR: &str = "INFLUX_RETRIES";
+const INFLUX_RETRIES_DEF: u32 = 3;
+const INFLUX_RETRY_DELAY_VAR: &str = "INFLUX_RETRY_DELAY";
+const INFLUX_RETRY_DELAY_DEF: u64 = 1; // seconds
+const WEBHOOKS_FILE_VAR: &str = "WEBHOOKS_FILE";
+const WEBHOOKS_FILE_DEF: &str = "/var/lib/mbus-httpd/webhooks.json";
+const WEBHOOK_TIMEOUT_VAR: &str = "WEBHOOK_TIMEOUT";
+const WEBHOOK_TIMEOUT_DEF: u64 = 10; // seconds
+const WEBHOOK_RETRIES_VAR: &str = "WEBHOOK_RETRIES";
+const WEBHOOK_RETRIES_DEF: u32 = 5;
+const WEBHOOK_RETRY_DELAY_VAR: &str = "WEBHOOK_RETRY_DELAY";
+const WEBHOOK_RETRY_DELAY_DEF: u64 = 10; // seconds
+const WEBHOOK_DEAD_LETTERS_VAR: &str = "WEBHOOK_DEAD_LETTERS";
+const WEBHOOK_DEAD_LETTERS_DEF: usize = 100;
+const API_TOKEN_VAR: &str = "API_TOKEN";
+const SERVER_IP_VAR: &str = "SERVER_IP";
+const SERVER_PORT_VAR: &str = "SERVER_PORT";
+const HAT_GPIO_VAR: &str = "HAT_GPIO";
+const HAT_GPIO_DEF: u64 = 26;
 
 const DEV_PREFIX: &str = "/dev/";
 const HAT_PATH: &str = "/proc/device-tree/hat/";
@@ -53,45 +136,386 @@ const HAT_UUID: &str = "uuid";
 const HAT_VENDOR: &str = "vendor";
 const MBUS_MASTER_HAT_PID: &str = "0x0001";
 const MBUS_MASTER_HAT_VENDOR: &str = "packom.net";
-const MBUS_MASTER_POWER_GPIO: u64 = 26;
 
 pub fn get_env() -> Vec<&'static str> {
     vec![
         LIBMBUS_PATH_VAR,
         LIBMBUS_GET_VAR,
-        LIBMBUS_GET_MULTI_VAR,
-        LIBMBUS_SCAN_VAR,
         LD_LIBRARY_PATH_VAR,
+        OPERATION_TIMEOUT_VAR,
+        QUEUE_LENGTH_VAR,
+        QUEUE_RETRY_AFTER_VAR,
+        JOB_RETENTION_VAR,
+        METERS_FILE_VAR,
+        POLL_JITTER_VAR,
+        POLL_RETRIES_VAR,
+        POLL_RETRY_DELAY_VAR,
+        HISTORY_FILE_VAR,
+        HISTORY_RETENTION_VAR,
+        MQTT_BROKER_VAR,
+        MQTT_PORT_VAR,
+        MQTT_CLIENT_ID_VAR,
+        MQTT_USERNAME_VAR,
+        MQTT_PASSWORD_VAR,
+        MQTT_CA_FILE_VAR,
+        MQTT_TOPIC_VAR,
+        MQTT_EVENT_TOPIC_VAR,
+        MQTT_QOS_VAR,
+        MQTT_RETAIN_VAR,
+        MQTT_DISCOVERY_VAR,
+        MQTT_DISCOVERY_PREFIX_VAR,
+        METRICS_RECORD_VALUES_VAR,
+        INFLUX_URL_VAR,
+        INFLUX_TOKEN_VAR,
+        INFLUX_MEASUREMENT_VAR,
+        INFLUX_BATCH_SIZE_VAR,
+        INFLUX_BATCH_INTERVAL_VAR,
+        INFLUX_RETRIES_VAR,
+        INFLUX_RETRY_DELAY_VAR,
+        WEBHOOKS_FILE_VAR,
+        WEBHOOK_TIMEOUT_VAR,
+        WEBHOOK_RETRIES_VAR,
+        WEBHOOK_RETRY_DELAY_VAR,
+        WEBHOOK_DEAD_LETTERS_VAR,
+        API_TOKEN_VAR,
+        HAT_GPIO_VAR,
     ]
 }
 
-lazy_static! {
-    static ref LIBMBUS_PATH: String = {
-        match env::var(LIBMBUS_PATH_VAR) {
-            Ok(v) => v,
-            Err(_) => LIBMBUS_PATH_DEF.to_string(),
+// A setting, failing if it is set but invalid
+fn parse<T: FromStr>(var: &str) -> Result<Option<T>, String> {
+    match config::var(var) {
+        Some(v) => match v.parse::<T>() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(format!("Invalid {}: {}", var, v)),
+        },
+        None => Ok(None),
+    }
+}
+
+// A setting, or its default if it isn't set.  The settings are checked at
+// startup, and the config file whenever it is reloaded, so are valid here.
+fn setting<T: FromStr>(var: &str, def: T) -> T {
+    match parse(var) {
+        Ok(Some(value)) => value,
+        _ => def,
+    }
+}
+
+/// Check every setting which has to be a number or a boolean, from the
+/// environment or the config file, failing with the first invalid one.
+pub(crate) fn check() -> Result<(), String> {
+    parse::<u64>(OPERATION_TIMEOUT_VAR)?;
+    for var in &[QUEUE_LENGTH_VAR, INFLUX_BATCH_SIZE_VAR] {
+        if parse::<usize>(var)? == Some(0) {
+            return Err(format!("{} must be at least 1", var));
         }
-    };
-    static ref LIBMBUS_GET: String = {
-        match env::var(LIBMBUS_GET_VAR) {
-            Ok(v) => v,
-            Err(_) => LIBMBUS_GET_DEF.to_string(),
+    }
+    for var in &[
+        QUEUE_RETRY_AFTER_VAR,
+        JOB_RETENTION_VAR,
+        POLL_JITTER_VAR,
+        POLL_RETRY_DELAY_VAR,
+        HISTORY_RETENTION_VAR,
+        INFLUX_BATCH_INTERVAL_VAR,
+        INFLUX_RETRY_DELAY_VAR,
+        WEBHOOK_TIMEOUT_VAR,
+        WEBHOOK_RETRY_DELAY_VAR,
+        HAT_GPIO_VAR,
+    ] {
+        parse::<u64>(var)?;
+    }
+    for var in &[POLL_RETRIES_VAR, INFLUX_RETRIES_VAR, WEBHOOK_RETRIES_VAR] {
+        parse::<u32>(var)?;
+    }
+    parse::<usize>(WEBHOOK_DEAD_LETTERS_VAR)?;
+    // httpd-util would otherwise fall back to its defaults
+    if let Some(ip) = config::var(SERVER_IP_VAR) {
+        (ip.as_str(), 0)
+            .to_socket_addrs()
+            .map_err(|_| format!("Invalid {}: {}", SERVER_IP_VAR, ip))?;
+    }
+    parse::<u16>(SERVER_PORT_VAR)?;
+    parse::<u16>(MQTT_PORT_VAR)?;
+    if let Some(qos) = parse::<u8>(MQTT_QOS_VAR)? {
+        if qos > 2 {
+            return Err(format!("{} must be 0, 1 or 2, not {}", MQTT_QOS_VAR, qos));
         }
-    };
-    static ref LIBMBUS_GET_MULTI: String = {
-        match env::var(LIBMBUS_GET_MULTI_VAR) {
-            Ok(v) => v,
-            Err(_) => LIBMBUS_GET_MULTI_DEF.to_string(),
+    }
+    for var in &[
+        MQTT_RETAIN_VAR,
+        MQTT_DISCOVERY_VAR,
+        METRICS_RECORD_VALUES_VAR,
+    ] {
+        parse::<bool>(var)?;
+    }
+    Ok(())
+}
+
+/// A setting which can be read again when the config file is reloaded.
+pub(crate) struct Reloadable<T> {
+    read: fn() -> T,
+    value: RwLock<T>,
+}
+
+impl<T: Clone> Reloadable<T> {
+    fn new(read: fn() -> T) -> Self {
+        Reloadable {
+            value: RwLock::new(read()),
+            read,
         }
+    }
+
+    /// The setting's current value.
+    pub(crate) fn get(&self) -> T {
+        self.value.read().unwrap().clone()
+    }
+
+    fn reload(&self) {
+        *self.value.write().unwrap() = (self.read)();
+    }
+}
+
+lazy_static! {
+    static ref LIBMBUS_PATH: Reloadable<String> =
+        Reloadable::new(|| setting(LIBMBUS_PATH_VAR, LIBMBUS_PATH_DEF.to_string()));
+    static ref LIBMBUS_GET: Reloadable<String> =
+        Reloadable::new(|| setting(LIBMBUS_GET_VAR, LIBMBUS_GET_DEF.to_string()));
+    /// Where libmbus binaries find libmbus.so, if not the system default
+    static ref LD_LIBRARY_PATH: Reloadable<Option<String>> =
+        Reloadable::new(|| config::var(LD_LIBRARY_PATH_VAR));
+    static ref OPERATION_TIMEOUT: Reloadable<Duration> = Reloadable::new(|| {
+        Duration::from_secs(setting(OPERATION_TIMEOUT_VAR, OPERATION_TIMEOUT_DEF))
+    });
+    /// GPIO which switches the M-Bus Master Hat's power
+    static ref HAT_GPIO: u64 = setting(HAT_GPIO_VAR, HAT_GPIO_DEF);
+    static ref GPIO: Pin = Pin::new(*HAT_GPIO);
+    static ref QUEUE_LENGTH: usize = setting(QUEUE_LENGTH_VAR, QUEUE_LENGTH_DEF);
+    static ref QUEUE_RETRY_AFTER: Duration = {
+        Duration::from_secs(setting(QUEUE_RETRY_AFTER_VAR, QUEUE_RETRY_AFTER_DEF))
     };
-    static ref LIBMBUS_SCAN: String = {
-        match env::var(LIBMBUS_SCAN_VAR) {
-            Ok(v) => v,
-            Err(_) => LIBMBUS_SCAN_DEF.to_string(),
-        }
+    /// How long finished jobs' results are kept
+    pub(crate) static ref JOB_RETENTION: Reloadable<Duration> = Reloadable::new(|| {
+        Duration::from_secs(setting(JOB_RETENTION_VAR, JOB_RETENTION_DEF))
+    });
+    /// Where the meter registry is kept
+    pub(crate) static ref METERS_FILE: String =
+        setting(METERS_FILE_VAR, METERS_FILE_DEF.to_string());
+    /// Most a poll is delayed by, so meters on the same schedule don't all
+    /// queue for the bus at once
+    pub(crate) static ref POLL_JITTER: Reloadable<Duration> = Reloadable::new(|| {
+        Duration::from_secs(setting(POLL_JITTER_VAR, POLL_JITTER_DEF))
+    });
+    pub(crate) static ref POLL_RETRIES: Reloadable<u32> =
+        Reloadable::new(|| setting(POLL_RETRIES_VAR, POLL_RETRIES_DEF));
+    /// Delay before retrying a failed poll, doubled for each further retry
+    pub(crate) static ref POLL_RETRY_DELAY: Reloadable<Duration> = Reloadable::new(|| {
+        Duration::from_secs(setting(POLL_RETRY_DELAY_VAR, POLL_RETRY_DELAY_DEF))
+    });
+    /// Where the reading history is kept
+    pub(crate) static ref HISTORY_FILE: String =
+        setting(HISTORY_FILE_VAR, HISTORY_FILE_DEF.to_string());
+    /// Days readings are kept for, or 0 to keep them forever
+    pub(crate) static ref HISTORY_RETENTION: Reloadable<u64> =
+        Reloadable::new(|| setting(HISTORY_RETENTION_VAR, HISTORY_RETENTION_DEF));
+    /// MQTT broker readings are published to, if any
+    pub(crate) static ref MQTT_BROKER: Reloadable<Option<String>> =
+        Reloadable::new(|| config::var(MQTT_BROKER_VAR));
+    pub(crate) static ref MQTT_PORT: Reloadable<u16> =
+        Reloadable::new(|| setting(MQTT_PORT_VAR, MQTT_PORT_DEF));
+    pub(crate) static ref MQTT_CLIENT_ID: Reloadable<String> =
+        Reloadable::new(|| setting(MQTT_CLIENT_ID_VAR, MQTT_CLIENT_ID_DEF.to_string()));
+    pub(crate) static ref MQTT_USERNAME: Reloadable<Option<String>> =
+        Reloadable::new(|| config::var(MQTT_USERNAME_VAR));
+    pub(crate) static ref MQTT_PASSWORD: Reloadable<Option<String>> =
+        Reloadable::new(|| config::var(MQTT_PASSWORD_VAR));
+    /// CA certificate to verify the broker with, connecting using TLS if set
+    pub(crate) static ref MQTT_CA_FILE: Reloadable<Option<String>> =
+        Reloadable::new(|| config::var(MQTT_CA_FILE_VAR));
+    /// Topic each data record of a reading is published to
+    pub(crate) static ref MQTT_TOPIC: Reloadable<String> =
+        Reloadable::new(|| setting(MQTT_TOPIC_VAR, MQTT_TOPIC_DEF.to_string()));
+    /// Topic scan results and M-Bus Master Hat changes are published to
+    pub(crate) static ref MQTT_EVENT_TOPIC: Reloadable<String> =
+        Reloadable::new(|| setting(MQTT_EVENT_TOPIC_VAR, MQTT_EVENT_TOPIC_DEF.to_string()));
+    pub(crate) static ref MQTT_QOS: Reloadable<u8> =
+        Reloadable::new(|| setting(MQTT_QOS_VAR, MQTT_QOS_DEF));
+    pub(crate) static ref MQTT_RETAIN: Reloadable<bool> =
+        Reloadable::new(|| setting(MQTT_RETAIN_VAR, MQTT_RETAIN_DEF));
+    /// Whether to publish Home Assistant discovery configs for each reading
+    pub(crate) static ref MQTT_DISCOVERY: Reloadable<bool> =
+        Reloadable::new(|| setting(MQTT_DISCOVERY_VAR, MQTT_DISCOVERY_DEF));
+    pub(crate) static ref MQTT_DISCOVERY_PREFIX: Reloadable<String> = Reloadable::new(|| {
+        setting(MQTT_DISCOVERY_PREFIX_VAR, MQTT_DISCOVERY_PREFIX_DEF.to_string())
+    });
+    /// Whether to export the latest value of each meter record as a metric
+    pub(crate) static ref METRICS_RECORD_VALUES: Reloadable<bool> =
+        Reloadable::new(|| setting(METRICS_RECORD_VALUES_VAR, METRICS_RECORD_VALUES_DEF));
+    /// InfluxDB write endpoint readings are exported to, if any
+    pub(crate) static ref INFLUX_URL: Reloadable<Option<String>> =
+        Reloadable::new(|| config::var(INFLUX_URL_VAR));
+    /// Token to authorize InfluxDB writes with
+    pub(crate) static ref INFLUX_TOKEN: Reloadable<Option<String>> =
+        Reloadable::new(|| config::var(INFLUX_TOKEN_VAR));
+    /// InfluxDB measurement readings are written to
+    pub(crate) static ref INFLUX_MEASUREMENT: Reloadable<String> =
+        Reloadable::new(|| setting(INFLUX_MEASUREMENT_VAR, INFLUX_MEASUREMENT_DEF.to_string()));
+    /// Most lines written to InfluxDB at once
+    pub(crate) static ref INFLUX_BATCH_SIZE: Reloadable<usize> =
+        Reloadable::new(|| setting(INFLUX_BATCH_SIZE_VAR, INFLUX_BATCH_SIZE_DEF));
+    /// Longest a line waits to be written to InfluxDB
+    pub(crate) static ref INFLUX_BATCH_INTERVAL: Reloadable<Duration> = Reloadable::new(|| {
+        Duration::from_secs(setting(INFLUX_BATCH_INTERVAL_VAR, INFLUX_BATCH_INTERVAL_DEF))
+    });
+    /// Times a failed InfluxDB write is retried
+    pub(crate) static ref INFLUX_RETRIES: Reloadable<u32> =
+        Reloadable::new(|| setting(INFLUX_RETRIES_VAR, INFLUX_RETRIES_DEF));
+    /// Delay before the first retry of an InfluxDB write, doubling each retry
+    pub(crate) static ref INFLUX_RETRY_DELAY: Reloadable<Duration> = Reloadable::new(|| {
+        Duration::from_secs(setting(INFLUX_RETRY_DELAY_VAR, INFLUX_RETRY_DELAY_DEF))
+    });
+    /// Where webhook registrations are persisted
+    pub(crate) static ref WEBHOOKS_FILE: String =
+        setting(WEBHOOKS_FILE_VAR, WEBHOOKS_FILE_DEF.to_string());
+    /// How long each webhook delivery attempt may take
+    pub(crate) static ref WEBHOOK_TIMEOUT: Reloadable<Duration> = Reloadable::new(|| {
+        Duration::from_secs(setting(WEBHOOK_TIMEOUT_VAR, WEBHOOK_TIMEOUT_DEF))
+    });
+    /// Times a failed webhook delivery is retried
+    pub(crate) static ref WEBHOOK_RETRIES: Reloadable<u32> =
+        Reloadable::new(|| setting(WEBHOOK_RETRIES_VAR, WEBHOOK_RETRIES_DEF));
+    /// Delay before the first retry of a webhook delivery, doubling each retry
+    pub(crate) static ref WEBHOOK_RETRY_DELAY: Reloadable<Duration> = Reloadable::new(|| {
+        Duration::from_secs(setting(WEBHOOK_RETRY_DELAY_VAR, WEBHOOK_RETRY_DELAY_DEF))
+    });
+    /// Most failed webhook deliveries kept
+    pub(crate) static ref WEBHOOK_DEAD_LETTERS: Reloadable<usize> =
+        Reloadable::new(|| setting(WEBHOOK_DEAD_LETTERS_VAR, WEBHOOK_DEAD_LETTERS_DEF));
+    /// Bearer token clients must send, if set
+    pub(crate) static ref API_TOKEN: Reloadable<Option<String>> =
+        Reloadable::new(|| config::var(API_TOKEN_VAR));
+    /// Requests waiting to use each bus, by device
+    static ref QUEUES: Mutex<HashMap<String, Arc<Queue>>> = Mutex::new(HashMap::new());
+}
+
+/// Read the settings which can change while running again, once the config
+/// file has been reloaded.
+pub(crate) fn reload() {
+    LIBMBUS_PATH.reload();
+    LIBMBUS_GET.reload();
+    LD_LIBRARY_PATH.reload();
+    OPERATION_TIMEOUT.reload();
+    JOB_RETENTION.reload();
+    POLL_JITTER.reload();
+    POLL_RETRIES.reload();
+    POLL_RETRY_DELAY.reload();
+    HISTORY_RETENTION.reload();
+    MQTT_BROKER.reload();
+    MQTT_PORT.reload();
+    MQTT_CLIENT_ID.reload();
+    MQTT_USERNAME.reload();
+    MQTT_PASSWORD.reload();
+    MQTT_CA_FILE.reload();
+    MQTT_TOPIC.reload();
+    MQTT_EVENT_TOPIC.reload();
+    MQTT_QOS.reload();
+    MQTT_RETAIN.reload();
+    MQTT_DISCOVERY.reload();
+    MQTT_DISCOVERY_PREFIX.reload();
+    METRICS_RECORD_VALUES.reload();
+    INFLUX_URL.reload();
+    INFLUX_TOKEN.reload();
+    INFLUX_MEASUREMENT.reload();
+    INFLUX_BATCH_SIZE.reload();
+    INFLUX_BATCH_INTERVAL.reload();
+    INFLUX_RETRIES.reload();
+    INFLUX_RETRY_DELAY.reload();
+    WEBHOOK_TIMEOUT.reload();
+    WEBHOOK_RETRIES.reload();
+    WEBHOOK_RETRY_DELAY.reload();
+    WEBHOOK_DEAD_LETTERS.reload();
+    API_TOKEN.reload();
+}
+
+/// The path of the bus on a device, resolving links like /dev/serial0 to
+/// the device they point to, so both name the same bus.
+pub(crate) fn bus_path(device: &str) -> String {
+    let dev = DEV_PREFIX.to_owned() + device;
+    match fs::canonicalize(&dev) {
+        Ok(path) => path.to_string_lossy().to_string(),
+        Err(_) => dev,
+    }
+}
+
+/// The queue for the bus on a device.  Each device is a separate bus, so
+/// operations on different devices can run at the same time.
+pub(crate) fn queue(device: &str) -> Arc<Queue> {
+    QUEUES
+        .lock()
+        .unwrap()
+        .entry(bus_path(device))
+        .or_insert_with(|| Arc::new(Queue::new(*QUEUE_LENGTH, *QUEUE_RETRY_AFTER)))
+        .clone()
+}
+
+/// The queue for each device used so far.
+pub(crate) fn queues() -> Vec<(String, Arc<Queue>)> {
+    QUEUES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(device, queue)| (device.clone(), queue.clone()))
+        .collect()
+}
+
+// How long an operation on a device may take - its bus's operation_timeout
+// from the config file, or OPERATION_TIMEOUT
+fn operation_timeout(device: &str) -> Duration {
+    match config::bus(device).and_then(|bus| bus.operation_timeout) {
+        Some(secs) => Duration::from_secs(secs),
+        None => OPERATION_TIMEOUT.get(),
+    }
+}
+
+// Run a bus operation on a blocking thread, holding the bus until it
+// finishes.  If it takes longer than the operation timeout, or the request is
+// dropped because the client disconnected, the operation is cancelled before
+// its next bus transaction.
+async fn run_blocking<T, F>(
+    operation: &'static str,
+    device: &str,
+    ticket: Ticket,
+    op: F,
+) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce(Cancel) -> Result<T, String> + Send + 'static,
+{
+    let cancel = Cancel::default();
+    let _guard = cancel.guard();
+    let op_cancel = cancel.clone();
+    let start = Instant::now();
+    let handle = task::spawn_blocking(move || {
+        let _ticket = ticket;
+        op(op_cancel)
+    });
+    let timeout = operation_timeout(device);
+    let rsp = match time::timeout(timeout, handle).await {
+        Ok(Ok(rsp)) => rsp,
+        Ok(Err(e)) => Err(format!("Bus operation failed: {}", e)),
+        Err(_) => Err(format!("Timed out after {}s", timeout.as_secs())),
     };
-    static ref GPIO: Pin = Pin::new(MBUS_MASTER_POWER_GPIO);
-    static ref BUS: Mutex<i32> = Mutex::new(0);
+    metrics::operation(metrics::NATIVE, operation, start.elapsed(), &rsp);
+    if let Err(ref e) = rsp {
+        webhooks::bus_error(device, operation, e);
+    }
+    rsp
+}
+
+fn open_bus(dev: &str, baudrate: &models::Baudrate, cancel: Cancel) -> Result<Bus, String> {
+    let mut bus = Bus::open(dev, baudrate)?;
+    bus.set_cancel(cancel);
+    Ok(bus)
 }
 
 pub(crate) fn api() -> MbusApiResponse {
@@ -189,11 +613,28 @@ fn hat_power(val: u8) -> Result<(), String> {
     }
 }
 
+/// Whether the M-Bus Master Hat's power is on, if it can be told without
+/// taking control of its GPIO.
+pub(crate) fn hat_state() -> Option<bool> {
+    if !hat_is_mbus_master() || !GPIO.is_exported() {
+        return None;
+    }
+    match GPIO.get_direction() {
+        Ok(Direction::Out) => GPIO.get_value().ok().map(|val| val != 0),
+        _ => None,
+    }
+}
+
 pub(crate) fn hat_off() -> HatOffResponse {
     info!("API {}", "hat_off");
 
     let rsp = match hat_power(0) {
-        Ok(_) => HatOffResponse::OK,
+        Ok(_) => {
+            metrics::hat_power(false);
+            mqtt::hat_power(false);
+            webhooks::hat_power(false);
+            HatOffResponse::OK
+        }
         Err(e) => HatOffResponse::NotFound(e),
     };
 
@@ -205,7 +646,12 @@ pub(crate) fn hat_on() -> HatOnResponse {
     info!("API {}", "hat_on");
 
     let rsp = match hat_power(1) {
-        Ok(_) => HatOnResponse::OK,
+        Ok(_) => {
+            metrics::hat_power(true);
+            mqtt::hat_power(true);
+            webhooks::hat_power(true);
+            HatOnResponse::OK
+        }
         Err(e) => HatOnResponse::NotFound(e),
     };
 
@@ -213,7 +659,7 @@ pub(crate) fn hat_on() -> HatOnResponse {
     rsp
 }
 
-fn check_address(address: &String) -> Result<(), String> {
+pub(crate) fn check_address(address: &String) -> Result<(), String> {
     let len = address.len();
     let err_s = format!("Not a valid primary or secondary address");
     if len == 16 {
@@ -240,7 +686,11 @@ fn check_address(address: &String) -> Result<(), String> {
     }
 }
 
-pub(crate) fn get(device: &String, baudrate: &models::Baudrate, address: &String) -> GetResponse {
+pub(crate) async fn get(
+    device: &String,
+    baudrate: &models::Baudrate,
+    address: &String,
+) -> GetResponse {
     info!("API {} : {:?} {:?} {:?}", "get", device, baudrate, address);
 
     // Check parameters
@@ -249,37 +699,55 @@ pub(crate) fn get(device: &String, baudrate: &models::Baudrate, address: &String
         Err(s) => return GetResponse::BadRequest(s),
     };
 
-    let lock = BUS.try_lock();
-    if lock.is_err() {
-        return GetResponse::NotFound("Bus is currently in use".to_string());
-    }
+    let rsp = match get_xml(device, baudrate, address).await {
+        // Should already be XML
+        Ok(s) => GetResponse::OK(s),
+        Err(e) => GetResponse::NotFound(e),
+    };
+
+    info!("API {} -> {:?}", "get", rsp);
+    rsp
+}
+
+/// Query a slave using libmbus, returning the XML it outputs.
+pub(crate) async fn get_xml(
+    device: &str,
+    baudrate: &models::Baudrate,
+    address: &str,
+) -> Result<String, String> {
+    let ticket = queue::ticket(&queue(device))
+        .await
+        .map_err(|e| e.to_string())?;
 
     // Construct mbus command like this:
     // mbus-serial-request-data [-d] [-b BAUDRATE] device mbus-address
-    let cmd = LIBMBUS_PATH.to_owned() + &LIBMBUS_GET;
+    let cmd = LIBMBUS_PATH.get() + &LIBMBUS_GET.get();
     let dev = DEV_PREFIX.to_owned() + device;
     info!("Executing: {} -b {} {} {}", cmd, baudrate, dev, address);
-    // XXX Todo - execute as a future
-    let rsp = match Command::new(cmd)
+    // The child is killed if it times out, or the client disconnects and
+    // this future is dropped
+    let start = Instant::now();
+    let mut command = Command::new(cmd);
+    if let Some(path) = LD_LIBRARY_PATH.get() {
+        command.env(LD_LIBRARY_PATH_VAR, path);
+    }
+    let output = command
         .arg("-b")
         .arg(baudrate.to_string())
         .arg(dev)
         .arg(address)
-        .output()
-    {
-        Ok(o) => {
+        .kill_on_drop(true)
+        .output();
+    let timeout = operation_timeout(device);
+    let rsp = match time::timeout(timeout, output).await {
+        Err(_) => Err(format!(
+            "Failed to query M-Bus: Timed out after {}s",
+            timeout.as_secs()
+        )),
+        Ok(Ok(o)) => {
             if o.status.success() {
-                match String::from_utf8(o.stdout) {
-                    // Should already be XML
-                    Ok(s) => {
-                        let x = serde_xml_rs::to_string(&s).unwrap();
-                        println!("s: {:?}", s);
-                        println!("x: {:?}", x);
-                        GetResponse::OK(s)
-                    }
-                    // Somehow failed to convert stdout to a string!
-                    Err(e) => GetResponse::NotFound(format!("Failed to query M-Bus: {:?}", e)),
-                }
+                // Somehow failed to convert stdout to a string!
+                String::from_utf8(o.stdout).map_err(|e| format!("Failed to query M-Bus: {:?}", e))
             } else {
                 // Process returned an error code
                 let code = match o.status.code() {
@@ -291,21 +759,31 @@ pub(crate) fn get(device: &String, baudrate: &models::Baudrate, address: &String
                     Ok(s) => s.to_string(),
                     Err(e) => format!("Failed to parse stderr {:?}", e),
                 };
-                GetResponse::NotFound(format!(
+                Err(format!(
                     "Failed to query M-Bus: Internal error, return code {}, stderr {}",
                     code, stderr
                 ))
             }
         }
         // Actually executing the process failed - couldn't find the process?
-        Err(e) => GetResponse::NotFound(format!("Failed to query M-Bus: Internal error {:?}", e)),
+        Ok(Err(e)) => Err(format!("Failed to query M-Bus: Internal error {:?}", e)),
     };
-
-    info!("API {} -> {:?}", "get", rsp);
+    drop(ticket);
+    metrics::operation(metrics::LIBMBUS, "get", start.elapsed(), &rsp);
+    match rsp {
+        Ok(ref xml) => {
+            if let Ok(reading) = data::from_xml(xml) {
+                metrics::reading(address, &reading);
+                mqtt::reading(device, address, &reading);
+                influx::reading(device, address, &reading);
+            }
+        }
+        Err(ref e) => webhooks::bus_error(device, "get", e),
+    }
     rsp
 }
 
-pub(crate) fn get_multi(
+pub(crate) async fn get_multi(
     device: &String,
     baudrate: &models::Baudrate,
     address: &String,
@@ -313,7 +791,7 @@ pub(crate) fn get_multi(
 ) -> GetMultiResponse {
     info!(
         "API {} : {:?} {:?} {:?} {:?}",
-        "get", device, baudrate, address, maxframes
+        "get_multi", device, baudrate, address, maxframes
     );
 
     // Check parameters
@@ -321,112 +799,505 @@ pub(crate) fn get_multi(
         Ok(_) => (),
         Err(s) => return GetMultiResponse::BadRequest(s),
     };
+    if *maxframes < 1 {
+        return GetMultiResponse::BadRequest(format!("Invalid maxframes: {}", maxframes));
+    }
+
+    let rsp = match get_multi_doc(device, baudrate, address, *maxframes as usize).await {
+        Ok(multi) => GetMultiResponse::OK(data::multi_reply_to_xml(&multi)),
+        Err(e) => GetMultiResponse::NotFound(e),
+    };
+
+    info!("API {} -> {:?}", "get_multi", rsp);
+    rsp
+}
 
-    let lock = BUS.try_lock();
-    if lock.is_err() {
-        return GetMultiResponse::NotFound("Bus is currently in use".to_string());
+/// Read up to `maxframes` frames from a slave.
+pub(crate) async fn get_multi_doc(
+    device: &str,
+    baudrate: &models::Baudrate,
+    address: &str,
+    maxframes: usize,
+) -> Result<data::MBusMultiReply, String> {
+    let ticket = queue::ticket(&queue(device))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let dev = DEV_PREFIX.to_owned() + device;
+    let address = address.to_ascii_uppercase();
+    info!(
+        "Requesting: up to {} frames from {} on {} at {}",
+        maxframes, address, dev, baudrate
+    );
+    let baudrate = *baudrate;
+    let op = {
+        let address = address.clone();
+        move |cancel| multi_reply(&dev, &baudrate, &address, maxframes, cancel)
+    };
+    let multi = run_blocking("get_multi", device, ticket, op)
+        .await
+        .map_err(|e| format!("Failed to query M-Bus: {}", e))?;
+    let reading = multi.merged();
+    metrics::reading(&address, &reading);
+    mqtt::reading(device, &address, &reading);
+    influx::reading(device, &address, &reading);
+    Ok(multi)
+}
+
+// Read frames from a slave until it has no more records, or maxframes
+fn multi_reply(
+    dev: &str,
+    baudrate: &models::Baudrate,
+    address: &str,
+    maxframes: usize,
+    cancel: Cancel,
+) -> Result<data::MBusMultiReply, String> {
+    let mut bus = open_bus(dev, baudrate, cancel)?;
+    let frames = bus.request_frames(address, maxframes)?;
+    let mut multi = data::MBusMultiReply::default();
+    for (index, frame) in frames.into_iter().enumerate() {
+        let mut f = data::MultiReplyFrame {
+            index,
+            fcb: frame.fcb,
+            ..Default::default()
+        };
+        match frame.reply {
+            Ok(reply) => {
+                f.more_records_follow = reply.data.more_records_follow;
+                f.status = data::FRAME_OK.to_string();
+                f.data = Some(data::MBusData::from(&reply.data));
+            }
+            Err(e) => {
+                f.status = data::FRAME_ERROR.to_string();
+                f.error = Some(e);
+            }
+        }
+        multi.frames.push(f);
     }
+    multi.complete = match multi.frames.last() {
+        Some(f) => f.status == data::FRAME_OK && !f.more_records_follow,
+        None => false,
+    };
+    Ok(multi)
+}
 
-    // Construct mbus command like this:
-    // mbus-serial-request-data [-d] [-b BAUDRATE] device mbus-address
-    let cmd = LIBMBUS_PATH.to_owned() + &LIBMBUS_GET_MULTI;
+pub(crate) async fn scan(device: &String, baudrate: &models::Baudrate) -> ScanResponse {
+    info!("API {} : {:?} {:?}", "scan", device, baudrate);
+
+    let rsp = match scan_doc(device, baudrate, Progress::default()).await {
+        Ok(scan) => ScanResponse::OK(data::scan_to_xml(&scan)),
+        Err(e) => ScanResponse::NotFound(e),
+    };
+
+    info!("API {} -> {:?}", "scan", rsp);
+    rsp
+}
+
+/// Scan for slaves by primary address, recording progress as it goes.
+pub(crate) async fn scan_doc(
+    device: &str,
+    baudrate: &models::Baudrate,
+    progress: Progress,
+) -> Result<data::MBusScan, String> {
+    let ticket = queue::ticket(&queue(device))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let dev = DEV_PREFIX.to_owned() + device;
+    info!("Scanning: {} at {}", dev, baudrate);
+    let baudrate = *baudrate;
+    let op = {
+        let device = device.to_string();
+        move |cancel| scan_primary(&device, &baudrate, cancel, &progress)
+    };
+    let scan = run_blocking("scan", device, ticket, op)
+        .await
+        .map_err(|e| format!("Failed to scan M-Bus: {}", e))?;
+    mqtt::scan(device, &scan);
+    webhooks::scan(device, None, &scan);
+    Ok(scan)
+}
+
+// Ping each primary address in turn, and ask any slave which answers for
+// its identity
+fn scan_primary(
+    device: &str,
+    baudrate: &models::Baudrate,
+    cancel: Cancel,
+    progress: &Progress,
+) -> Result<data::MBusScan, String> {
     let dev = DEV_PREFIX.to_owned() + device;
+    let mut bus = open_bus(&dev, baudrate, cancel)?;
+    let mut scan = data::MBusScan::default();
+    for address in 0..=bus::PRIMARY_ADDRESS_MAX {
+        let probe = bus.ping(address)?;
+        let mut probed = data::ScanProbe {
+            address: Some(address),
+            ..Default::default()
+        };
+        let mut slave = data::ScanSlave {
+            address: Some(address),
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            ..Default::default()
+        };
+        match probe {
+            Probe::None => {
+                probed.result = data::PROBE_NONE.to_string();
+                progress.probed(probed);
+                continue;
+            }
+            Probe::Collision(e) => {
+                info!("Collision at address {}: {}", address, e);
+                probed.result = data::PROBE_COLLISION.to_string();
+                probed.error = Some(e.clone());
+                progress.probed(probed);
+                slave.collision = Some(e);
+            }
+            Probe::Ack => {
+                info!("Found M-Bus device at address {}", address);
+                probed.result = data::PROBE_RESPONSE.to_string();
+                progress.probed(probed);
+                match bus.request_data(address) {
+                    Ok(reply) => {
+                        slave.identify(&reply.data.header);
+                        let reading = data::MBusData::from(&reply.data);
+                        mqtt::discover(device, &address.to_string(), &reading);
+                    }
+                    Err(e) => info!("No data from address {}: {}", address, e),
+                }
+                progress.found(&slave);
+            }
+        }
+        scan.slaves.push(slave);
+    }
+    Ok(scan)
+}
+
+pub(crate) async fn scan_secondary(
+    device: &String,
+    baudrate: &models::Baudrate,
+    mask: &Option<String>,
+) -> ScanSecondaryResponse {
     info!(
-        "Executing: {} -b {} -f {} {} {}",
-        cmd, baudrate, maxframes, dev, address
+        "API {} : {:?} {:?} {:?}",
+        "scan_secondary", device, baudrate, mask
     );
-    // XXX Todo - execute as a future
-    let rsp = match Command::new(cmd)
-        .arg("-b")
-        .arg(baudrate.to_string())
-        .arg(dev)
-        .arg(address)
-        .output()
-    {
-        Ok(o) => {
-            if o.status.success() {
-                match str::from_utf8(&o.stdout) {
-                    // Should already be XML
-                    Ok(s) => GetMultiResponse::OK(s.to_string()),
-                    // Somehow failed to convert stdout to a string!
-                    Err(e) => GetMultiResponse::NotFound(format!("Failed to query M-Bus: {:?}", e)),
+
+    // Check parameters
+    let mask = match mask {
+        Some(mask) => mask.to_ascii_uppercase(),
+        None => bus::SECONDARY_WILDCARD.to_string(),
+    };
+    match bus::pack_secondary(&mask) {
+        Ok(_) => (),
+        Err(s) => return ScanSecondaryResponse::BadRequest(s),
+    };
+
+    let rsp = match scan_secondary_doc(device, baudrate, &mask, Progress::default()).await {
+        Ok(scan) => ScanSecondaryResponse::OK(scan),
+        Err(e) => ScanSecondaryResponse::NotFound(e),
+    };
+
+    info!("API {} -> {:?}", "scan_secondary", rsp);
+    rsp
+}
+
+/// Scan for slaves matching a secondary address mask, recording progress
+/// as it goes.
+pub(crate) async fn scan_secondary_doc(
+    device: &str,
+    baudrate: &models::Baudrate,
+    mask: &str,
+    progress: Progress,
+) -> Result<data::MBusScan, String> {
+    let ticket = queue::ticket(&queue(device))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let dev = DEV_PREFIX.to_owned() + device;
+    let mask = mask.to_ascii_uppercase();
+    info!("Scanning: {} at {} for {}", dev, baudrate, mask);
+    let baudrate = *baudrate;
+    let op = {
+        let device = device.to_string();
+        let mask = mask.clone();
+        move |cancel| scan_secondary_mask(&device, &baudrate, &mask, cancel, &progress)
+    };
+    let scan = run_blocking("scan_secondary", device, ticket, op)
+        .await
+        .map_err(|e| format!("Failed to scan M-Bus: {}", e))?;
+    mqtt::scan(device, &scan);
+    webhooks::scan(device, Some(&mask), &scan);
+    Ok(scan)
+}
+
+fn scan_secondary_mask(
+    device: &str,
+    baudrate: &models::Baudrate,
+    mask: &str,
+    cancel: Cancel,
+    progress: &Progress,
+) -> Result<data::MBusScan, String> {
+    let dev = DEV_PREFIX.to_owned() + device;
+    let mut bus = open_bus(&dev, baudrate, cancel)?;
+    let found = bus.scan_secondary_with(mask, &mut |mask, probe| {
+        let mut probed = data::ScanProbe {
+            secondary_address: Some(mask.to_string()),
+            ..Default::default()
+        };
+        probed.result = match probe {
+            SecondaryProbe::None => data::PROBE_NONE,
+            SecondaryProbe::Single(_) => data::PROBE_RESPONSE,
+            SecondaryProbe::Collision(e) => {
+                probed.error = Some(e.clone());
+                data::PROBE_COLLISION
+            }
+        }
+        .to_string();
+        progress.probed(probed);
+        if let SecondaryProbe::Single(reply) = probe {
+            let mut slave = data::ScanSlave {
+                address: Some(reply.address),
+                time: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                ..Default::default()
+            };
+            slave.identify(&reply.data.header);
+            let reading = data::MBusData::from(&reply.data);
+            mqtt::discover(device, &reply.address.to_string(), &reading);
+            progress.found(&slave);
+        }
+    })?;
+    let time = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let slaves = found
+        .into_iter()
+        .map(|found| {
+            let mut slave = data::ScanSlave {
+                time: time.clone(),
+                ..Default::default()
+            };
+            match found {
+                SecondaryFound::Slave(reply) => {
+                    slave.address = Some(reply.address);
+                    slave.identify(&reply.data.header);
+                }
+                SecondaryFound::Collision { mask, error } => {
+                    info!("Unresolved collision for {}: {}", mask, error);
+                    slave.secondary_address = Some(mask);
+                    slave.collision = Some(error);
+                }
+            }
+            slave
+        })
+        .collect();
+    Ok(data::MBusScan { slaves })
+}
+
+const MASK_ERR: &str = "Secondary address can't contain F wildcards";
+
+// Whether a secondary address has wildcards, so could select more than one
+// slave
+fn is_mask(address: &str) -> bool {
+    address.contains(&['F', 'f'][..])
+}
+
+pub(crate) async fn select(
+    device: &String,
+    baudrate: &models::Baudrate,
+    address: &String,
+) -> SelectResponse {
+    info!(
+        "API {} : {:?} {:?} {:?}",
+        "select", device, baudrate, address
+    );
+
+    // Check parameters - must be a secondary address
+    match check_address(address) {
+        Ok(_) if address.len() == bus::SECONDARY_LEN => (),
+        Ok(_) => return SelectResponse::BadRequest("Not a valid secondary address".to_string()),
+        Err(s) => return SelectResponse::BadRequest(s),
+    };
+    let address = address.to_ascii_uppercase();
+    match bus::pack_secondary(&address) {
+        Ok(_) if is_mask(&address) => return SelectResponse::BadRequest(MASK_ERR.to_string()),
+        Ok(_) => (),
+        Err(s) => return SelectResponse::BadRequest(s),
+    };
+
+    let ticket = match queue::ticket(&queue(device)).await {
+        Ok(ticket) => ticket,
+        Err(e) => return SelectResponse::NotFound(e.to_string()),
+    };
+
+    let dev = DEV_PREFIX.to_owned() + device;
+    info!("Selecting: {} at {} on {}", address, baudrate, dev);
+    let baudrate = *baudrate;
+    let select_address = address.clone();
+    let op = move |cancel| open_bus(&dev, &baudrate, cancel)?.select(&select_address);
+    let rsp = match run_blocking("select", device, ticket, op).await {
+        Ok(probe) => {
+            let mut select = data::MBusSelect {
+                secondary_address: address,
+                ..Default::default()
+            };
+            select.result = match probe {
+                Probe::Ack => data::SELECT_SINGLE,
+                Probe::None => data::SELECT_NONE,
+                Probe::Collision(e) => {
+                    select.collision = Some(e);
+                    data::SELECT_COLLISION
                 }
-            } else {
-                // Process returned an error code
-                let code = match o.status.code() {
-                    Some(c) => c.to_string(),
-                    None => "None".to_string(),
-                };
-                // Get stderr (not stdout)
-                let stderr = match str::from_utf8(&o.stderr) {
-                    Ok(s) => s.to_string(),
-                    Err(e) => format!("Failed to parse stderr {:?}", e),
-                };
-                GetMultiResponse::NotFound(format!(
-                    "Failed to query M-Bus: Internal error, return code {}, stderr {}",
-                    code, stderr
-                ))
             }
+            .to_string();
+            SelectResponse::OK(select)
         }
-        // Actually executing the process failed - couldn't find the process?
-        Err(e) => {
-            GetMultiResponse::NotFound(format!("Failed to query M-Bus: Internal error {:?}", e))
+        Err(e) => SelectResponse::NotFound(format!("Failed to select on M-Bus: {}", e)),
+    };
+
+    info!("API {} -> {:?}", "select", rsp);
+    rsp
+}
+
+pub(crate) async fn set_address(
+    device: &String,
+    baudrate: &models::Baudrate,
+    secondary: &String,
+    address: &String,
+) -> SetAddressResponse {
+    info!(
+        "API {} : {:?} {:?} {:?} {:?}",
+        "set_address", device, baudrate, secondary, address
+    );
+
+    // Check parameters - the slave must be identified by secondary address,
+    // and given a primary address a slave can be configured with
+    match check_address(secondary) {
+        Ok(_) if secondary.len() == bus::SECONDARY_LEN => (),
+        Ok(_) => {
+            return SetAddressResponse::BadRequest("Not a valid secondary address".to_string())
+        }
+        Err(s) => return SetAddressResponse::BadRequest(s),
+    };
+    let secondary = secondary.to_ascii_uppercase();
+    match bus::pack_secondary(&secondary) {
+        Ok(_) if is_mask(&secondary) => {
+            return SetAddressResponse::BadRequest(MASK_ERR.to_string())
         }
+        Ok(_) => (),
+        Err(s) => return SetAddressResponse::BadRequest(s),
+    };
+    let new_address = match check_address(address) {
+        Ok(_) if address.len() != bus::SECONDARY_LEN => match address.parse::<u8>() {
+            Ok(a) if a <= bus::PRIMARY_ADDRESS_MAX => a,
+            _ => {
+                return SetAddressResponse::BadRequest(format!(
+                    "Primary address must be 0-{}",
+                    bus::PRIMARY_ADDRESS_MAX
+                ))
+            }
+        },
+        Ok(_) => return SetAddressResponse::BadRequest("Not a valid primary address".to_string()),
+        Err(s) => return SetAddressResponse::BadRequest(s),
     };
 
-    info!("API {} -> {:?}", "get_multi", rsp);
+    let ticket = match queue::ticket(&queue(device)).await {
+        Ok(ticket) => ticket,
+        Err(e) => return SetAddressResponse::NotFound(e.to_string()),
+    };
+
+    let dev = DEV_PREFIX.to_owned() + device;
+    info!(
+        "Setting address: {} to {} at {} on {}",
+        secondary, new_address, baudrate, dev
+    );
+    let baudrate = *baudrate;
+    let set_secondary = secondary.clone();
+    let op = move |cancel| {
+        let mut bus = open_bus(&dev, &baudrate, cancel)?;
+        Ok(bus.set_primary_address(&set_secondary, new_address))
+    };
+    let rsp = match run_blocking("set_address", device, ticket, op).await {
+        Ok(result) => {
+            let mut set = data::MBusSetAddress {
+                secondary_address: secondary,
+                address: new_address,
+                ..Default::default()
+            };
+            set.result = match result {
+                Ok(_) => data::RESULT_SUCCESS,
+                Err(e) => {
+                    set.error = Some(e);
+                    data::RESULT_FAILURE
+                }
+            }
+            .to_string();
+            SetAddressResponse::OK(set)
+        }
+        Err(e) => SetAddressResponse::NotFound(format!("Failed to set address: {}", e)),
+    };
+
+    info!("API {} -> {:?}", "set_address", rsp);
     rsp
 }
 
-pub(crate) fn scan(device: &String, baudrate: &models::Baudrate) -> ScanResponse {
-    info!("API {} : {:?} {:?}", "scan", device, baudrate);
+pub(crate) async fn switch_baudrate(
+    device: &String,
+    baudrate: &models::Baudrate,
+    address: &String,
+    new_baudrate: &models::Baudrate,
+) -> SwitchBaudrateResponse {
+    info!(
+        "API {} : {:?} {:?} {:?} {:?}",
+        "switch_baudrate", device, baudrate, address, new_baudrate
+    );
 
-    let lock = BUS.try_lock();
-    if lock.is_err() {
-        return ScanResponse::NotFound("Bus is currently in use".to_string());
-    }
+    // Check parameters
+    match check_address(address) {
+        Ok(_) => (),
+        Err(s) => return SwitchBaudrateResponse::BadRequest(s),
+    };
+    let address = address.to_ascii_uppercase();
+    let bps = match serial::baudrate_bps(new_baudrate) {
+        Ok(bps) => bps,
+        Err(s) => return SwitchBaudrateResponse::BadRequest(s),
+    };
+
+    let ticket = match queue::ticket(&queue(device)).await {
+        Ok(ticket) => ticket,
+        Err(e) => return SwitchBaudrateResponse::NotFound(e.to_string()),
+    };
 
-    // Construct libmbus exec like this:
-    // mbus-serial-scan [-d] [-b BAUDRATE] [-r RETRIES] device
-    let cmd = LIBMBUS_PATH.to_owned() + &LIBMBUS_SCAN;
     let dev = DEV_PREFIX.to_owned() + device;
-    info!("Executing: {} -b {} {}", cmd, baudrate, dev);
-    // XXX Todo - execute as a future
-    let rsp = match Command::new(cmd)
-        .arg("-b")
-        .arg(baudrate.to_string())
-        .arg(dev)
-        .output()
-    {
-        // XXX Don't duplicate following code
-        Ok(o) => {
-            if o.status.success() {
-                match str::from_utf8(&o.stdout) {
-                    Ok(s) => ScanResponse::OK(s.to_string()), // XXX Actually, need to convert to XML
-                    // Somehow failed to convert stdout to a string!
-                    Err(e) => ScanResponse::NotFound(format!("Failed to query M-Bus: {:?}", e)),
+    info!(
+        "Switching: {} on {} from {} to {}",
+        address, dev, baudrate, new_baudrate
+    );
+    let open_baudrate = *baudrate;
+    let switch_address = address.clone();
+    let op = move |cancel| {
+        let mut bus = open_bus(&dev, &open_baudrate, cancel)?;
+        Ok(bus.switch_baudrate(&switch_address, bps))
+    };
+    let rsp = match run_blocking("switch_baudrate", device, ticket, op).await {
+        Ok(result) => {
+            let mut switch = data::MBusSwitchBaudrate {
+                address,
+                baudrate: baudrate.to_string(),
+                new_baudrate: new_baudrate.to_string(),
+                ..Default::default()
+            };
+            switch.result = match result {
+                Ok(_) => data::RESULT_SUCCESS,
+                Err(e) => {
+                    switch.error = Some(e);
+                    data::RESULT_FAILURE
                 }
-            } else {
-                // Process returned an error code
-                let code = match o.status.code() {
-                    Some(c) => c.to_string(),
-                    None => "None".to_string(),
-                };
-                // Get stderr (not stdout)
-                let stderr = match str::from_utf8(&o.stderr) {
-                    Ok(s) => s.to_string(),
-                    Err(e) => format!("Failed to parse stderr {:?}", e),
-                };
-                ScanResponse::NotFound(format!(
-                    "Failed to query M-Bus: Internal error, return code {}, stderr {}",
-                    code, stderr
-                ))
             }
+            .to_string();
+            SwitchBaudrateResponse::OK(switch)
         }
-        // Actually executing the process failed - couldn't find the process?
-        Err(e) => ScanResponse::NotFound(format!("Failed to query M-Bus: Internal error {:?}", e)),
+        Err(e) => SwitchBaudrateResponse::NotFound(format!("Failed to switch baudrate: {}", e)),
     };
 
-    info!("API {} -> {:?}", "scan", rsp);
+    info!("API {} -> {:?}", "switch_baudrate", rsp);
     rsp
 }
@@ -0,0 +1,408 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! InfluxDB export - formats readings as line protocol, and, if INFLUX_URL
+//! is set, writes them to InfluxDB in batches from the background.
+
+use chrono::{DateTime, Utc};
+use hyper::client::HttpConnector;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Client, Request, Uri};
+use hyper_tls::HttpsConnector;
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+
+use crate::data;
+use crate::http;
+
+lazy_static! {
+    /// Lines waiting to be written, once started
+    static ref LINES: Mutex<Option<mpsc::UnboundedSender<String>>> = Mutex::new(None);
+}
+
+// Escape a measurement name
+fn measurement(name: &str) -> String {
+    name.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+// Escape a tag key or value, or a field key
+fn key(key: &str) -> String {
+    key.replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+// Quote a string field value
+fn string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A reading of the slave at `address` as InfluxDB line protocol, one line
+/// per data record, timestamped `time`.
+pub fn lines(
+    device: &str,
+    address: &str,
+    reading: &data::MBusData,
+    time: DateTime<Utc>,
+) -> Vec<String> {
+    let info = &reading.slave_information;
+    let secondary_id = reading.secondary_id(address);
+    let nanos = format!("{}{:09}", time.timestamp(), time.timestamp_subsec_nanos());
+    reading
+        .data_records
+        .iter()
+        .enumerate()
+        .map(|(ii, record)| {
+            let id = match record.id {
+                Some(ref id) => id.clone(),
+                None => ii.to_string(),
+            };
+            // Empty tag values aren't allowed, so are left out
+            let tags = [
+                ("device", Some(device)),
+                ("secondary_id", Some(secondary_id.as_str())),
+                ("manufacturer", info.manufacturer.as_deref()),
+                ("medium", info.medium.as_deref()),
+                ("record", Some(id.as_str())),
+            ]
+            .iter()
+            .filter_map(|(name, value)| match value {
+                Some(value) if !value.is_empty() => Some(format!(",{}={}", name, key(value))),
+                _ => None,
+            })
+            .collect::<String>();
+
+            // Numeric and text values are written to different fields, as a
+            // field's type can't change
+            let mut fields = Vec::new();
+            match record.value.as_deref() {
+                Some(value) => match value.parse::<f64>() {
+                    Ok(number) if number.is_finite() => fields.push(format!("value={}", number)),
+                    _ => fields.push(format!("text={}", string(value))),
+                },
+                None => fields.push("text=\"\"".to_string()),
+            }
+            for (name, value) in &[
+                ("unit", &record.unit),
+                ("function", &record.function),
+                ("storage_number", &record.storage_number),
+                ("tariff", &record.tariff),
+            ] {
+                if let Some(value) = value {
+                    fields.push(format!("{}={}", name, string(value)));
+                }
+            }
+
+            format!(
+                "{}{} {} {}",
+                measurement(&http::INFLUX_MEASUREMENT.get()),
+                tags,
+                fields.join(","),
+                nanos
+            )
+        })
+        .collect()
+}
+
+/// Start writing to INFLUX_URL, if set.  Starting again, when the config is
+/// reloaded, replaces the earlier writer, which finishes writing the lines
+/// already queued for it.
+pub fn start() -> Result<(), String> {
+    let url = match http::INFLUX_URL.get() {
+        Some(url) => url
+            .parse::<Uri>()
+            .map_err(|e| format!("Invalid InfluxDB URL {}: {}", url, e))?,
+        None => {
+            *LINES.lock().unwrap() = None;
+            return Ok(());
+        }
+    };
+    let (tx, rx) = mpsc::unbounded_channel();
+    *LINES.lock().unwrap() = Some(tx);
+    tokio::spawn(writer(url, rx));
+    Ok(())
+}
+
+/// Queue a reading of the slave at `address` to be written to InfluxDB.
+pub fn reading(device: &str, address: &str, reading: &data::MBusData) {
+    let sender = LINES.lock().unwrap();
+    if let Some(ref sender) = *sender {
+        for line in lines(device, address, reading, Utc::now()) {
+            let _ = sender.send(line);
+        }
+    }
+}
+
+// Write batches of lines until the writer is replaced
+async fn writer(url: Uri, mut rx: mpsc::UnboundedReceiver<String>) {
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    loop {
+        let size = http::INFLUX_BATCH_SIZE.get();
+        let interval = http::INFLUX_BATCH_INTERVAL.get();
+        let batch = match batch(&mut rx, size, interval).await {
+            Some(batch) => batch,
+            None => return,
+        };
+        let retries = http::INFLUX_RETRIES.get();
+        let delay = http::INFLUX_RETRY_DELAY.get();
+        write(&client, &url, batch, retries, delay).await;
+    }
+}
+
+// The next batch of lines, once it has `size` lines or its first line has
+// waited `interval`, or None once no more lines will be sent
+async fn batch(
+    rx: &mut mpsc::UnboundedReceiver<String>,
+    size: usize,
+    interval: Duration,
+) -> Option<Vec<String>> {
+    let mut batch = vec![rx.recv().await?];
+    let deadline = time::Instant::now() + interval;
+    while batch.len() < size {
+        match time::timeout_at(deadline, rx.recv()).await {
+            Ok(Some(line)) => batch.push(line),
+            _ => break,
+        }
+    }
+    Some(batch)
+}
+
+// Write a batch, retrying if InfluxDB can't be reached or has a problem,
+// waiting `delay` before the first retry and doubling it each retry
+async fn write(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    url: &Uri,
+    batch: Vec<String>,
+    retries: u32,
+    mut delay: Duration,
+) {
+    let body = batch.join("\n");
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            time::delay_for(delay).await;
+            delay *= 2;
+        }
+        let mut req = Request::post(url.clone()).header(CONTENT_TYPE, "text/plain; charset=utf-8");
+        if let Some(token) = http::INFLUX_TOKEN.get() {
+            req = req.header(AUTHORIZATION, format!("Token {}", token));
+        }
+        let req = match req.body(Body::from(body.clone())) {
+            Ok(req) => req,
+            Err(e) => {
+                warn!("Failed to build InfluxDB write: {}", e);
+                return;
+            }
+        };
+        match client.request(req).await {
+            Ok(rsp) if rsp.status().is_success() => {
+                debug!("Wrote {} lines to InfluxDB", batch.len());
+                return;
+            }
+            // InfluxDB rejected the lines themselves, so they won't be
+            // accepted if retried
+            Ok(rsp) if rsp.status().is_client_error() && rsp.status().as_u16() != 429 => {
+                let status = rsp.status();
+                let detail = hyper::body::to_bytes(rsp.into_body())
+                    .await
+                    .map(|b| String::from_utf8_lossy(&b).to_string())
+                    .unwrap_or_default();
+                warn!(
+                    "InfluxDB rejected {} lines: {} {}",
+                    batch.len(),
+                    status,
+                    detail
+                );
+                return;
+            }
+            Ok(rsp) => warn!("Failed to write to InfluxDB: {}", rsp.status()),
+            Err(e) => warn!("Failed to write to InfluxDB: {}", e),
+        }
+    }
+    warn!(
+        "Dropped {} lines after {} retries writing to InfluxDB",
+        batch.len(),
+        retries
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use std::convert::Infallible;
+    use std::sync::Arc;
+
+    fn record(id: &str, unit: &str, value: &str) -> data::DataRecord {
+        data::DataRecord {
+            id: Some(id.to_string()),
+            function: Some("Instantaneous value".to_string()),
+            storage_number: Some("0".to_string()),
+            unit: Some(unit.to_string()),
+            value: Some(value.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn time() -> DateTime<Utc> {
+        "2020-05-17T13:45:30.000000123Z".parse().unwrap()
+    }
+
+    #[test]
+    fn numeric_and_text_values() {
+        let reading = data::MBusData {
+            slave_information: data::SlaveInformation {
+                id: Some("12345678".to_string()),
+                manufacturer: Some("PAD".to_string()),
+                version: Some("1".to_string()),
+                medium: Some("Water".to_string()),
+                ..Default::default()
+            },
+            data_records: vec![
+                record("0", "Volume (m m^3)", "12565"),
+                record("1", "Model / Version", "ABC"),
+            ],
+        };
+        let lines = lines("ttyAMA0", "48", &reading, time());
+        assert_eq!(
+            lines,
+            vec![
+                "mbus,device=ttyAMA0,secondary_id=1234567824400107,manufacturer=PAD,medium=Water,record=0 \
+                 value=12565,unit=\"Volume (m m^3)\",function=\"Instantaneous value\",\
+                 storage_number=\"0\" 1589723130000000123",
+                "mbus,device=ttyAMA0,secondary_id=1234567824400107,manufacturer=PAD,medium=Water,record=1 \
+                 text=\"ABC\",unit=\"Model / Version\",function=\"Instantaneous value\",\
+                 storage_number=\"0\" 1589723130000000123",
+            ]
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        let reading = data::MBusData {
+            slave_information: data::SlaveInformation {
+                manufacturer: Some("A,B C=D".to_string()),
+                medium: Some("".to_string()),
+                ..Default::default()
+            },
+            data_records: vec![record("a b", "Say \"hi\\\"", "not a number")],
+        };
+        let lines = lines("tty, =", "48", &reading, time());
+        assert_eq!(
+            lines,
+            vec![
+                "mbus,device=tty\\,\\ \\=,secondary_id=48,manufacturer=A\\,B\\ C\\=D,\
+                 record=a\\ b text=\"not a number\",unit=\"Say \\\"hi\\\\\\\"\",\
+                 function=\"Instantaneous value\",storage_number=\"0\" 1589723130000000123"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn batches() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        for ii in 0..5 {
+            tx.send(ii.to_string()).unwrap();
+        }
+        let interval = Duration::from_millis(50);
+
+        // Full batches are written straight away
+        assert_eq!(
+            batch(&mut rx, 3, Duration::from_secs(60)).await,
+            Some(vec!["0".to_string(), "1".to_string(), "2".to_string()])
+        );
+        // Others once the first line has waited the interval
+        let start = time::Instant::now();
+        assert_eq!(
+            batch(&mut rx, 3, interval).await,
+            Some(vec!["3".to_string(), "4".to_string()])
+        );
+        assert!(start.elapsed() >= interval);
+
+        drop(tx);
+        assert_eq!(batch(&mut rx, 3, interval).await, None);
+    }
+
+    // An InfluxDB stand-in, giving each write the next status, or 204 once
+    // they run out, and keeping the bodies written
+    fn influx(statuses: Vec<u16>) -> (Uri, Arc<Mutex<Vec<String>>>) {
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let statuses = Arc::new(Mutex::new(statuses.into_iter()));
+        let written = bodies.clone();
+        let make = make_service_fn(move |_| {
+            let bodies = bodies.clone();
+            let statuses = statuses.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let bodies = bodies.clone();
+                    let status = statuses.lock().unwrap().next().unwrap_or(204);
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        bodies
+                            .lock()
+                            .unwrap()
+                            .push(String::from_utf8_lossy(&body).to_string());
+                        Response::builder().status(status).body(Body::from("error"))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+        let url = format!("http://{}/write?db=mbus", server.local_addr());
+        tokio::spawn(server);
+        (url.parse().unwrap(), written)
+    }
+
+    async fn write_to(statuses: Vec<u16>, retries: u32) -> Vec<String> {
+        let (url, bodies) = influx(statuses);
+        let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+        let batch = vec!["a value=1 1".to_string(), "b value=2 2".to_string()];
+        write(&client, &url, batch, retries, Duration::from_millis(1)).await;
+        let bodies = bodies.lock().unwrap();
+        bodies.clone()
+    }
+
+    #[tokio::test]
+    async fn writes_batch() {
+        let bodies = write_to(vec![], 3).await;
+        assert_eq!(bodies, vec!["a value=1 1\nb value=2 2"]);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let bodies = write_to(vec![500, 503, 429], 3).await;
+        assert_eq!(bodies.len(), 4);
+        assert!(bodies.iter().all(|b| b == "a value=1 1\nb value=2 2"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let bodies = write_to(vec![500; 10], 2).await;
+        assert_eq!(bodies.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn doesnt_retry_rejected_lines() {
+        let bodies = write_to(vec![400], 3).await;
+        assert_eq!(bodies.len(), 1);
+    }
+}
@@ -0,0 +1,374 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Background jobs, for bus operations which take too long to wait for.
+//! A job runs in the device's queue like any other request, and its
+//! progress and result are kept until JOB_RETENTION after it finishes.
+
+use chrono::{SecondsFormat, Utc};
+use futures::future::{self, AbortHandle};
+use lazy_static::lazy_static;
+use log::info;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time;
+use uuid::Uuid;
+
+use mbus_api::models;
+
+use crate::bus;
+use crate::data::{self, JobProgress, JobRequest, JobResult, MBusJob};
+use crate::http;
+use crate::progress::Progress;
+use crate::queue::{self, Priority};
+
+// A validated job request
+enum Operation {
+    Scan,
+    ScanSecondary { mask: String },
+    Get { address: String },
+    GetMulti { address: String, maxframes: usize },
+}
+
+struct Job {
+    doc: Arc<Mutex<MBusJob>>,
+    progress: Progress,
+    abort: AbortHandle,
+}
+
+impl Job {
+    fn snapshot(&self) -> MBusJob {
+        let mut doc = self.doc.lock().unwrap().clone();
+        doc.progress = self.progress.get();
+        doc
+    }
+}
+
+lazy_static! {
+    /// Jobs which are running, or finished within JOB_RETENTION, by ID
+    static ref JOBS: Mutex<HashMap<String, Job>> = Mutex::new(HashMap::new());
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn check(request: &JobRequest) -> Result<(models::Baudrate, Operation, Priority), String> {
+    if request.device.is_empty() {
+        return Err("No device".to_string());
+    }
+    let baudrate = request
+        .baudrate
+        .parse::<models::Baudrate>()
+        .map_err(|_| format!("Invalid baudrate: {}", request.baudrate))?;
+    let address = || match request.address {
+        Some(ref address) => http::check_address(address).map(|_| address.clone()),
+        None => Err(format!("No address for {}", request.operation)),
+    };
+    let operation = match request.operation.as_str() {
+        data::JOB_SCAN => Operation::Scan,
+        data::JOB_SCAN_SECONDARY => {
+            let mask = match request.mask {
+                Some(ref mask) => mask.to_ascii_uppercase(),
+                None => bus::SECONDARY_WILDCARD.to_string(),
+            };
+            bus::pack_secondary(&mask)?;
+            Operation::ScanSecondary { mask }
+        }
+        data::JOB_GET => Operation::Get {
+            address: address()?,
+        },
+        data::JOB_GET_MULTI => match request.max_frames {
+            Some(maxframes) if maxframes >= 1 => Operation::GetMulti {
+                address: address()?,
+                maxframes: maxframes as usize,
+            },
+            Some(maxframes) => return Err(format!("Invalid maxframes: {}", maxframes)),
+            None => return Err(format!("No maxframes for {}", request.operation)),
+        },
+        op => return Err(format!("Invalid operation: {}", op)),
+    };
+    let priority = match request.priority {
+        Some(ref priority) => priority.parse::<Priority>()?,
+        None => Priority::Interactive,
+    };
+    Ok((baudrate, operation, priority))
+}
+
+async fn run(
+    device: &str,
+    baudrate: &models::Baudrate,
+    operation: Operation,
+    progress: Progress,
+) -> Result<JobResult, String> {
+    match operation {
+        Operation::Scan => http::scan_doc(device, baudrate, progress)
+            .await
+            .map(JobResult::Scan),
+        Operation::ScanSecondary { mask } => {
+            http::scan_secondary_doc(device, baudrate, &mask, progress)
+                .await
+                .map(JobResult::Scan)
+        }
+        Operation::Get { address } => http::get_xml(device, baudrate, &address)
+            .await
+            .and_then(|xml| data::from_xml(&xml))
+            .map(JobResult::Data),
+        Operation::GetMulti { address, maxframes } => {
+            http::get_multi_doc(device, baudrate, &address, maxframes)
+                .await
+                .map(JobResult::MultiReply)
+        }
+    }
+}
+
+/// Start a job in the background, returning it as queued, or an error if
+/// the request is invalid.
+pub fn create(request: JobRequest) -> Result<MBusJob, String> {
+    start(request, || http::JOB_RETENTION.get())
+}
+
+// Start a job, which is forgotten `retention()` after it finishes
+fn start(request: JobRequest, retention: fn() -> Duration) -> Result<MBusJob, String> {
+    let (baudrate, operation, priority) = check(&request)?;
+    let id = Uuid::new_v4().to_string();
+    let doc = Arc::new(Mutex::new(MBusJob {
+        id: id.clone(),
+        request: request.clone(),
+        state: data::JOB_QUEUED.to_string(),
+        created: now(),
+        started: None,
+        finished: None,
+        progress: JobProgress::default(),
+        result: None,
+        error: None,
+    }));
+    let progress = Progress::default();
+    info!("Job {} created: {:?}", id, request);
+
+    let task_doc = doc.clone();
+    let task_progress = progress.clone();
+    let task_id = id.clone();
+    let task = async move {
+        let device = request.device;
+        let result = match http::queue(&device).acquire(priority, None).await {
+            Ok(ticket) => {
+                {
+                    let mut doc = task_doc.lock().unwrap();
+                    doc.state = data::JOB_RUNNING.to_string();
+                    doc.started = Some(now());
+                }
+                info!("Job {} running", task_id);
+                let op = run(&device, &baudrate, operation, task_progress);
+                queue::REQUEST_TICKET
+                    .scope(RefCell::new(Some(ticket)), op)
+                    .await
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        {
+            let mut doc = task_doc.lock().unwrap();
+            match result {
+                Ok(result) => {
+                    doc.state = data::JOB_COMPLETED.to_string();
+                    doc.result = Some(result);
+                }
+                Err(e) => {
+                    doc.state = data::JOB_FAILED.to_string();
+                    doc.error = Some(e);
+                }
+            }
+            doc.finished = Some(now());
+            info!("Job {} {}", task_id, doc.state);
+        }
+
+        // Keep the result for a while, then forget the job
+        time::delay_for(retention()).await;
+        JOBS.lock().unwrap().remove(&task_id);
+        info!("Job {} expired", task_id);
+    };
+    let (task, abort) = future::abortable(task);
+
+    let job = Job {
+        doc,
+        progress,
+        abort,
+    };
+    let snapshot = job.snapshot();
+    JOBS.lock().unwrap().insert(id, job);
+    tokio::spawn(task);
+    Ok(snapshot)
+}
+
+/// The job with the given ID, with its progress so far.
+pub fn get(id: &str) -> Option<MBusJob> {
+    JOBS.lock().unwrap().get(id).map(Job::snapshot)
+}
+
+/// Cancel a job, if it hasn't finished, and forget it.  Returns the job as
+/// it was left.
+pub fn delete(id: &str) -> Option<MBusJob> {
+    let job = JOBS.lock().unwrap().remove(id)?;
+
+    // Dropping the job's future cancels any bus operation in progress, and
+    // removes it from the queue if it is still waiting
+    job.abort.abort();
+    {
+        let mut doc = job.doc.lock().unwrap();
+        if doc.finished.is_none() {
+            doc.state = data::JOB_CANCELLED.to_string();
+            doc.finished = Some(now());
+        }
+    }
+    info!("Job {} deleted", id);
+    Some(job.snapshot())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(operation: &str) -> JobRequest {
+        JobRequest {
+            operation: operation.to_string(),
+            device: "jobs-test".to_string(),
+            baudrate: "2400".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn error(request: JobRequest) -> String {
+        match check(&request) {
+            Ok(_) => panic!("{:?} is valid", request),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn checks() {
+        assert!(check(&request(data::JOB_SCAN)).is_ok());
+        assert_eq!(error(request("rescan")), "Invalid operation: rescan");
+        assert_eq!(
+            error(JobRequest {
+                device: String::new(),
+                ..request(data::JOB_SCAN)
+            }),
+            "No device"
+        );
+        assert_eq!(
+            error(JobRequest {
+                baudrate: "1234".to_string(),
+                ..request(data::JOB_SCAN)
+            }),
+            "Invalid baudrate: 1234"
+        );
+        assert_eq!(error(request(data::JOB_GET)), "No address for get");
+
+        let get_multi = |max_frames| JobRequest {
+            address: Some("48".to_string()),
+            max_frames,
+            ..request(data::JOB_GET_MULTI)
+        };
+        assert!(check(&get_multi(Some(1))).is_ok());
+        assert_eq!(error(get_multi(Some(0))), "Invalid maxframes: 0");
+        assert_eq!(error(get_multi(None)), "No maxframes for getMulti");
+
+        let scan_secondary = |mask: &str| JobRequest {
+            mask: Some(mask.to_string()),
+            ..request(data::JOB_SCAN_SECONDARY)
+        };
+        match check(&scan_secondary("12345678ffffffff")) {
+            Ok((_, Operation::ScanSecondary { mask }, _)) => assert_eq!(mask, "12345678FFFFFFFF"),
+            _ => panic!("Expected a secondary scan"),
+        }
+        assert!(check(&scan_secondary("1234567A")).is_err());
+
+        let priority = |priority: &str| JobRequest {
+            priority: Some(priority.to_string()),
+            ..request(data::JOB_SCAN)
+        };
+        assert!(matches!(
+            check(&priority("scheduled")),
+            Ok((_, _, Priority::Scheduled))
+        ));
+        assert!(check(&priority("urgent")).is_err());
+    }
+
+    #[tokio::test]
+    async fn delete_aborts() {
+        let device = "jobs-test-delete";
+        let queue = http::queue(device);
+        let holder = queue.acquire(Priority::Interactive, None).await.unwrap();
+
+        let job = start(
+            JobRequest {
+                device: device.to_string(),
+                ..request(data::JOB_SCAN)
+            },
+            || Duration::from_secs(3600),
+        )
+        .unwrap();
+        assert_eq!(job.state, data::JOB_QUEUED);
+        time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(queue.waiting(), 1);
+        assert_eq!(get(&job.id).unwrap().state, data::JOB_QUEUED);
+
+        let deleted = delete(&job.id).unwrap();
+        assert_eq!(deleted.state, data::JOB_CANCELLED);
+        assert!(deleted.finished.is_some());
+        assert_eq!(get(&job.id), None);
+        assert_eq!(delete(&job.id), None);
+
+        // The job no longer waits for the bus
+        time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(queue.waiting(), 0);
+        drop(holder);
+    }
+
+    #[tokio::test]
+    async fn retention() {
+        let job = start(
+            JobRequest {
+                device: "jobs-test-retention".to_string(),
+                address: Some("48".to_string()),
+                ..request(data::JOB_GET)
+            },
+            || Duration::from_millis(200),
+        )
+        .unwrap();
+
+        // The job fails, as there is no such device, and is kept until its
+        // retention has passed
+        let mut finished = None;
+        for _ in 0..20 {
+            time::delay_for(Duration::from_millis(10)).await;
+            finished = get(&job.id).filter(|job| job.finished.is_some());
+            if finished.is_some() {
+                break;
+            }
+        }
+        let finished = finished.expect("Job didn't finish");
+        assert_eq!(finished.state, data::JOB_FAILED);
+        assert!(finished.error.is_some());
+
+        time::delay_for(Duration::from_millis(400)).await;
+        assert_eq!(get(&job.id), None);
+    }
+}
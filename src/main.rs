@@ -21,12 +21,50 @@
 #![allow(missing_docs)]
 
 use httpd_util::{get_server_addr, https, init_app, ssl};
-use log::debug;
+use log::{debug, error, info};
 
+#[path = "api.rs"]
+mod api;
+#[path = "bus.rs"]
+mod bus;
+#[path = "config.rs"]
+mod config;
+#[path = "data.rs"]
+mod data;
+#[path = "discovery.rs"]
+mod discovery;
+#[path = "frame.rs"]
+mod frame;
+#[path = "history.rs"]
+mod history;
 #[path = "http.rs"]
 mod http;
+#[path = "influx.rs"]
+mod influx;
+#[path = "jobs.rs"]
+mod jobs;
+#[path = "metrics.rs"]
+mod metrics;
+#[path = "mqtt.rs"]
+mod mqtt;
+#[path = "poller.rs"]
+mod poller;
+#[path = "progress.rs"]
+mod progress;
+#[path = "queue.rs"]
+mod queue;
+#[path = "records.rs"]
+mod records;
+#[path = "registry.rs"]
+mod registry;
+#[path = "router.rs"]
+mod router;
+#[path = "serial.rs"]
+mod serial;
 #[path = "server.rs"]
 mod server;
+#[path = "webhooks.rs"]
+mod webhooks;
 
 use http::get_env;
 
@@ -34,6 +72,16 @@ use http::get_env;
 /// and pass it to the web server.
 #[tokio::main]
 async fn main() {
+    // Settings from the config file must be in place before anything reads
+    // the environment
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     init_app(
         "mbus-httpd",
         "packom.net, mbus@packom.net",
@@ -41,15 +89,78 @@ async fn main() {
         vec![
             "[LIBMBUS_PATH] - Path to libmbus binaries",
             "[LIBMBUS_GET] - libmbus get binary",
-            "[LIBMBUS_SCAN] - libmbus scan binary",
             "[LD_LIBRARY_PATH] - Path containing libmbus.so, used by libmbus binaries",
+            "[OPERATION_TIMEOUT] - Seconds before an M-Bus operation is abandoned",
+            "[QUEUE_LENGTH] - Maximum number of requests waiting for each M-Bus device",
+            "[QUEUE_RETRY_AFTER] - Seconds clients are told to wait when the queue is full",
+            "[JOB_RETENTION] - Seconds a finished job's result is kept",
+            "[METERS_FILE] - File the meter registry is kept in",
+            "[POLL_JITTER] - Most seconds a meter poll is randomly delayed by",
+            "[POLL_RETRIES] - Number of times a failed meter poll is retried",
+            "[POLL_RETRY_DELAY] - Seconds before retrying a failed poll, doubling each retry",
+            "[HISTORY_FILE] - SQLite database the reading history is kept in",
+            "[HISTORY_RETENTION] - Days readings are kept for, or 0 to keep them forever",
+            "[MQTT_BROKER] - MQTT broker to publish readings to",
+            "[MQTT_PORT] - MQTT broker port",
+            "[MQTT_CLIENT_ID] - Client ID to connect to the MQTT broker with",
+            "[MQTT_USERNAME] - Username for the MQTT broker",
+            "[MQTT_PASSWORD] - Password for the MQTT broker",
+            "[MQTT_CA_FILE] - CA certificate for the MQTT broker, connecting using TLS if set",
+            "[MQTT_TOPIC] - Topic template each data record of a reading is published to",
+            "[MQTT_EVENT_TOPIC] - Topic template scan results and hat changes are published to",
+            "[MQTT_QOS] - QoS to publish to the MQTT broker with, 0, 1 or 2",
+            "[MQTT_RETAIN] - Whether the MQTT broker should retain published messages",
+            "[MQTT_DISCOVERY] - Whether to publish Home Assistant discovery configs",
+            "[MQTT_DISCOVERY_PREFIX] - Home Assistant discovery topic prefix",
+            "[METRICS_RECORD_VALUES] - Whether to export meter record values as metrics",
+            "[INFLUX_URL] - InfluxDB write endpoint to export readings to",
+            "[INFLUX_TOKEN] - Token to authorize InfluxDB writes with",
+            "[INFLUX_MEASUREMENT] - InfluxDB measurement readings are written to",
+            "[INFLUX_BATCH_SIZE] - Most lines written to InfluxDB at once",
+            "[INFLUX_BATCH_INTERVAL] - Most seconds a line waits to be written to InfluxDB",
+            "[INFLUX_RETRIES] - Number of times a failed InfluxDB write is retried",
+            "[INFLUX_RETRY_DELAY] - Seconds before retrying an InfluxDB write, doubling each retry",
+            "[WEBHOOKS_FILE] - File webhooks are kept in",
+            "[WEBHOOK_TIMEOUT] - Seconds each webhook delivery attempt may take",
+            "[WEBHOOK_RETRIES] - Number of times a failed webhook delivery is retried",
+            "[WEBHOOK_RETRY_DELAY] - Seconds before retrying a webhook delivery, doubling each retry",
+            "[WEBHOOK_DEAD_LETTERS] - Most failed webhook deliveries kept",
+            "[API_TOKEN] - Bearer token clients must send",
+            "[HAT_GPIO] - GPIO which switches the M-Bus Master Hat's power",
+            "[CONFIG_FILE] - TOML config file, if --config isn't given",
         ],
         get_env(),
     );
+    if let Some(path) = config {
+        info!("Loaded config from {}", path);
+    }
+
+    if let Err(e) = registry::load() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    poller::start();
+    metrics::start();
+    if let Err(e) = mqtt::start() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = influx::start() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = webhooks::start() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = config::start() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
 
     let ssl = match https() {
         true => Some(ssl().unwrap()),
-        _ => None,
+        false => None,
     };
     match ssl {
         Some(_) => debug!("Using SSL"),
@@ -0,0 +1,211 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Prometheus metrics - how bus operations are going, and optionally the
+//! latest value of each meter record, served from /metrics.
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge_vec, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, GaugeVec, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
+};
+use std::time::Duration;
+
+use crate::data;
+use crate::http;
+
+/// Bus operations run by libmbus
+pub const LIBMBUS: &str = "libmbus";
+/// Bus operations run natively
+pub const NATIVE: &str = "native";
+
+lazy_static! {
+    static ref OPERATIONS: IntCounterVec = register_int_counter_vec!(
+        "mbus_operations_total",
+        "M-Bus operations, by operation and result",
+        &["operation", "result"]
+    )
+    .unwrap();
+    static ref DURATION: HistogramVec = register_histogram_vec!(
+        "mbus_operation_duration_seconds",
+        "Time M-Bus operations spent using the bus",
+        &["backend", "operation"],
+        vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0]
+    )
+    .unwrap();
+    static ref REJECTIONS: IntCounterVec = register_int_counter_vec!(
+        "mbus_queue_rejections_total",
+        "Requests turned away because the bus was busy, by reason",
+        &["reason"]
+    )
+    .unwrap();
+    static ref QUEUE_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        "mbus_queue_depth",
+        "Requests waiting for the bus, by device",
+        &["device"]
+    )
+    .unwrap();
+    /// Only registered once the hat's power is known, as 0 would read as off
+    static ref HAT_POWER: IntGauge = register_int_gauge!(
+        "mbus_hat_power",
+        "Whether the M-Bus Master Hat's power is on (1) or off (0)"
+    )
+    .unwrap();
+    static ref RECORD_VALUE: GaugeVec = register_gauge_vec!(
+        "mbus_record_value",
+        "Latest value read of each numeric meter data record",
+        &["secondary_id", "record", "unit"]
+    )
+    .unwrap();
+}
+
+/// Count a bus operation, and how long it used the bus for.
+pub fn operation<T>(
+    backend: &str,
+    operation: &str,
+    duration: Duration,
+    result: &Result<T, String>,
+) {
+    let result = match result {
+        Ok(_) => "ok",
+        Err(_) => "error",
+    };
+    OPERATIONS.with_label_values(&[operation, result]).inc();
+    DURATION
+        .with_label_values(&[backend, operation])
+        .observe(duration.as_secs_f64());
+}
+
+/// Count a request turned away by a bus queue.
+pub fn rejected(reason: &str) {
+    REJECTIONS.with_label_values(&[reason]).inc();
+}
+
+/// Record the M-Bus Master Hat's power being switched on or off.
+pub fn hat_power(on: bool) {
+    HAT_POWER.set(on as i64);
+}
+
+/// Record whether the M-Bus Master Hat's power is on at startup, if that can
+/// be told.
+pub fn start() {
+    if let Some(on) = http::hat_state() {
+        hat_power(on);
+    }
+}
+
+/// Record the values of a reading, if METRICS_RECORD_VALUES is set.
+pub fn reading(address: &str, reading: &data::MBusData) {
+    if http::METRICS_RECORD_VALUES.get() {
+        record_values(address, reading);
+    }
+}
+
+fn record_values(address: &str, reading: &data::MBusData) {
+    let secondary_id = reading.secondary_id(address);
+    for (ii, record) in reading.data_records.iter().enumerate() {
+        let value = match record.value.as_ref().map(|v| v.parse::<f64>()) {
+            Some(Ok(value)) => value,
+            _ => continue,
+        };
+        let id = match record.id {
+            Some(ref id) => id.clone(),
+            None => ii.to_string(),
+        };
+        let unit = record.unit.as_deref().unwrap_or("");
+        RECORD_VALUE
+            .with_label_values(&[&secondary_id, &id, unit])
+            .set(value);
+    }
+}
+
+/// All the metrics, in Prometheus' text format.
+pub fn gather() -> Result<String, String> {
+    QUEUE_DEPTH.reset();
+    for (device, queue) in http::queues() {
+        QUEUE_DEPTH
+            .with_label_values(&[&device])
+            .set(queue.waiting() as i64);
+    }
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|e| format!("Failed to encode metrics: {}", e))?;
+    String::from_utf8(buffer).map_err(|e| format!("Failed to encode metrics: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::Priority;
+
+    fn record(id: Option<&str>, unit: &str, value: &str) -> data::DataRecord {
+        data::DataRecord {
+            id: id.map(|id| id.to_string()),
+            unit: Some(unit.to_string()),
+            value: Some(value.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn record_value_labels() {
+        let reading = data::MBusData {
+            slave_information: data::SlaveInformation {
+                id: Some("87654321".to_string()),
+                ..Default::default()
+            },
+            data_records: vec![
+                record(Some("0"), "Volume (m m^3)", "12565"),
+                record(Some("1"), "Model / Version", "ABC"),
+                record(None, "Energy (10 Wh)", "-2.5"),
+            ],
+        };
+        record_values("48", &reading);
+        let text = gather().unwrap();
+        assert!(text.contains(
+            r#"mbus_record_value{record="0",secondary_id="87654321",unit="Volume (m m^3)"} 12565"#
+        ));
+        assert!(text.contains(
+            r#"mbus_record_value{record="2",secondary_id="87654321",unit="Energy (10 Wh)"} -2.5"#
+        ));
+        // Non-numeric values aren't exported
+        assert!(!text.contains("Model / Version"));
+    }
+
+    #[tokio::test]
+    async fn queue_depth() {
+        let depth = |depth| {
+            format!(
+                "mbus_queue_depth{{device=\"/dev/metrics-test\"}} {}\n",
+                depth
+            )
+        };
+        let queue = http::queue("metrics-test");
+        let holder = queue.acquire(Priority::Interactive, None).await.unwrap();
+        let mut waiter = Box::pin(queue.acquire(Priority::Scheduled, None));
+        assert!(futures::poll!(&mut waiter).is_pending());
+        assert!(gather().unwrap().contains(&depth(1)));
+
+        // The depth is set afresh each time, not left from before
+        drop(waiter);
+        assert!(gather().unwrap().contains(&depth(0)));
+        drop(holder);
+    }
+}
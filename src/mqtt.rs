@@ -0,0 +1,323 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Publishes readings, scan results and M-Bus Master Hat changes to an MQTT
+//! broker, if MQTT_BROKER is set, along with Home Assistant discovery
+//! configs for the readings if MQTT_DISCOVERY is set.  Publishing never
+//! holds up the request which produced the data - if the broker can't keep
+//! up, messages are dropped.
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS, TlsConfiguration, Transport};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::data;
+use crate::discovery;
+use crate::http;
+
+// Messages waiting to be sent to the broker before new ones are dropped
+const CAPACITY: usize = 64;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Bumped each time the client is started, so an earlier connection's thread
+// knows to stop
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// The connection to the broker, once started
+    static ref CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+    /// Discovery configs already published, by topic
+    static ref DISCOVERED: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+fn qos() -> Result<QoS, String> {
+    match http::MQTT_QOS.get() {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        qos => Err(format!("Invalid MQTT QoS: {}", qos)),
+    }
+}
+
+// Fill in a topic template's {placeholders}
+fn topic(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |topic, (name, value)| {
+            topic.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+fn started() -> bool {
+    CLIENT.lock().unwrap().is_some()
+}
+
+// Queue a message to be sent to the broker, returning whether it was
+fn publish(topic: String, payload: String, retain: bool) -> bool {
+    let mut client = CLIENT.lock().unwrap();
+    let client = match *client {
+        Some(ref mut client) => client,
+        None => return false,
+    };
+    // QoS was checked when the client was started
+    let qos = qos().unwrap_or(QoS::AtMostOnce);
+    match client.try_publish(topic.as_str(), qos, retain, payload) {
+        Ok(_) => true,
+        Err(e) => {
+            warn!("Failed to publish to {}: {}", topic, e);
+            false
+        }
+    }
+}
+
+/// Connect to MQTT_BROKER, if set.  The connection is kept up in the
+/// background, reconnecting if it drops.  Starting again, when the config is
+/// reloaded, replaces any earlier connection.
+pub fn start() -> Result<(), String> {
+    let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    if let Some(mut client) = CLIENT.lock().unwrap().take() {
+        let _ = client.try_disconnect();
+    }
+    // The new broker may not have them, or may need them differently
+    DISCOVERED.lock().unwrap().clear();
+    let broker = match http::MQTT_BROKER.get() {
+        Some(broker) => broker,
+        None => return Ok(()),
+    };
+    qos()?;
+    let mut options = MqttOptions::new(http::MQTT_CLIENT_ID.get(), &broker, http::MQTT_PORT.get());
+    options.set_keep_alive(KEEP_ALIVE);
+    if let Some(username) = http::MQTT_USERNAME.get() {
+        let password = http::MQTT_PASSWORD.get().unwrap_or_default();
+        options.set_credentials(username, password);
+    }
+    if let Some(ca_file) = http::MQTT_CA_FILE.get() {
+        let ca = fs::read(&ca_file).map_err(|e| format!("Failed to read {}: {}", ca_file, e))?;
+        options.set_transport(Transport::Tls(TlsConfiguration::Simple {
+            ca,
+            alpn: None,
+            client_auth: None,
+        }));
+    }
+
+    let (client, mut connection) = Client::new(options, CAPACITY);
+    *CLIENT.lock().unwrap() = Some(client);
+    let broker = format!("{}:{}", broker, http::MQTT_PORT.get());
+    thread::spawn(move || {
+        for event in connection.iter() {
+            // Replaced by a later start
+            if GENERATION.load(Ordering::SeqCst) != generation {
+                break;
+            }
+            match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker {}", broker)
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    warn!("MQTT broker {}: {}", broker, e);
+                    thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// Publish a Home Assistant discovery config for a data record, if it has
+// changed since it was last published.  Configs are always retained, so Home
+// Assistant finds them when it restarts.
+fn discover_record(
+    reading: &data::MBusData,
+    secondary_id: &str,
+    id: &str,
+    record: &data::DataRecord,
+    state_topic: &str,
+) {
+    let (topic, config) = discovery::config(reading, secondary_id, id, record, state_topic);
+    let payload = match data::to_json(&config) {
+        Ok(payload) => payload,
+        Err(_) => return,
+    };
+    let mut discovered = DISCOVERED.lock().unwrap();
+    if discovered.get(&topic) != Some(&payload) && publish(topic.clone(), payload.clone(), true) {
+        info!("Published discovery config {}", topic);
+        discovered.insert(topic, payload);
+    }
+}
+
+// Each data record of a reading, with its ID and the topic it is published
+// to, and the slave's secondary ID
+fn records<'a>(
+    device: &str,
+    address: &str,
+    reading: &'a data::MBusData,
+) -> (String, Vec<(String, String, &'a data::DataRecord)>) {
+    let secondary_id = reading.secondary_id(address);
+    let records = reading
+        .data_records
+        .iter()
+        .enumerate()
+        .map(|(ii, record)| {
+            let id = match record.id {
+                Some(ref id) => id.clone(),
+                None => ii.to_string(),
+            };
+            let topic = topic(
+                &http::MQTT_TOPIC.get(),
+                &[
+                    ("device", device),
+                    ("address", address),
+                    ("secondary_id", &secondary_id),
+                    ("record", &id),
+                ],
+            );
+            (id, topic, record)
+        })
+        .collect();
+    (secondary_id, records)
+}
+
+/// Publish each data record of a reading of the slave at `address`.
+pub fn reading(device: &str, address: &str, reading: &data::MBusData) {
+    if !started() {
+        return;
+    }
+    let (secondary_id, records) = records(device, address, reading);
+    for (id, topic, record) in records {
+        if http::MQTT_DISCOVERY.get() {
+            discover_record(reading, &secondary_id, &id, record, &topic);
+        }
+        if let Ok(payload) = data::to_json(record) {
+            publish(topic, payload, http::MQTT_RETAIN.get());
+        }
+    }
+}
+
+/// Publish Home Assistant discovery configs for a slave found by a scan, so
+/// it appears in Home Assistant before it is first read.
+pub fn discover(device: &str, address: &str, reading: &data::MBusData) {
+    if !http::MQTT_DISCOVERY.get() || !started() {
+        return;
+    }
+    let (secondary_id, records) = records(device, address, reading);
+    for (id, topic, record) in records {
+        discover_record(reading, &secondary_id, &id, record, &topic);
+    }
+}
+
+/// Publish the result of a scan.
+pub fn scan(device: &str, scan: &data::MBusScan) {
+    let topic = topic(
+        &http::MQTT_EVENT_TOPIC.get(),
+        &[("device", device), ("event", "scan")],
+    );
+    if let Ok(payload) = data::to_json(scan) {
+        publish(topic, payload, http::MQTT_RETAIN.get());
+    }
+}
+
+/// Publish that the M-Bus Master Hat's power has been switched on or off.
+pub fn hat_power(on: bool) {
+    let topic = topic(
+        &http::MQTT_EVENT_TOPIC.get(),
+        &[("device", "hat"), ("event", "power")],
+    );
+    publish(
+        topic,
+        if on { "on" } else { "off" }.to_string(),
+        http::MQTT_RETAIN.get(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_placeholders() {
+        let values = [("device", "ttyAMA0"), ("record", "3")];
+        assert_eq!(
+            topic("mbus/{device}/{record}/{device}", &values),
+            "mbus/ttyAMA0/3/ttyAMA0"
+        );
+        // Unknown placeholders and stray braces are left alone
+        assert_eq!(
+            topic("mbus/{unknown}/{device/{record}}", &values),
+            "mbus/{unknown}/{device/3}"
+        );
+        assert_eq!(topic("mbus/readings", &values), "mbus/readings");
+    }
+
+    #[test]
+    fn record_ids_and_topics() {
+        let reading = data::MBusData {
+            slave_information: data::SlaveInformation {
+                id: Some("12345678".to_string()),
+                manufacturer: Some("PAD".to_string()),
+                version: Some("1".to_string()),
+                medium: Some("Water".to_string()),
+                ..Default::default()
+            },
+            data_records: vec![
+                data::DataRecord {
+                    id: Some("7".to_string()),
+                    ..Default::default()
+                },
+                data::DataRecord::default(),
+            ],
+        };
+        let (secondary_id, records) = records("ttyAMA0", "48", &reading);
+        assert_eq!(secondary_id, "1234567824400107");
+        let records = records
+            .iter()
+            .map(|(id, topic, _)| (id.as_str(), topic.as_str()))
+            .collect::<Vec<_>>();
+        // Records without an ID are numbered by position
+        assert_eq!(
+            records,
+            vec![
+                ("7", "mbus/ttyAMA0/1234567824400107/7"),
+                ("1", "mbus/ttyAMA0/1234567824400107/1"),
+            ]
+        );
+
+        // Without enough of a header for a secondary address, the ID is used,
+        // or failing that the address read at
+        let mut reading = reading;
+        reading.slave_information.medium = None;
+        assert_eq!(records_of(&reading).0, "12345678");
+        reading.slave_information.id = None;
+        let (secondary_id, topics) = records_of(&reading);
+        assert_eq!(secondary_id, "48");
+        assert_eq!(topics[0], "mbus/ttyAMA0/48/7");
+    }
+
+    fn records_of(reading: &data::MBusData) -> (String, Vec<String>) {
+        let (secondary_id, records) = records("ttyAMA0", "48", reading);
+        let topics = records.into_iter().map(|(_, topic, _)| topic).collect();
+        (secondary_id, topics)
+    }
+}
@@ -0,0 +1,358 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Polls registered meters in the background, on each meter's schedule.
+//! Polls queue for the bus as scheduled requests, so interactive requests
+//! go first.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use log::info;
+use rand::Rng;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time;
+
+use mbus_api::models;
+
+use crate::config;
+use crate::data::{self, MBusPollStatus, Meter};
+use crate::history;
+use crate::http;
+use crate::queue::{self, Priority};
+use crate::registry;
+use crate::webhooks;
+
+#[derive(Clone)]
+enum Schedule {
+    Every(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    // The meter's poll schedule, if it has one
+    fn from_meter(meter: &Meter) -> Result<Option<Schedule>, String> {
+        match (meter.poll_interval, &meter.poll_schedule) {
+            (Some(_), Some(_)) => Err("Set PollInterval or PollSchedule, not both".to_string()),
+            (Some(0), None) => Err("PollInterval must be at least 1 second".to_string()),
+            (Some(secs), None) => Ok(Some(Schedule::Every(Duration::from_secs(secs)))),
+            (None, Some(expr)) => match cron::Schedule::from_str(expr) {
+                Ok(schedule) => Ok(Some(Schedule::Cron(Box::new(schedule)))),
+                Err(e) => Err(format!("Invalid PollSchedule {}: {}", expr, e)),
+            },
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn next(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Every(interval) => {
+                Some(Utc::now() + chrono::Duration::from_std(*interval).ok()?)
+            }
+            Schedule::Cron(schedule) => schedule.upcoming(Utc).next(),
+        }
+    }
+}
+
+// A meter's polling task, and the settings it is polling with.  Dropping
+// the task stops polling once any poll in progress has finished.
+struct Task {
+    changed: watch::Sender<Schedule>,
+    interval: Option<u64>,
+    schedule: Option<String>,
+}
+
+lazy_static! {
+    /// Polling tasks, by meter name
+    static ref TASKS: Mutex<HashMap<String, Task>> = Mutex::new(HashMap::new());
+    /// How polling of each meter is going, by meter name
+    static ref STATUS: Mutex<HashMap<String, MBusPollStatus>> = Mutex::new(HashMap::new());
+}
+
+fn rfc3339(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn update_status<F: FnOnce(&mut MBusPollStatus)>(name: &str, f: F) {
+    let mut status = STATUS.lock().unwrap();
+    let status = status
+        .entry(name.to_string())
+        .or_insert_with(|| MBusPollStatus {
+            meter: name.to_string(),
+            ..Default::default()
+        });
+    f(status)
+}
+
+fn jitter() -> chrono::Duration {
+    let max = http::POLL_JITTER.get().as_millis() as i64;
+    chrono::Duration::milliseconds(rand::thread_rng().gen_range(0, max + 1))
+}
+
+/// Check a meter's poll settings.
+pub fn check(meter: &Meter) -> Result<(), String> {
+    Schedule::from_meter(meter).map(|_| ())
+}
+
+// Read the meter once, queueing for the bus behind interactive requests
+async fn read(meter: &Meter) -> Result<data::MBusData, String> {
+    let baudrate = meter
+        .baudrate
+        .parse::<models::Baudrate>()
+        .map_err(|_| format!("Invalid baudrate: {}", meter.baudrate))?;
+    let ticket = http::queue(&meter.device)
+        .acquire(Priority::Scheduled, None)
+        .await
+        .map_err(|e| e.to_string())?;
+    let get = http::get_xml(&meter.device, &baudrate, &meter.address);
+    let xml = queue::REQUEST_TICKET
+        .scope(RefCell::new(Some(ticket)), get)
+        .await?;
+    data::from_xml(&xml)
+}
+
+// Read the meter, retrying with exponential backoff if it fails
+async fn read_with_retries(meter: &Meter) -> Result<data::MBusData, String> {
+    let max_retries = config::bus(&meter.device)
+        .and_then(|bus| bus.poll_retries)
+        .unwrap_or(http::POLL_RETRIES.get());
+    let mut delay = http::POLL_RETRY_DELAY.get();
+    let mut retries = 0;
+    loop {
+        match read(meter).await {
+            Ok(reading) => return Ok(reading),
+            Err(e) if retries < max_retries => {
+                info!(
+                    "Poll of {} failed, retrying in {}s: {}",
+                    meter.name,
+                    delay.as_secs(),
+                    e
+                );
+                time::delay_for(delay).await;
+                delay *= 2;
+                retries += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Poll the meter until its task is dropped, picking up schedule changes
+// between polls
+async fn poll(name: String, mut changed: watch::Receiver<Schedule>) {
+    // The first value received is the schedule the task started with
+    let mut schedule = match changed.recv().await {
+        Some(schedule) => schedule,
+        None => return,
+    };
+    loop {
+        let next = match schedule.next() {
+            Some(next) => next + jitter(),
+            None => {
+                info!("No more polls scheduled for {}", name);
+                update_status(&name, |s| s.next_poll = None);
+                match changed.recv().await {
+                    Some(new) => {
+                        schedule = new;
+                        continue;
+                    }
+                    None => return,
+                }
+            }
+        };
+        update_status(&name, |s| s.next_poll = Some(rfc3339(next)));
+        let delay = time::delay_for((next - Utc::now()).to_std().unwrap_or_default());
+        tokio::select! {
+            _ = delay => (),
+            new = changed.recv() => match new {
+                Some(new) => {
+                    schedule = new;
+                    continue;
+                }
+                None => return,
+            },
+        }
+
+        // Use the meter's current settings, in case they have changed
+        let meter = match registry::get(&name) {
+            Some(meter) => meter,
+            None => return,
+        };
+        let result = read_with_retries(&meter).await;
+        if registry::get(&name).is_none() {
+            return;
+        }
+        if let Ok(ref reading) = result {
+            history::record(&name, reading).await;
+            webhooks::reading(&meter, reading);
+        }
+        update_status(&name, |s| match result {
+            Ok(reading) => {
+                info!("Polled {}", name);
+                s.last_reading = Some(reading);
+                s.last_reading_time = Some(rfc3339(Utc::now()));
+                s.failures = 0;
+            }
+            Err(e) => {
+                info!("Poll of {} failed: {}", name, e);
+                s.last_error = Some(e);
+                s.last_error_time = Some(rfc3339(Utc::now()));
+                s.failures += 1;
+            }
+        });
+    }
+}
+
+/// Start, reschedule or stop polling a meter, following a change to its
+/// settings.  A poll in progress is never interrupted: a new schedule is
+/// picked up, or polling stops, once it has finished.
+pub fn schedule(meter: &Meter) {
+    let mut tasks = TASKS.lock().unwrap();
+    if let Some(task) = tasks.get(&meter.name) {
+        if task.interval == meter.poll_interval && task.schedule == meter.poll_schedule {
+            return;
+        }
+    }
+    let schedule = match Schedule::from_meter(meter) {
+        Ok(Some(schedule)) => schedule,
+        Ok(None) => {
+            tasks.remove(&meter.name);
+            if let Some(status) = STATUS.lock().unwrap().get_mut(&meter.name) {
+                status.next_poll = None;
+            }
+            return;
+        }
+        Err(e) => {
+            tasks.remove(&meter.name);
+            info!("Not polling {}: {}", meter.name, e);
+            return;
+        }
+    };
+    if let Some(task) = tasks.get_mut(&meter.name) {
+        info!("Rescheduled polling of {}", meter.name);
+        if task.changed.broadcast(schedule.clone()).is_ok() {
+            task.interval = meter.poll_interval;
+            task.schedule = meter.poll_schedule.clone();
+            return;
+        }
+        // The task has finished, so start another
+    }
+    info!("Polling {}", meter.name);
+    let (changed, receiver) = watch::channel(schedule);
+    tokio::spawn(poll(meter.name.clone(), receiver));
+    tasks.insert(
+        meter.name.clone(),
+        Task {
+            changed,
+            interval: meter.poll_interval,
+            schedule: meter.poll_schedule.clone(),
+        },
+    );
+}
+
+/// Stop polling a meter which has been removed, and forget its status.
+pub fn unschedule(name: &str) {
+    if TASKS.lock().unwrap().remove(name).is_some() {
+        info!("Stopped polling {}", name);
+    }
+    STATUS.lock().unwrap().remove(name);
+}
+
+/// Start polling all the registered meters.
+pub fn start() {
+    for meter in registry::list() {
+        schedule(&meter);
+    }
+}
+
+/// How polling of a meter is going.
+pub fn status(name: &str) -> MBusPollStatus {
+    match STATUS.lock().unwrap().get(name) {
+        Some(status) => status.clone(),
+        None => MBusPollStatus {
+            meter: name.to_string(),
+            ..Default::default()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meter(interval: Option<u64>, schedule: Option<&str>) -> Meter {
+        Meter {
+            name: "poller-test".to_string(),
+            device: "ttyAMA0".to_string(),
+            baudrate: "2400".to_string(),
+            address: "48".to_string(),
+            poll_interval: interval,
+            poll_schedule: schedule.map(|s| s.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn from_meter() {
+        match Schedule::from_meter(&meter(Some(60), None)) {
+            Ok(Some(Schedule::Every(interval))) => assert_eq!(interval, Duration::from_secs(60)),
+            _ => panic!("Expected an interval"),
+        }
+        match Schedule::from_meter(&meter(None, Some("0 */15 * * * *"))) {
+            Ok(Some(schedule @ Schedule::Cron(_))) => {
+                let next = schedule.next().unwrap();
+                assert!(next > Utc::now());
+                assert_eq!(next.timestamp() % (15 * 60), 0);
+            }
+            _ => panic!("Expected a cron schedule"),
+        }
+        assert!(matches!(Schedule::from_meter(&meter(None, None)), Ok(None)));
+        assert!(Schedule::from_meter(&meter(Some(60), Some("0 * * * * *"))).is_err());
+        assert!(Schedule::from_meter(&meter(Some(0), None)).is_err());
+        assert!(Schedule::from_meter(&meter(None, Some("every minute"))).is_err());
+    }
+
+    #[tokio::test]
+    async fn schedule_and_unschedule() {
+        let polled = meter(Some(3600), None);
+        schedule(&polled);
+        assert!(TASKS.lock().unwrap().contains_key(&polled.name));
+        time::delay_for(Duration::from_millis(10)).await;
+        assert!(status(&polled.name).next_poll.is_some());
+
+        // Polling stops when the meter no longer has a schedule
+        schedule(&meter(None, None));
+        assert!(!TASKS.lock().unwrap().contains_key(&polled.name));
+        assert_eq!(status(&polled.name).next_poll, None);
+
+        schedule(&polled);
+        unschedule(&polled.name);
+        assert!(!TASKS.lock().unwrap().contains_key(&polled.name));
+        assert_eq!(
+            status(&polled.name),
+            MBusPollStatus {
+                meter: polled.name.clone(),
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,80 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Reports how a scan is getting on, as it runs on the bus thread - counted
+//! for jobs, and as events for clients streaming the scan.
+
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+use crate::data::{self, JobProgress};
+
+/// Something which happened during a scan.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanEvent {
+    /// An address was probed
+    Probe(data::ScanProbe),
+    /// A slave was found and identified
+    Device(data::ScanSlave),
+    /// The scan finished
+    Complete(data::MBusScan),
+    /// The scan failed
+    Error(String),
+}
+
+/// Progress of an operation, shared between the thread running it and
+/// anyone who wants to know how it is getting on.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    counts: Arc<Mutex<JobProgress>>,
+    events: Option<mpsc::UnboundedSender<ScanEvent>>,
+}
+
+impl Progress {
+    /// Progress which is also sent as events, as it happens.
+    pub fn with_events(events: mpsc::UnboundedSender<ScanEvent>) -> Progress {
+        Progress {
+            events: Some(events),
+            ..Default::default()
+        }
+    }
+
+    /// Another address has been probed.
+    pub fn probed(&self, probe: data::ScanProbe) {
+        self.counts.lock().unwrap().addresses_probed += 1;
+        self.send(ScanEvent::Probe(probe));
+    }
+
+    /// Another device has been found.
+    pub fn found(&self, slave: &data::ScanSlave) {
+        self.counts.lock().unwrap().devices_found += 1;
+        self.send(ScanEvent::Device(slave.clone()));
+    }
+
+    pub fn send(&self, event: ScanEvent) {
+        if let Some(ref events) = self.events {
+            // Nobody may be listening any more, in which case the scan is
+            // about to be cancelled
+            let _ = events.send(event);
+        }
+    }
+
+    pub fn get(&self) -> JobProgress {
+        self.counts.lock().unwrap().clone()
+    }
+}
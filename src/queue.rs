@@ -0,0 +1,402 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! A fair queue for use of the M-Bus.  Requests take turns in FIFO order
+//! within each priority class, with interactive requests going ahead of
+//! scheduled ones.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time;
+
+use crate::metrics;
+
+tokio::task_local! {
+    /// The ticket acquired for the request being handled, if any
+    pub static REQUEST_TICKET: RefCell<Option<Ticket>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+    /// Someone is waiting for the answer
+    Interactive,
+    /// Background work, e.g. polling, which can wait
+    Scheduled,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "interactive" => Ok(Priority::Interactive),
+            "scheduled" => Ok(Priority::Scheduled),
+            _ => Err(format!("Invalid priority: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueueError {
+    /// Too many requests are already waiting.  Try again after the given
+    /// time.
+    Full(Duration),
+    /// The request's deadline passed before it reached the front of the
+    /// queue
+    Timeout(Duration),
+}
+
+impl QueueError {
+    /// How long the client should wait before trying again.
+    pub fn retry_after(&self) -> Duration {
+        match self {
+            QueueError::Full(d) | QueueError::Timeout(d) => *d,
+        }
+    }
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueueError::Full(_) => write!(f, "Bus queue is full"),
+            QueueError::Timeout(_) => write!(f, "Timed out waiting for the bus"),
+        }
+    }
+}
+
+struct Waiter {
+    id: u64,
+    tx: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct State {
+    busy: bool,
+    // The queued request the bus was last handed to, so a request which
+    // gives up just as it is handed the bus can pass it on
+    owner: Option<u64>,
+    next_id: u64,
+    interactive: VecDeque<Waiter>,
+    scheduled: VecDeque<Waiter>,
+}
+
+impl State {
+    fn waiting(&self) -> usize {
+        self.interactive.len() + self.scheduled.len()
+    }
+
+    // Remove a waiter, returning whether it was still queued
+    fn remove(&mut self, id: u64) -> bool {
+        let len = self.waiting();
+        self.interactive.retain(|w| w.id != id);
+        self.scheduled.retain(|w| w.id != id);
+        self.waiting() != len
+    }
+
+    // Hand the bus to the next waiter, or mark it free
+    fn release(&mut self) {
+        match self
+            .interactive
+            .pop_front()
+            .or_else(|| self.scheduled.pop_front())
+        {
+            Some(next) => {
+                self.owner = Some(next.id);
+                // If the waiter has gone, its Waiting guard passes the bus on
+                let _ = next.tx.send(());
+            }
+            None => {
+                self.owner = None;
+                self.busy = false;
+            }
+        }
+    }
+}
+
+/// Exclusive use of the bus, until dropped.
+pub struct Ticket {
+    state: Arc<Mutex<State>>,
+}
+
+impl Ticket {
+    /// Whether this is a ticket for the given queue's bus.
+    pub fn is_for(&self, queue: &Queue) -> bool {
+        Arc::ptr_eq(&self.state, &queue.state)
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().release();
+    }
+}
+
+// Removes a request from the queue if it stops waiting - e.g. because the
+// client disconnected
+struct Waiting {
+    state: Arc<Mutex<State>>,
+    id: u64,
+    done: bool,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if !self.done {
+            let mut state = self.state.lock().unwrap();
+            if !state.remove(self.id) && state.owner == Some(self.id) {
+                state.release();
+            }
+        }
+    }
+}
+
+pub struct Queue {
+    state: Arc<Mutex<State>>,
+    max_len: usize,
+    retry_after: Duration,
+}
+
+impl Queue {
+    /// A queue allowing up to `max_len` requests to wait, which tells
+    /// clients turned away to retry after `retry_after`.
+    pub fn new(max_len: usize, retry_after: Duration) -> Queue {
+        Queue {
+            state: Arc::new(Mutex::new(State::default())),
+            max_len,
+            retry_after,
+        }
+    }
+
+    /// Wait for exclusive use of the bus, for no longer than `wait` if
+    /// given.
+    pub async fn acquire(
+        &self,
+        priority: Priority,
+        wait: Option<Duration>,
+    ) -> Result<Ticket, QueueError> {
+        let (id, rx) = {
+            let mut state = self.state.lock().unwrap();
+            if !state.busy {
+                state.busy = true;
+                return Ok(self.ticket());
+            }
+            if state.waiting() >= self.max_len {
+                metrics::rejected("full");
+                return Err(QueueError::Full(self.retry_after));
+            }
+            let (tx, rx) = oneshot::channel();
+            state.next_id += 1;
+            let id = state.next_id;
+            match priority {
+                Priority::Interactive => state.interactive.push_back(Waiter { id, tx }),
+                Priority::Scheduled => state.scheduled.push_back(Waiter { id, tx }),
+            }
+            (id, rx)
+        };
+
+        let mut waiting = Waiting {
+            state: self.state.clone(),
+            id,
+            done: false,
+        };
+        let handed_over = match wait {
+            Some(wait) => matches!(time::timeout(wait, rx).await, Ok(Ok(()))),
+            None => rx.await.is_ok(),
+        };
+        waiting.done = true;
+
+        // Check the queue, in case the bus was handed over as we timed out
+        if !handed_over && self.state.lock().unwrap().remove(id) {
+            metrics::rejected("timeout");
+            return Err(QueueError::Timeout(self.retry_after));
+        }
+        Ok(self.ticket())
+    }
+
+    /// The number of requests waiting for the bus.
+    pub fn waiting(&self) -> usize {
+        self.state.lock().unwrap().waiting()
+    }
+
+    fn ticket(&self) -> Ticket {
+        Ticket {
+            state: self.state.clone(),
+        }
+    }
+}
+
+/// The ticket acquired for the current request, if it is for this queue's
+/// bus.  Otherwise wait for the bus as an interactive request.
+pub async fn ticket(queue: &Queue) -> Result<Ticket, QueueError> {
+    let ticket = REQUEST_TICKET
+        .try_with(|t| {
+            let mut t = t.borrow_mut();
+            match *t {
+                Some(ref ticket) if ticket.is_for(queue) => t.take(),
+                _ => None,
+            }
+        })
+        .ok()
+        .flatten();
+    match ticket {
+        Some(ticket) => Ok(ticket),
+        None => queue.acquire(Priority::Interactive, None).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::poll;
+    use std::task::Poll;
+
+    const RETRY_AFTER: Duration = Duration::from_secs(10);
+
+    fn queue(max_len: usize) -> Queue {
+        Queue::new(max_len, RETRY_AFTER)
+    }
+
+    #[tokio::test]
+    async fn fifo_within_priority() {
+        let queue = queue(8);
+        let first = queue.acquire(Priority::Interactive, None).await.unwrap();
+        let mut a = Box::pin(queue.acquire(Priority::Interactive, None));
+        let mut b = Box::pin(queue.acquire(Priority::Interactive, None));
+        assert!(poll!(&mut a).is_pending());
+        assert!(poll!(&mut b).is_pending());
+        assert_eq!(queue.waiting(), 2);
+
+        drop(first);
+        assert!(poll!(&mut b).is_pending());
+        let a = match poll!(&mut a) {
+            Poll::Ready(ticket) => ticket.unwrap(),
+            Poll::Pending => panic!("First waiter wasn't handed the bus"),
+        };
+        assert_eq!(queue.waiting(), 1);
+        drop(a);
+        assert!(matches!(poll!(&mut b), Poll::Ready(Ok(_))));
+        assert_eq!(queue.waiting(), 0);
+    }
+
+    #[tokio::test]
+    async fn interactive_first() {
+        let queue = queue(8);
+        let first = queue.acquire(Priority::Scheduled, None).await.unwrap();
+        let mut scheduled = Box::pin(queue.acquire(Priority::Scheduled, None));
+        let mut interactive = Box::pin(queue.acquire(Priority::Interactive, None));
+        assert!(poll!(&mut scheduled).is_pending());
+        assert!(poll!(&mut interactive).is_pending());
+
+        drop(first);
+        assert!(poll!(&mut scheduled).is_pending());
+        let ticket = match poll!(&mut interactive) {
+            Poll::Ready(ticket) => ticket.unwrap(),
+            Poll::Pending => panic!("Interactive waiter wasn't handed the bus"),
+        };
+        drop(ticket);
+        assert!(matches!(poll!(&mut scheduled), Poll::Ready(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn full() {
+        let queue = queue(2);
+        let _first = queue.acquire(Priority::Interactive, None).await.unwrap();
+        let mut a = Box::pin(queue.acquire(Priority::Interactive, None));
+        let mut b = Box::pin(queue.acquire(Priority::Scheduled, None));
+        assert!(poll!(&mut a).is_pending());
+        assert!(poll!(&mut b).is_pending());
+        let result = queue.acquire(Priority::Interactive, None).await;
+        assert_eq!(result.err(), Some(QueueError::Full(RETRY_AFTER)));
+        assert_eq!(queue.waiting(), 2);
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let queue = queue(8);
+        let first = queue.acquire(Priority::Interactive, None).await.unwrap();
+        let wait = Duration::from_millis(20);
+        let start = time::Instant::now();
+        let result = queue.acquire(Priority::Interactive, Some(wait)).await;
+        assert_eq!(result.err(), Some(QueueError::Timeout(RETRY_AFTER)));
+        assert!(start.elapsed() >= wait);
+        assert_eq!(queue.waiting(), 0);
+
+        // The bus isn't handed to the waiter which gave up
+        drop(first);
+        let mut next = Box::pin(queue.acquire(Priority::Interactive, None));
+        assert!(matches!(poll!(&mut next), Poll::Ready(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn dropped_waiter_passes_bus_on() {
+        let queue = queue(8);
+        let first = queue.acquire(Priority::Interactive, None).await.unwrap();
+        let mut a = Box::pin(queue.acquire(Priority::Interactive, None));
+        let mut b = Box::pin(queue.acquire(Priority::Interactive, None));
+        assert!(poll!(&mut a).is_pending());
+        assert!(poll!(&mut b).is_pending());
+
+        // Handed the bus, but gone before it noticed
+        drop(first);
+        drop(a);
+        assert!(matches!(poll!(&mut b), Poll::Ready(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn dropped_waiter_leaves_queue() {
+        let queue = queue(8);
+        let first = queue.acquire(Priority::Interactive, None).await.unwrap();
+        let mut a = Box::pin(queue.acquire(Priority::Interactive, None));
+        assert!(poll!(&mut a).is_pending());
+        drop(a);
+        assert_eq!(queue.waiting(), 0);
+        drop(first);
+        let mut next = Box::pin(queue.acquire(Priority::Interactive, None));
+        assert!(matches!(poll!(&mut next), Poll::Ready(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn request_ticket_reused_for_same_queue() {
+        let queue = queue(8);
+        let other = self::queue(8);
+        let ticket = queue.acquire(Priority::Scheduled, None).await.unwrap();
+        REQUEST_TICKET
+            .scope(RefCell::new(Some(ticket)), async {
+                // Another bus is acquired separately, leaving the ticket
+                let other_ticket = super::ticket(&other).await.unwrap();
+                assert!(other_ticket.is_for(&other));
+                assert!(REQUEST_TICKET.with(|t| t.borrow().is_some()));
+
+                // This bus is busy, but the request already has it
+                let mut reused = Box::pin(super::ticket(&queue));
+                let reused = match poll!(&mut reused) {
+                    Poll::Ready(ticket) => ticket.unwrap(),
+                    Poll::Pending => panic!("Request's ticket wasn't reused"),
+                };
+                assert!(reused.is_for(&queue));
+                assert!(REQUEST_TICKET.with(|t| t.borrow().is_none()));
+                assert_eq!(queue.waiting(), 0);
+            })
+            .await;
+    }
+}
@@ -0,0 +1,1006 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! M-Bus application layer decoder for variable data structures
+//! (EN 13757-3).
+
+use chrono::{NaiveDate, NaiveDateTime};
+
+use crate::frame::Frame;
+
+// CI fields for variable data responses
+pub const CI_RSP_VARIABLE: u8 = 0x72;
+pub const CI_RSP_VARIABLE_MSB: u8 = 0x76;
+pub const CI_RSP_VARIABLE_SHORT: u8 = 0x7A;
+
+const LONG_HEADER_LEN: usize = 12;
+const SHORT_HEADER_LEN: usize = 4;
+
+// DIF
+const DIF_EXTENSION: u8 = 0x80;
+const DIF_STORAGE_LSB: u8 = 0x40;
+const DIF_FUNCTION_MASK: u8 = 0x30;
+const DIF_DATA_MASK: u8 = 0x0F;
+const DIF_MANUFACTURER_SPECIFIC: u8 = 0x0F;
+const DIF_MORE_RECORDS_FOLLOW: u8 = 0x1F;
+const DIF_IDLE_FILLER: u8 = 0x2F;
+const DIF_GLOBAL_READOUT: u8 = 0x7F;
+const MAX_DIFE: usize = 10;
+
+// DIFE
+const DIFE_SUBUNIT: u8 = 0x40;
+const DIFE_TARIFF_MASK: u8 = 0x30;
+const DIFE_STORAGE_MASK: u8 = 0x0F;
+
+// VIF
+const VIF_EXTENSION: u8 = 0x80;
+const VIF_PLAIN_TEXT: u8 = 0x7C;
+const VIF_TABLE_FB: u8 = 0xFB;
+const VIF_TABLE_FD: u8 = 0xFD;
+const MAX_VIFE: usize = 10;
+
+/// Fixed data header of a variable data response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlaveInformation {
+    /// Identification number (8 BCD digits).  Absent with a short header.
+    pub id: Option<String>,
+    /// Three letter manufacturer code.  Absent with a short header.
+    pub manufacturer: Option<String>,
+    pub version: Option<u8>,
+    pub medium: Option<u8>,
+    pub access_number: u8,
+    pub status: u8,
+    pub signature: u16,
+}
+
+impl SlaveInformation {
+    /// The 16 hex digit secondary address (ID, manufacturer, version,
+    /// medium) this header corresponds to, if it has a long header.
+    pub fn secondary_address(&self) -> Option<String> {
+        let id = self.id.as_ref()?;
+        let man = encode_manufacturer(self.manufacturer.as_ref()?)?;
+        Some(format!(
+            "{}{:02X}{:02X}{:02X}{:02X}",
+            id,
+            man & 0xFF,
+            man >> 8,
+            self.version?,
+            self.medium?
+        ))
+    }
+
+    pub fn medium_name(&self) -> Option<&'static str> {
+        self.medium.map(medium_name)
+    }
+}
+
+/// The function field of a data record.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Instantaneous,
+    Maximum,
+    Minimum,
+    Error,
+}
+
+impl Function {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Function::Instantaneous => "Instantaneous value",
+            Function::Maximum => "Maximum value",
+            Function::Minimum => "Minimum value",
+            Function::Error => "Value during error state",
+        }
+    }
+}
+
+/// The decoded value of a data record, before any scaling is applied.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    None,
+    Integer(i64),
+    Real(f64),
+    Date(NaiveDate),
+    DateTime(NaiveDateTime),
+    String(String),
+    /// Data the decoder does not know how to interpret, e.g. invalid BCD
+    Bytes(Vec<u8>),
+}
+
+/// A single data record (DIB/VIB plus data) from a variable data response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataRecord {
+    pub dif: Vec<u8>,
+    pub vif: Vec<u8>,
+    pub storage_number: u64,
+    pub tariff: u32,
+    pub subunit: u32,
+    pub function: Function,
+    /// What the record measures, e.g. "Energy" or "Flow temperature"
+    pub quantity: String,
+    /// SI (or other) unit of the scaled value, empty if dimensionless
+    pub unit: String,
+    /// Power of ten the raw value is multiplied by to give the unit
+    pub exponent: i32,
+    pub value: Value,
+}
+
+/// A decoded variable data response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VariableData {
+    pub header: SlaveInformation,
+    pub records: Vec<DataRecord>,
+    /// Set if the slave has more records to send in a further frame
+    /// (DIF 0x1F)
+    pub more_records_follow: bool,
+    /// Manufacturer specific data following DIF 0x0F/0x1F
+    pub manufacturer_data: Vec<u8>,
+}
+
+/// Decode the application layer of an RSP_UD long frame.
+pub fn decode(frame: &Frame) -> Result<VariableData, String> {
+    let ci = match frame.ci() {
+        Some(ci) => ci,
+        None => return Err("Not a long frame".to_string()),
+    };
+    decode_data(ci, frame.data())
+}
+
+/// Decode variable data structure user data for the given CI field.
+pub fn decode_data(ci: u8, data: &[u8]) -> Result<VariableData, String> {
+    let (header, msb_first, records) = match ci {
+        CI_RSP_VARIABLE | CI_RSP_VARIABLE_MSB => {
+            let msb_first = ci == CI_RSP_VARIABLE_MSB;
+            if data.len() < LONG_HEADER_LEN {
+                return Err(format!(
+                    "Variable data header too short: {} bytes",
+                    data.len()
+                ));
+            }
+            (
+                decode_long_header(&data[..LONG_HEADER_LEN], msb_first)?,
+                msb_first,
+                &data[LONG_HEADER_LEN..],
+            )
+        }
+        CI_RSP_VARIABLE_SHORT => {
+            if data.len() < SHORT_HEADER_LEN {
+                return Err(format!(
+                    "Variable data header too short: {} bytes",
+                    data.len()
+                ));
+            }
+            (
+                decode_short_header(&data[..SHORT_HEADER_LEN]),
+                false,
+                &data[SHORT_HEADER_LEN..],
+            )
+        }
+        _ => return Err(format!("Unsupported CI field: 0x{:02X}", ci)),
+    };
+
+    let mut vd = VariableData {
+        header,
+        records: Vec::new(),
+        more_records_follow: false,
+        manufacturer_data: Vec::new(),
+    };
+    decode_records(records, msb_first, &mut vd)?;
+    Ok(vd)
+}
+
+// Multi-byte fields are LSB first, unless the CI field says otherwise
+fn ordered(bytes: &[u8], msb_first: bool) -> Vec<u8> {
+    let mut v = bytes.to_vec();
+    if msb_first {
+        v.reverse();
+    }
+    v
+}
+
+fn le_uint(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0u64, |acc, b| (acc << 8) | *b as u64)
+}
+
+fn decode_long_header(h: &[u8], msb_first: bool) -> Result<SlaveInformation, String> {
+    let id = ordered(&h[0..4], msb_first);
+    let id = bcd_digits(&id).ok_or(format!("Invalid identification number: {:02X?}", id))?;
+    let man = le_uint(&ordered(&h[4..6], msb_first)) as u16;
+    Ok(SlaveInformation {
+        id: Some(id),
+        manufacturer: Some(decode_manufacturer(man)),
+        version: Some(h[6]),
+        medium: Some(h[7]),
+        access_number: h[8],
+        status: h[9],
+        signature: le_uint(&ordered(&h[10..12], msb_first)) as u16,
+    })
+}
+
+fn decode_short_header(h: &[u8]) -> SlaveInformation {
+    SlaveInformation {
+        id: None,
+        manufacturer: None,
+        version: None,
+        medium: None,
+        access_number: h[0],
+        status: h[1],
+        signature: le_uint(&h[2..4]) as u16,
+    }
+}
+
+/// Decode a 2 byte manufacturer field into its three letter code.
+pub fn decode_manufacturer(man: u16) -> String {
+    [(man >> 10) & 0x1F, (man >> 5) & 0x1F, man & 0x1F]
+        .iter()
+        .map(|c| (*c as u8 + 64) as char)
+        .collect()
+}
+
+/// Encode a three letter manufacturer code into its 2 byte field.
+pub fn encode_manufacturer(man: &str) -> Option<u16> {
+    let bytes = man.as_bytes();
+    if bytes.len() != 3 || !bytes.iter().all(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+    Some(
+        bytes
+            .iter()
+            .fold(0u16, |acc, c| (acc << 5) | (*c - 64) as u16),
+    )
+}
+
+pub fn medium_name(medium: u8) -> &'static str {
+    match medium {
+        0x00 => "Other",
+        0x01 => "Oil",
+        0x02 => "Electricity",
+        0x03 => "Gas",
+        0x04 => "Heat: Outlet",
+        0x05 => "Steam",
+        0x06 => "Warm water (30-90°C)",
+        0x07 => "Water",
+        0x08 => "Heat Cost Allocator",
+        0x09 => "Compressed Air",
+        0x0A => "Cooling load meter: Outlet",
+        0x0B => "Cooling load meter: Inlet",
+        0x0C => "Heat: Inlet",
+        0x0D => "Heat / Cooling load meter",
+        0x0E => "Bus/System",
+        0x0F => "Unknown",
+        0x15 => "Hot water (>=90°C)",
+        0x16 => "Cold water",
+        0x17 => "Dual register (hot/cold) water",
+        0x18 => "Pressure",
+        0x19 => "A/D Converter",
+        _ => "Reserved",
+    }
+}
+
+// BCD bytes are LSB first.  Returns None if any nibble is not a digit.
+fn bcd_digits(bytes: &[u8]) -> Option<String> {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes.iter().rev() {
+        for nibble in &[b >> 4, b & 0x0F] {
+            if *nibble > 9 {
+                return None;
+            }
+            s.push((b'0' + nibble) as char);
+        }
+    }
+    Some(s)
+}
+
+// Signed BCD: a most significant nibble of 0xF marks a negative value.
+fn bcd_value(bytes: &[u8]) -> Option<i64> {
+    let mut bytes = bytes.to_vec();
+    let negative = match bytes.last_mut() {
+        Some(msb) if *msb & 0xF0 == 0xF0 => {
+            *msb &= 0x0F;
+            true
+        }
+        Some(_) => false,
+        None => return None,
+    };
+    let v = bcd_digits(&bytes)?.parse::<i64>().ok()?;
+    Some(if negative { -v } else { v })
+}
+
+// Little endian two's complement integer of 1-8 bytes
+fn int_value(bytes: &[u8]) -> i64 {
+    let bits = bytes.len() * 8;
+    let v = le_uint(bytes);
+    if bits < 64 && v & (1 << (bits - 1)) != 0 {
+        (v | (!0u64 << bits)) as i64
+    } else {
+        v as i64
+    }
+}
+
+fn year(lo: u8, hi: u8) -> i32 {
+    let y = (((lo & 0xE0) >> 5) | ((hi & 0xF0) >> 1)) as i32;
+    if y < 81 {
+        2000 + y
+    } else {
+        1900 + y
+    }
+}
+
+// Type G: date
+fn date_g(b: &[u8]) -> Value {
+    NaiveDate::from_ymd_opt(year(b[0], b[1]), (b[1] & 0x0F) as u32, (b[0] & 0x1F) as u32)
+        .map(Value::Date)
+        .unwrap_or_else(|| Value::Bytes(b.to_vec()))
+}
+
+// Type F: date and time to the minute
+fn date_time_f(b: &[u8]) -> Value {
+    if b[0] & 0x80 != 0 {
+        // Time invalid
+        return Value::None;
+    }
+    NaiveDate::from_ymd_opt(year(b[2], b[3]), (b[3] & 0x0F) as u32, (b[2] & 0x1F) as u32)
+        .and_then(|d| d.and_hms_opt((b[1] & 0x1F) as u32, (b[0] & 0x3F) as u32, 0))
+        .map(Value::DateTime)
+        .unwrap_or_else(|| Value::Bytes(b.to_vec()))
+}
+
+// Type I: date and time to the second
+fn date_time_i(b: &[u8]) -> Value {
+    if b[1] & 0x80 != 0 {
+        // Time invalid
+        return Value::None;
+    }
+    NaiveDate::from_ymd_opt(year(b[3], b[4]), (b[4] & 0x0F) as u32, (b[3] & 0x1F) as u32)
+        .and_then(|d| {
+            d.and_hms_opt(
+                (b[2] & 0x1F) as u32,
+                (b[1] & 0x3F) as u32,
+                (b[0] & 0x3F) as u32,
+            )
+        })
+        .map(Value::DateTime)
+        .unwrap_or_else(|| Value::Bytes(b.to_vec()))
+}
+
+// What a VIF means: quantity, unit, exponent, and any special data type
+struct VifInfo {
+    quantity: String,
+    unit: String,
+    exponent: i32,
+    kind: VifKind,
+}
+
+#[derive(PartialEq)]
+enum VifKind {
+    Normal,
+    Date,
+    DateTime,
+}
+
+fn vif_info(quantity: &str, unit: &str, exponent: i32) -> VifInfo {
+    VifInfo {
+        quantity: quantity.to_string(),
+        unit: unit.to_string(),
+        exponent,
+        kind: VifKind::Normal,
+    }
+}
+
+fn duration_unit(nn: u8) -> &'static str {
+    match nn & 0x03 {
+        0 => "s",
+        1 => "min",
+        2 => "h",
+        _ => "d",
+    }
+}
+
+// Primary VIF table, EN 13757-3 table 10
+fn primary_vif(vif: u8) -> VifInfo {
+    let n3 = (vif & 0x07) as i32;
+    let n2 = (vif & 0x03) as i32;
+    match vif & 0x7F {
+        0x00..=0x07 => vif_info("Energy", "Wh", n3 - 3),
+        0x08..=0x0F => vif_info("Energy", "J", n3),
+        0x10..=0x17 => vif_info("Volume", "m^3", n3 - 6),
+        0x18..=0x1F => vif_info("Mass", "kg", n3 - 3),
+        0x20..=0x23 => vif_info("On time", duration_unit(vif), 0),
+        0x24..=0x27 => vif_info("Operating time", duration_unit(vif), 0),
+        0x28..=0x2F => vif_info("Power", "W", n3 - 3),
+        0x30..=0x37 => vif_info("Power", "J/h", n3),
+        0x38..=0x3F => vif_info("Volume flow", "m^3/h", n3 - 6),
+        0x40..=0x47 => vif_info("Volume flow", "m^3/min", n3 - 7),
+        0x48..=0x4F => vif_info("Volume flow", "m^3/s", n3 - 9),
+        0x50..=0x57 => vif_info("Mass flow", "kg/h", n3 - 3),
+        0x58..=0x5B => vif_info("Flow temperature", "°C", n2 - 3),
+        0x5C..=0x5F => vif_info("Return temperature", "°C", n2 - 3),
+        0x60..=0x63 => vif_info("Temperature difference", "K", n2 - 3),
+        0x64..=0x67 => vif_info("External temperature", "°C", n2 - 3),
+        0x68..=0x6B => vif_info("Pressure", "bar", n2 - 3),
+        0x6C => VifInfo {
+            kind: VifKind::Date,
+            ..vif_info("Time point (date)", "", 0)
+        },
+        0x6D => VifInfo {
+            kind: VifKind::DateTime,
+            ..vif_info("Time point (date & time)", "", 0)
+        },
+        0x6E => vif_info("Units for H.C.A.", "", 0),
+        0x70..=0x73 => vif_info("Averaging duration", duration_unit(vif), 0),
+        0x74..=0x77 => vif_info("Actuality duration", duration_unit(vif), 0),
+        0x78 => vif_info("Fabrication number", "", 0),
+        0x79 => vif_info("Enhanced identification", "", 0),
+        0x7A => vif_info("Bus address", "", 0),
+        // The unit follows as a string
+        0x7C => vif_info("Custom", "", 0),
+        0x7E => vif_info("Any VIF", "", 0),
+        0x7F => vif_info("Manufacturer specific", "", 0),
+        _ => vif_info("Reserved", "", 0),
+    }
+}
+
+// First extension table (VIF 0xFD), EN 13757-3 table 14
+fn extension_vif_fd(vife: u8) -> VifInfo {
+    let n2 = (vife & 0x03) as i32;
+    let n4 = (vife & 0x0F) as i32;
+    match vife & 0x7F {
+        0x00..=0x03 => vif_info("Credit", "currency units", n2 - 3),
+        0x04..=0x07 => vif_info("Debit", "currency units", n2 - 3),
+        0x08 => vif_info("Access number (transmission count)", "", 0),
+        0x09 => vif_info("Medium", "", 0),
+        0x0A => vif_info("Manufacturer", "", 0),
+        0x0B => vif_info("Parameter set identification", "", 0),
+        0x0C => vif_info("Model / Version", "", 0),
+        0x0D => vif_info("Hardware version", "", 0),
+        0x0E => vif_info("Firmware version", "", 0),
+        0x0F => vif_info("Software version", "", 0),
+        0x10 => vif_info("Customer location", "", 0),
+        0x11 => vif_info("Customer", "", 0),
+        0x12 => vif_info("Access code user", "", 0),
+        0x13 => vif_info("Access code operator", "", 0),
+        0x14 => vif_info("Access code system operator", "", 0),
+        0x15 => vif_info("Access code developer", "", 0),
+        0x16 => vif_info("Password", "", 0),
+        0x17 => vif_info("Error flags", "", 0),
+        0x18 => vif_info("Error mask", "", 0),
+        0x1A => vif_info("Digital output", "", 0),
+        0x1B => vif_info("Digital input", "", 0),
+        0x1C => vif_info("Baudrate", "Bd", 0),
+        0x1D => vif_info("Response delay time", "bit times", 0),
+        0x1E => vif_info("Retry", "", 0),
+        0x20 => vif_info("First storage number for cyclic storage", "", 0),
+        0x21 => vif_info("Last storage number for cyclic storage", "", 0),
+        0x22 => vif_info("Size of storage block", "", 0),
+        0x24..=0x27 => vif_info("Storage interval", duration_unit(vife), 0),
+        0x40..=0x4F => vif_info("Voltage", "V", n4 - 9),
+        0x50..=0x5F => vif_info("Current", "A", n4 - 12),
+        0x60 => vif_info("Reset counter", "", 0),
+        0x61 => vif_info("Cumulation counter", "", 0),
+        0x62 => vif_info("Control signal", "", 0),
+        0x63 => vif_info("Day of week", "", 0),
+        0x64 => vif_info("Week number", "", 0),
+        0x65 => vif_info("Time point of day change", "", 0),
+        0x66 => vif_info("State of parameter activation", "", 0),
+        0x67 => vif_info("Special supplier information", "", 0),
+        0x68..=0x6B => vif_info("Duration since last cumulation", duration_unit(vife), 0),
+        0x6C..=0x6F => vif_info("Operating time battery", duration_unit(vife), 0),
+        0x70 => vif_info("Date and time of battery change", "", 0),
+        _ => vif_info("Reserved", "", 0),
+    }
+}
+
+// Second extension table (VIF 0xFB), EN 13757-3 table 12
+fn extension_vif_fb(vife: u8) -> VifInfo {
+    let n1 = (vife & 0x01) as i32;
+    let n2 = (vife & 0x03) as i32;
+    match vife & 0x7F {
+        0x00..=0x01 => vif_info("Energy", "MWh", n1 - 1),
+        0x08..=0x09 => vif_info("Energy", "GJ", n1 - 1),
+        0x10..=0x11 => vif_info("Volume", "m^3", n1 + 2),
+        0x18..=0x19 => vif_info("Mass", "t", n1 + 2),
+        0x21 => vif_info("Volume", "feet^3", -1),
+        0x22 => vif_info("Volume", "american gallon", -1),
+        0x23 => vif_info("Volume", "american gallon", 0),
+        0x24 => vif_info("Volume flow", "american gallon/min", -3),
+        0x25 => vif_info("Volume flow", "american gallon/min", 0),
+        0x26 => vif_info("Volume flow", "american gallon/h", 0),
+        0x28..=0x29 => vif_info("Power", "MW", n1 - 1),
+        0x30..=0x31 => vif_info("Power", "GJ/h", n1 - 1),
+        0x58..=0x5B => vif_info("Flow temperature", "°F", n2 - 3),
+        0x5C..=0x5F => vif_info("Return temperature", "°F", n2 - 3),
+        0x60..=0x63 => vif_info("Temperature difference", "°F", n2 - 3),
+        0x64..=0x67 => vif_info("External temperature", "°F", n2 - 3),
+        0x70..=0x73 => vif_info("Cold / Warm Temperature Limit", "°F", n2 - 3),
+        0x74..=0x77 => vif_info("Cold / Warm Temperature Limit", "°C", n2 - 3),
+        _ => vif_info("Reserved", "", 0),
+    }
+}
+
+// Apply combinable (orthogonal) VIFEs which affect scaling
+fn apply_vife(info: &mut VifInfo, vife: u8) {
+    match vife & 0x7F {
+        // Multiplicative correction factor 10^(nnn-6)
+        0x70..=0x77 => info.exponent += (vife & 0x07) as i32 - 6,
+        // Multiplicative correction factor 10^3
+        0x7D => info.exponent += 3,
+        _ => (),
+    }
+}
+
+// Read a run of extension bytes (DIFE or VIFE) starting at data[pos]
+fn extensions(
+    data: &[u8],
+    pos: &mut usize,
+    mut more: bool,
+    max: usize,
+    what: &str,
+) -> Result<Vec<u8>, String> {
+    let mut exts = Vec::new();
+    while more {
+        if exts.len() >= max {
+            return Err(format!("Too many {}s at offset {}", what, *pos));
+        }
+        let b = *data
+            .get(*pos)
+            .ok_or(format!("Truncated {} at offset {}", what, *pos))?;
+        *pos += 1;
+        exts.push(b);
+        more = b & 0x80 != 0;
+    }
+    Ok(exts)
+}
+
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], String> {
+    if *pos + len > data.len() {
+        return Err(format!(
+            "Truncated record data at offset {}: need {} bytes, {} available",
+            *pos,
+            len,
+            data.len() - *pos
+        ));
+    }
+    let bytes = &data[*pos..*pos + len];
+    *pos += len;
+    Ok(bytes)
+}
+
+fn string_value(bytes: &[u8]) -> Value {
+    // Strings are transmitted last character first
+    let s: String = bytes.iter().rev().map(|b| *b as char).collect();
+    Value::String(s)
+}
+
+// Variable length data (data field 0x0D), with the LVAR byte at data[pos]
+fn lvar_value(data: &[u8], pos: &mut usize) -> Result<Value, String> {
+    let lvar = *take(data, pos, 1)?.first().unwrap();
+    match lvar {
+        0x00..=0xBF => Ok(string_value(take(data, pos, lvar as usize)?)),
+        0xC0..=0xC9 => {
+            let b = take(data, pos, (lvar - 0xC0) as usize)?;
+            Ok(bcd_digits(b)
+                .and_then(|s| s.parse::<i64>().ok())
+                .map(Value::Integer)
+                .unwrap_or_else(|| Value::Bytes(b.to_vec())))
+        }
+        0xD0..=0xD9 => {
+            let b = take(data, pos, (lvar - 0xD0) as usize)?;
+            Ok(bcd_digits(b)
+                .and_then(|s| s.parse::<i64>().ok())
+                .map(|v| Value::Integer(-v))
+                .unwrap_or_else(|| Value::Bytes(b.to_vec())))
+        }
+        0xE0..=0xEF => {
+            let b = take(data, pos, (lvar - 0xE0) as usize)?;
+            if b.is_empty() || b.len() > 8 {
+                Ok(Value::Bytes(b.to_vec()))
+            } else {
+                Ok(Value::Integer(int_value(b)))
+            }
+        }
+        _ => Err(format!(
+            "Unsupported LVAR 0x{:02X} at offset {}",
+            lvar,
+            *pos - 1
+        )),
+    }
+}
+
+fn decode_records(data: &[u8], msb_first: bool, vd: &mut VariableData) -> Result<(), String> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let dif = data[pos];
+        pos += 1;
+
+        if dif == DIF_IDLE_FILLER {
+            continue;
+        }
+        // The extension bit doesn't change what these mean
+        match dif & !DIF_EXTENSION {
+            DIF_MANUFACTURER_SPECIFIC | DIF_MORE_RECORDS_FOLLOW => {
+                vd.more_records_follow = dif & !DIF_EXTENSION == DIF_MORE_RECORDS_FOLLOW;
+                vd.manufacturer_data = data[pos..].to_vec();
+                break;
+            }
+            _ => (),
+        }
+
+        // Data information block
+        let difes = extensions(data, &mut pos, dif & DIF_EXTENSION != 0, MAX_DIFE, "DIFE")?;
+        let mut storage_number = ((dif & DIF_STORAGE_LSB) >> 6) as u64;
+        let mut tariff = 0u32;
+        let mut subunit = 0u32;
+        for (ii, dife) in difes.iter().enumerate() {
+            storage_number |= ((dife & DIFE_STORAGE_MASK) as u64) << (1 + 4 * ii);
+            tariff |= (((dife & DIFE_TARIFF_MASK) >> 4) as u32) << (2 * ii);
+            subunit |= (((dife & DIFE_SUBUNIT) >> 6) as u32) << ii;
+        }
+        let function = match (dif & DIF_FUNCTION_MASK) >> 4 {
+            0 => Function::Instantaneous,
+            1 => Function::Maximum,
+            2 => Function::Minimum,
+            _ => Function::Error,
+        };
+
+        // A global readout request carries no VIB or data
+        if dif == DIF_GLOBAL_READOUT {
+            continue;
+        }
+
+        // Value information block
+        let vif = *data
+            .get(pos)
+            .ok_or(format!("Truncated VIF at offset {}", pos))?;
+        pos += 1;
+        let vifes = extensions(data, &mut pos, vif & VIF_EXTENSION != 0, MAX_VIFE, "VIFE")?;
+        let (mut info, combinable) = match vif {
+            VIF_TABLE_FD | VIF_TABLE_FB => {
+                let ext = *vifes
+                    .first()
+                    .ok_or(format!("Missing extension VIF at offset {}", pos))?;
+                let info = if vif == VIF_TABLE_FD {
+                    extension_vif_fd(ext)
+                } else {
+                    extension_vif_fb(ext)
+                };
+                (info, &vifes[1..])
+            }
+            _ => (primary_vif(vif), &vifes[..]),
+        };
+        for vife in combinable {
+            apply_vife(&mut info, *vife);
+        }
+        if vif & 0x7F == VIF_PLAIN_TEXT {
+            // The unit follows the VIFEs as a length-prefixed string
+            let len = *take(data, &mut pos, 1)?.first().unwrap() as usize;
+            if let Value::String(s) = string_value(take(data, &mut pos, len)?) {
+                info.unit = s;
+            }
+        }
+
+        // Data
+        let value = match dif & DIF_DATA_MASK {
+            0x00 | 0x08 => Value::None,
+            0x01 => Value::Integer(int_value(take(data, &mut pos, 1)?)),
+            0x02 => {
+                let b = ordered(take(data, &mut pos, 2)?, msb_first);
+                if info.kind == VifKind::Date {
+                    date_g(&b)
+                } else {
+                    Value::Integer(int_value(&b))
+                }
+            }
+            0x03 => Value::Integer(int_value(&ordered(take(data, &mut pos, 3)?, msb_first))),
+            0x04 => {
+                let b = ordered(take(data, &mut pos, 4)?, msb_first);
+                if info.kind == VifKind::DateTime {
+                    date_time_f(&b)
+                } else {
+                    Value::Integer(int_value(&b))
+                }
+            }
+            0x05 => {
+                let b = ordered(take(data, &mut pos, 4)?, msb_first);
+                Value::Real(f32::from_bits(le_uint(&b) as u32) as f64)
+            }
+            0x06 => {
+                let b = ordered(take(data, &mut pos, 6)?, msb_first);
+                if info.kind == VifKind::DateTime {
+                    date_time_i(&b)
+                } else {
+                    Value::Integer(int_value(&b))
+                }
+            }
+            0x07 => Value::Integer(int_value(&ordered(take(data, &mut pos, 8)?, msb_first))),
+            0x09 | 0x0A | 0x0B | 0x0C | 0x0E => {
+                let len = match dif & DIF_DATA_MASK {
+                    0x09 => 1,
+                    0x0A => 2,
+                    0x0B => 3,
+                    0x0C => 4,
+                    _ => 6,
+                };
+                let b = ordered(take(data, &mut pos, len)?, msb_first);
+                bcd_value(&b)
+                    .map(Value::Integer)
+                    .unwrap_or_else(|| Value::Bytes(b))
+            }
+            0x0D => lvar_value(data, &mut pos)?,
+            _ => {
+                return Err(format!(
+                    "Unsupported DIF 0x{:02X} at offset {}",
+                    dif,
+                    pos - 1
+                ))
+            }
+        };
+
+        vd.records.push(DataRecord {
+            dif: [&[dif][..], &difes[..]].concat(),
+            vif: [&[vif][..], &vifes[..]].concat(),
+            storage_number,
+            tariff,
+            subunit,
+            function,
+            quantity: info.quantity,
+            unit: info.unit,
+            exponent: info.exponent,
+            value,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records following a short header, so only the records matter
+    fn records(records: &[u8]) -> VariableData {
+        let data = [&[0x55, 0x00, 0x00, 0x00][..], records].concat();
+        decode_data(CI_RSP_VARIABLE_SHORT, &data).unwrap()
+    }
+
+    fn record(bytes: &[u8]) -> DataRecord {
+        let mut vd = records(bytes);
+        assert_eq!(vd.records.len(), 1);
+        vd.records.remove(0)
+    }
+
+    fn date_time(y: i32, m: u32, d: u32, h: u32, min: u32, s: u32) -> Value {
+        Value::DateTime(
+            NaiveDate::from_ymd_opt(y, m, d)
+                .unwrap()
+                .and_hms_opt(h, min, s)
+                .unwrap(),
+        )
+    }
+
+    // The EN 1434-3 example heat meter response, after the CI field
+    const EXAMPLE: &[u8] = &[
+        0x78, 0x56, 0x34, 0x12, 0x24, 0x40, 0x01, 0x07, 0x55, 0x00, 0x00, 0x00, 0x03, 0x13, 0x15,
+        0x31, 0x00, 0xDA, 0x02, 0x3B, 0x13, 0x01, 0x8B, 0x60, 0x04, 0x37, 0x18, 0x02,
+    ];
+
+    #[test]
+    fn example() {
+        let vd = decode_data(CI_RSP_VARIABLE, EXAMPLE).unwrap();
+        assert_eq!(vd.header.id.as_deref(), Some("12345678"));
+        assert_eq!(vd.header.manufacturer.as_deref(), Some("PAD"));
+        assert_eq!(vd.header.version, Some(1));
+        assert_eq!(vd.header.medium_name(), Some("Water"));
+        assert_eq!(vd.header.access_number, 0x55);
+        assert_eq!(
+            vd.header.secondary_address().as_deref(),
+            Some("1234567824400107")
+        );
+        assert_eq!(vd.records.len(), 3);
+        assert!(!vd.more_records_follow);
+
+        // 24 bit integer
+        let r = &vd.records[0];
+        assert_eq!((r.quantity.as_str(), r.unit.as_str()), ("Volume", "m^3"));
+        assert_eq!(r.value, Value::Integer(12565));
+        assert_eq!(r.exponent, -3);
+        assert_eq!(r.storage_number, 0);
+
+        // 4 digit BCD maximum, with a DIFE extending the storage number
+        let r = &vd.records[1];
+        assert_eq!(r.function, Function::Maximum);
+        assert_eq!(r.quantity, "Volume flow");
+        assert_eq!(r.value, Value::Integer(113));
+        assert_eq!(r.storage_number, 5);
+        assert_eq!(r.dif, vec![0xDA, 0x02]);
+
+        // 6 digit BCD, with a DIFE giving the tariff and subunit
+        let r = &vd.records[2];
+        assert_eq!((r.quantity.as_str(), r.unit.as_str()), ("Energy", "Wh"));
+        assert_eq!(r.value, Value::Integer(21837));
+        assert_eq!(r.exponent, 1);
+        assert_eq!((r.storage_number, r.tariff, r.subunit), (0, 2, 1));
+    }
+
+    #[test]
+    fn msb_first() {
+        let mut data = EXAMPLE[..12].to_vec();
+        data[..4].reverse();
+        data[4..6].reverse();
+        data.extend_from_slice(&[0x03, 0x13, 0x00, 0x31, 0x15]);
+        let vd = decode_data(CI_RSP_VARIABLE_MSB, &data).unwrap();
+        assert_eq!(vd.header.id.as_deref(), Some("12345678"));
+        assert_eq!(vd.header.manufacturer.as_deref(), Some("PAD"));
+        assert_eq!(vd.records[0].value, Value::Integer(12565));
+    }
+
+    #[test]
+    fn bcd() {
+        assert_eq!(
+            record(&[0x0C, 0x13, 0x78, 0x56, 0x34, 0x12]).value,
+            Value::Integer(12345678)
+        );
+        // A top nibble of 0xF makes it negative
+        assert_eq!(
+            record(&[0x0C, 0x13, 0x78, 0x56, 0x34, 0xF2]).value,
+            Value::Integer(-2345678)
+        );
+        assert_eq!(record(&[0x09, 0x13, 0xF5]).value, Value::Integer(-5));
+        // Not BCD
+        assert_eq!(
+            record(&[0x0A, 0x13, 0x1A, 0x00]).value,
+            Value::Bytes(vec![0x1A, 0x00])
+        );
+    }
+
+    #[test]
+    fn ints() {
+        assert_eq!(record(&[0x01, 0x13, 0xFF]).value, Value::Integer(-1));
+        let r = record(&[0x02, 0x59, 0x38, 0xFF]);
+        assert_eq!(r.quantity, "Flow temperature");
+        assert_eq!(r.value, Value::Integer(-200));
+        assert_eq!(r.exponent, -2);
+        assert_eq!(
+            record(&[0x04, 0x13, 0x01, 0x02, 0x03, 0x04]).value,
+            Value::Integer(0x04030201)
+        );
+        assert_eq!(
+            record(&[0x06, 0x13, 0x01, 0x00, 0x00, 0x00, 0x00, 0x80]).value,
+            Value::Integer(-0x7FFF_FFFF_FFFF)
+        );
+        assert_eq!(
+            record(&[0x07, 0x13, 0xFE, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]).value,
+            Value::Integer(-2)
+        );
+        assert_eq!(record(&[0x00, 0x13]).value, Value::None);
+    }
+
+    #[test]
+    fn real() {
+        let r = record(&[0x05, 0x2B, 0x00, 0x00, 0xC0, 0x3F]);
+        assert_eq!((r.quantity.as_str(), r.unit.as_str()), ("Power", "W"));
+        assert_eq!(r.value, Value::Real(1.5));
+    }
+
+    #[test]
+    fn combinable_vife() {
+        // Volume in litres, with a VIFE multiplying by 10^-1
+        let r = record(&[0x01, 0x93, 0x75, 0x07]);
+        assert_eq!(r.exponent, -4);
+        assert_eq!(r.vif, vec![0x93, 0x75]);
+    }
+
+    #[test]
+    fn dates() {
+        // Type G
+        assert_eq!(
+            record(&[0x02, 0x6C, 0x1F, 0x1C]).value,
+            Value::Date(NaiveDate::from_ymd_opt(2008, 12, 31).unwrap())
+        );
+        // Type F
+        assert_eq!(
+            record(&[0x04, 0x6D, 0x2D, 0x0D, 0x91, 0x25]).value,
+            date_time(2020, 5, 17, 13, 45, 0)
+        );
+        assert_eq!(
+            record(&[0x04, 0x6D, 0xAD, 0x0D, 0x91, 0x25]).value,
+            Value::None
+        );
+        // Type I
+        assert_eq!(
+            record(&[0x06, 0x6D, 0x1E, 0x2D, 0x0D, 0x91, 0x25, 0x00]).value,
+            date_time(2020, 5, 17, 13, 45, 30)
+        );
+        assert_eq!(
+            record(&[0x06, 0x6D, 0x1E, 0xAD, 0x0D, 0x91, 0x25, 0x00]).value,
+            Value::None
+        );
+        // Month 13
+        assert_eq!(
+            record(&[0x02, 0x6C, 0x1F, 0x1D]).value,
+            Value::Bytes(vec![0x1F, 0x1D])
+        );
+    }
+
+    #[test]
+    fn lvar() {
+        // Strings are sent last character first
+        let r = record(&[0x0D, 0xFD, 0x0C, 0x03, b'C', b'B', b'A']);
+        assert_eq!(r.quantity, "Model / Version");
+        assert_eq!(r.value, Value::String("ABC".to_string()));
+        assert_eq!(
+            record(&[0x0D, 0x13, 0xC2, 0x34, 0x12]).value,
+            Value::Integer(1234)
+        );
+        assert_eq!(
+            record(&[0x0D, 0x13, 0xD2, 0x34, 0x12]).value,
+            Value::Integer(-1234)
+        );
+        assert_eq!(
+            record(&[0x0D, 0x13, 0xE2, 0xFE, 0xFF]).value,
+            Value::Integer(-2)
+        );
+        assert!(decode_data(CI_RSP_VARIABLE_SHORT, &[0, 0, 0, 0, 0x0D, 0x13, 0xF0]).is_err());
+    }
+
+    #[test]
+    fn plain_text_vif() {
+        let r = record(&[0x01, 0x7C, 0x03, b'h', b'W', b'k', 0x05]);
+        assert_eq!((r.quantity.as_str(), r.unit.as_str()), ("Custom", "kWh"));
+        assert_eq!(r.value, Value::Integer(5));
+    }
+
+    #[test]
+    fn manufacturer_data() {
+        for (dif, more) in &[(0x0F, false), (0x1F, true), (0x8F, false), (0x9F, true)] {
+            let vd = records(&[0x01, 0x13, 0x05, *dif, 0x01, 0x02, 0x03]);
+            assert_eq!(vd.records.len(), 1);
+            assert_eq!(vd.more_records_follow, *more);
+            assert_eq!(vd.manufacturer_data, vec![0x01, 0x02, 0x03]);
+        }
+    }
+
+    #[test]
+    fn idle_filler() {
+        let vd = records(&[0x2F, 0x2F, 0x01, 0x13, 0x05, 0x2F]);
+        assert_eq!(vd.records.len(), 1);
+    }
+
+    #[test]
+    fn invalid() {
+        // Data type 0x0F with a function other than those with a meaning
+        let data = [0, 0, 0, 0, 0x3F, 0x13];
+        let e = decode_data(CI_RSP_VARIABLE_SHORT, &data).unwrap_err();
+        assert!(e.starts_with("Unsupported DIF"), "{}", e);
+
+        let data = [0, 0, 0, 0, 0x04, 0x13, 0x01, 0x02];
+        let e = decode_data(CI_RSP_VARIABLE_SHORT, &data).unwrap_err();
+        assert!(e.starts_with("Truncated record data"), "{}", e);
+
+        let data = [0, 0, 0, 0, 0x84];
+        let e = decode_data(CI_RSP_VARIABLE_SHORT, &data).unwrap_err();
+        assert!(e.starts_with("Truncated DIFE"), "{}", e);
+
+        let e = decode_data(CI_RSP_VARIABLE, &EXAMPLE[..11]).unwrap_err();
+        assert!(e.starts_with("Variable data header too short"), "{}", e);
+
+        let e = decode_data(0x51, EXAMPLE).unwrap_err();
+        assert!(e.starts_with("Unsupported CI field"), "{}", e);
+    }
+}
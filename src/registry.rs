@@ -0,0 +1,322 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! The meter registry - named meters, with where to find them on the bus,
+//! persisted as JSON in METERS_FILE.
+
+use lazy_static::lazy_static;
+use log::info;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use mbus_api::models;
+
+use crate::config;
+use crate::data::{self, Meter};
+use crate::http;
+use crate::poller;
+
+// AES-128 key length, in hex digits
+const KEY_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegistryError {
+    /// The meter is missing something, or has invalid settings
+    Invalid(String),
+    /// A meter with this name already exists
+    Exists(String),
+    /// The registry couldn't be saved
+    Storage(String),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistryError::Invalid(e) => write!(f, "{}", e),
+            RegistryError::Exists(name) => write!(f, "Meter {} already exists", name),
+            RegistryError::Storage(e) => write!(f, "Failed to save meters: {}", e),
+        }
+    }
+}
+
+lazy_static! {
+    /// Registered meters, by name
+    static ref METERS: Mutex<BTreeMap<String, Meter>> = Mutex::new(BTreeMap::new());
+}
+
+// Fill in a baudrate the meter doesn't have from its bus's config
+fn defaults(meter: &mut Meter) {
+    if meter.baudrate.is_empty() {
+        if let Some(baudrate) = config::bus(&meter.device).and_then(|bus| bus.baudrate) {
+            meter.baudrate = baudrate.to_string();
+        }
+    }
+}
+
+fn check(meter: &Meter) -> Result<(), String> {
+    if meter.name.is_empty() || meter.name.contains('/') {
+        return Err(format!("Invalid meter name: {:?}", meter.name));
+    }
+    if meter.device.is_empty() {
+        return Err("No device".to_string());
+    }
+    meter
+        .baudrate
+        .parse::<models::Baudrate>()
+        .map_err(|_| format!("Invalid baudrate: {}", meter.baudrate))?;
+    http::check_address(&meter.address)?;
+    match meter.key {
+        Some(ref key) if key.len() != KEY_LEN || !key.chars().all(|c| c.is_ascii_hexdigit()) => {
+            return Err(format!("Key must be {} hex digits", KEY_LEN))
+        }
+        _ => (),
+    }
+    poller::check(meter)
+}
+
+fn save(meters: &BTreeMap<String, Meter>) -> Result<(), RegistryError> {
+    write(Path::new(&*http::METERS_FILE), meters)
+}
+
+fn write(path: &Path, meters: &BTreeMap<String, Meter>) -> Result<(), RegistryError> {
+    let doc = data::MBusMeters {
+        meters: meters.values().cloned().collect(),
+    };
+    let json =
+        serde_json::to_string_pretty(&doc).map_err(|e| RegistryError::Storage(format!("{}", e)))?;
+
+    // Write a new file and move it into place, so the registry is never
+    // left half written
+    let tmp = path.with_extension("tmp");
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| RegistryError::Storage(format!("{}", e)))?;
+    }
+    fs::write(&tmp, json)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| RegistryError::Storage(format!("{}: {}", path.display(), e)))
+}
+
+/// Read the registry from METERS_FILE, if there is one.  Fails if the file
+/// can't be read or contains invalid meters, rather than risk overwriting
+/// it.
+pub fn load() -> Result<usize, String> {
+    let path = &*http::METERS_FILE;
+    let meters = read(path)?;
+    info!("Loaded {} meters from {}", meters.len(), path);
+    let count = meters.len();
+    *METERS.lock().unwrap() = meters;
+    Ok(count)
+}
+
+fn read(path: &str) -> Result<BTreeMap<String, Meter>, String> {
+    let json = match fs::read(path) {
+        Ok(json) => json,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+    };
+    let doc: data::MBusMeters =
+        serde_json::from_slice(&json).map_err(|e| format!("Failed to parse {}: {}", path, e))?;
+    let mut meters = BTreeMap::new();
+    for mut meter in doc.meters {
+        defaults(&mut meter);
+        check(&meter).map_err(|e| format!("Invalid meter in {}: {}", path, e))?;
+        meters.insert(meter.name.clone(), meter);
+    }
+    Ok(meters)
+}
+
+/// Read the registry from METERS_FILE again, starting, restarting or
+/// stopping polls to match.  Keeps the meters already loaded if the file
+/// can't be read.
+pub fn reload() -> Result<usize, String> {
+    let old = list();
+    load()?;
+    let meters = list();
+    for meter in old {
+        if !meters.iter().any(|m| m.name == meter.name) {
+            poller::unschedule(&meter.name);
+        }
+    }
+    for meter in &meters {
+        poller::schedule(meter);
+    }
+    Ok(meters.len())
+}
+
+/// All the registered meters.
+pub fn list() -> Vec<Meter> {
+    METERS.lock().unwrap().values().cloned().collect()
+}
+
+pub fn get(name: &str) -> Option<Meter> {
+    METERS.lock().unwrap().get(name).cloned()
+}
+
+/// Add a new meter.
+pub fn create(mut meter: Meter) -> Result<Meter, RegistryError> {
+    defaults(&mut meter);
+    check(&meter).map_err(RegistryError::Invalid)?;
+    let mut meters = METERS.lock().unwrap();
+    if meters.contains_key(&meter.name) {
+        return Err(RegistryError::Exists(meter.name));
+    }
+    meters.insert(meter.name.clone(), meter.clone());
+    if let Err(e) = save(&meters) {
+        meters.remove(&meter.name);
+        return Err(e);
+    }
+    info!("Meter {} created", meter.name);
+    poller::schedule(&meter);
+    Ok(meter)
+}
+
+/// Add or replace the named meter, returning it and whether it is new.
+pub fn put(name: &str, mut meter: Meter) -> Result<(Meter, bool), RegistryError> {
+    if meter.name.is_empty() {
+        meter.name = name.to_string();
+    } else if meter.name != name {
+        return Err(RegistryError::Invalid(format!(
+            "Meter name {} doesn't match {}",
+            meter.name, name
+        )));
+    }
+    defaults(&mut meter);
+    check(&meter).map_err(RegistryError::Invalid)?;
+    let mut meters = METERS.lock().unwrap();
+    let old = meters.insert(meter.name.clone(), meter.clone());
+    if let Err(e) = save(&meters) {
+        match old {
+            Some(old) => meters.insert(meter.name.clone(), old),
+            None => meters.remove(&meter.name),
+        };
+        return Err(e);
+    }
+    info!("Meter {} updated", meter.name);
+    poller::schedule(&meter);
+    Ok((meter, old.is_none()))
+}
+
+/// Remove the named meter, returning it if it existed.
+pub fn delete(name: &str) -> Result<Option<Meter>, RegistryError> {
+    let mut meters = METERS.lock().unwrap();
+    let meter = match meters.remove(name) {
+        Some(meter) => meter,
+        None => return Ok(None),
+    };
+    if let Err(e) = save(&meters) {
+        meters.insert(meter.name.clone(), meter);
+        return Err(e);
+    }
+    info!("Meter {} deleted", name);
+    poller::unschedule(name);
+    Ok(Some(meter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+
+    fn meter(name: &str) -> Meter {
+        Meter {
+            name: name.to_string(),
+            device: "ttyAMA0".to_string(),
+            baudrate: "2400".to_string(),
+            address: "48".to_string(),
+            ..Default::default()
+        }
+    }
+
+    // A path in a new temporary directory
+    fn temp(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mbus-httpd-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("meters.json")
+    }
+
+    #[test]
+    fn checks() {
+        assert!(check(&meter("hall")).is_ok());
+        assert!(check(&meter("")).is_err());
+        assert!(check(&meter("hall/1")).is_err());
+        assert!(check(&Meter {
+            device: String::new(),
+            ..meter("hall")
+        })
+        .is_err());
+        assert!(check(&Meter {
+            baudrate: "1234".to_string(),
+            ..meter("hall")
+        })
+        .is_err());
+        assert!(check(&Meter {
+            address: "256".to_string(),
+            ..meter("hall")
+        })
+        .is_err());
+        assert!(check(&Meter {
+            address: "1234567824400107".to_string(),
+            ..meter("hall")
+        })
+        .is_ok());
+
+        let key = |key: &str| Meter {
+            key: Some(key.to_string()),
+            ..meter("hall")
+        };
+        assert!(check(&key("000102030405060708090A0B0C0D0E0F")).is_ok());
+        assert!(check(&key("000102030405060708090A0B0C0D0E")).is_err());
+        assert!(check(&key("000102030405060708090A0B0C0D0E0G")).is_err());
+
+        // Poll settings are checked too
+        assert!(check(&Meter {
+            poll_interval: Some(0),
+            ..meter("hall")
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = temp("registry");
+        let mut meters = BTreeMap::new();
+        assert_eq!(read(path.to_str().unwrap()), Ok(meters.clone()));
+
+        for name in &["hall", "kitchen"] {
+            meters.insert(name.to_string(), meter(name));
+        }
+        meters.get_mut("hall").unwrap().key = Some("000102030405060708090A0B0C0D0E0F".to_string());
+        write(&path, &meters).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+        assert_eq!(read(path.to_str().unwrap()), Ok(meters.clone()));
+
+        // A file with an invalid meter isn't loaded
+        let invalid =
+            r#"{"Meter":[{"Name":"hall","Device":"ttyAMA0","Baudrate":"2400","Address":"300"}]}"#;
+        fs::write(&path, invalid).unwrap();
+        assert!(read(path.to_str().unwrap()).is_err());
+        fs::write(&path, "{").unwrap();
+        assert!(read(path.to_str().unwrap()).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
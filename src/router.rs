@@ -0,0 +1,1098 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Routes requests which the generated mbus_api server can't express -
+//! operations it doesn't know about, or those needing content negotiation -
+//! and passes everything else through to it.
+
+use chrono::{DateTime, TimeZone, Utc};
+use futures::future::{self, AbortHandle, MapOk, TryFutureExt};
+use futures::StreamExt;
+use hyper::header::{
+    HeaderValue, ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, LOCATION, RETRY_AFTER,
+    WWW_AUTHENTICATE,
+};
+use hyper::service::Service;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::info;
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::cell::RefCell;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use mbus_api::models;
+use mbus_api::{GetMultiResponse, GetResponse, ScanResponse};
+
+use crate::api::{
+    ScanSecondaryResponse, SelectResponse, SetAddressResponse, SwitchBaudrateResponse,
+};
+use crate::bus;
+use crate::config;
+use crate::data;
+use crate::history;
+use crate::http;
+use crate::influx;
+use crate::jobs;
+use crate::metrics;
+use crate::poller;
+use crate::progress::{Progress, ScanEvent};
+use crate::queue::{self, Priority, QueueError};
+use crate::registry::{self, RegistryError};
+use crate::webhooks::{self, WebhookError};
+
+const MIME_JSON: &str = "application/json";
+const MIME_XML: &str = "application/xml";
+const MIME_TEXT: &str = "text/plain";
+const MIME_LINE_PROTOCOL: &str = "application/vnd.influxdb.line-protocol";
+const MIME_METRICS: &str = "text/plain; version=0.0.4";
+const MIME_EVENT_STREAM: &str = "text/event-stream";
+
+// Let clients say how urgent a bus request is, and how long it may wait for
+// the bus
+const HEADER_PRIORITY: &str = "X-MBus-Priority";
+const HEADER_WAIT: &str = "X-MBus-Wait";
+
+// Operations which use the bus, and so must queue for it
+const BUS_OPERATIONS: [&str; 7] = [
+    "get",
+    "getMulti",
+    "scan",
+    "scan-secondary",
+    "select",
+    "set-address",
+    "switch-baudrate",
+];
+
+/// Wraps the generated make service, so each connection gets a Router.
+pub struct MakeRouter<M> {
+    inner: M,
+}
+
+impl<M> MakeRouter<M> {
+    pub fn new(inner: M) -> Self {
+        MakeRouter { inner }
+    }
+}
+
+impl<M, T> Service<T> for MakeRouter<M>
+where
+    M: Service<T>,
+{
+    type Response = Router<M::Response>;
+    type Error = M::Error;
+    type Future = MapOk<M::Future, fn(M::Response) -> Router<M::Response>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        self.inner.call(target).map_ok(Router::new)
+    }
+}
+
+#[derive(Clone)]
+pub struct Router<S> {
+    inner: S,
+}
+
+impl<S> Router<S> {
+    fn new(inner: S) -> Self {
+        Router { inner }
+    }
+}
+
+impl<S> Service<Request<Body>> for Router<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if !authorized(&req) {
+            let mut rsp = response(
+                StatusCode::UNAUTHORIZED,
+                MIME_TEXT,
+                "Unauthorized".to_string(),
+            );
+            rsp.headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return Box::pin(future::ok(rsp));
+        }
+        let queued = queue_options(&req);
+        let rsp: Self::Future = match route(&req) {
+            // Nothing to queue for if the request is bad
+            Some((Route::BadRequest(e), _)) => {
+                return Box::pin(future::ok(response(StatusCode::BAD_REQUEST, MIME_TEXT, e)))
+            }
+            Some((route, json)) => {
+                let body = req.into_body();
+                Box::pin(async move { Ok(handle(route, json, body).await) })
+            }
+            None => Box::pin(self.inner.call(req)),
+        };
+        match queued {
+            None => rsp,
+            Some(Err(e)) => Box::pin(future::ok(response(StatusCode::BAD_REQUEST, MIME_TEXT, e))),
+            // Wait for the bus before handling the request.  The handler
+            // takes the ticket, and holds it until it is done with the bus.
+            Some(Ok(q)) => Box::pin(async move {
+                match http::queue(&q.device).acquire(q.priority, q.wait).await {
+                    Ok(ticket) => {
+                        queue::REQUEST_TICKET
+                            .scope(RefCell::new(Some(ticket)), rsp)
+                            .await
+                    }
+                    Err(e) => Ok(busy_response(e)),
+                }
+            }),
+        }
+    }
+}
+
+enum Route {
+    Get {
+        device: String,
+        baudrate: models::Baudrate,
+        address: String,
+    },
+    // A get, as InfluxDB line protocol
+    GetLines {
+        device: String,
+        baudrate: models::Baudrate,
+        address: String,
+    },
+    GetMulti {
+        device: String,
+        baudrate: models::Baudrate,
+        address: String,
+        maxframes: i32,
+    },
+    Scan {
+        device: String,
+        baudrate: models::Baudrate,
+    },
+    ScanSecondary {
+        device: String,
+        baudrate: models::Baudrate,
+        mask: Option<String>,
+    },
+    // A primary scan, or a secondary one if there is a mask, streamed as
+    // server-sent events
+    ScanEvents {
+        device: String,
+        baudrate: models::Baudrate,
+        mask: Option<String>,
+    },
+    Select {
+        device: String,
+        baudrate: models::Baudrate,
+        address: String,
+    },
+    SetAddress {
+        device: String,
+        baudrate: models::Baudrate,
+        secondary: String,
+        address: String,
+    },
+    SwitchBaudrate {
+        device: String,
+        baudrate: models::Baudrate,
+        address: String,
+        new_baudrate: models::Baudrate,
+    },
+    ListMeters,
+    CreateMeter,
+    GetMeter(String),
+    PutMeter(String),
+    DeleteMeter(String),
+    // Read a meter in the registry, by name
+    Reading(String),
+    PollStatus(String),
+    // A meter's readings between two times
+    History {
+        name: String,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        record: Option<u32>,
+    },
+    CreateJob,
+    GetJob(String),
+    DeleteJob(String),
+    // Prometheus metrics
+    Metrics,
+    ListWebhooks,
+    CreateWebhook,
+    GetWebhook(String),
+    DeleteWebhook(String),
+    DeadLetters,
+    ClearDeadLetters,
+    // Reload the config file
+    Reload,
+    BadRequest(String),
+}
+
+// Whether the request has the API_TOKEN as a bearer token, if there is one.
+// Compares every byte, so the time taken doesn't give away how much of the
+// token was right.
+fn authorized(req: &Request<Body>) -> bool {
+    let token = match http::API_TOKEN.get() {
+        Some(token) => token,
+        None => return true,
+    };
+    let given = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("")
+        .as_bytes();
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// The media ranges a request's Accept headers list, with their q-values
+fn media_ranges(req: &Request<Body>) -> Vec<(String, f32)> {
+    req.headers()
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let mime = params.next()?.trim().to_ascii_lowercase();
+            if mime.is_empty() {
+                return None;
+            }
+            let q = params
+                .filter_map(|param| {
+                    let mut param = param.splitn(2, '=');
+                    match (param.next()?.trim(), param.next()) {
+                        ("q", Some(q)) | ("Q", Some(q)) => q.trim().parse::<f32>().ok(),
+                        _ => None,
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            Some((mime, q))
+        })
+        .collect()
+}
+
+// The q-value of a media type, from the most specific range matching it, or
+// 0 if none does
+fn quality(ranges: &[(String, f32)], mime: &str) -> f32 {
+    let wildcard = format!("{}/*", mime.split('/').next().unwrap_or(""));
+    ranges
+        .iter()
+        .filter_map(|(range, q)| {
+            let specificity = if range == mime {
+                2
+            } else if *range == wildcard {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                return None;
+            };
+            Some((specificity, *q))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, q)| q)
+        .unwrap_or(0.0)
+}
+
+// Whether a request prefers a media type to XML, which is the default
+fn accepts(req: &Request<Body>, mime: &str) -> bool {
+    let ranges = media_ranges(req);
+    let q = quality(&ranges, mime);
+    q > 0.0 && q > quality(&ranges, MIME_XML)
+}
+
+fn wants_json(req: &Request<Body>) -> bool {
+    accepts(req, MIME_JSON)
+}
+
+fn wants_lines(req: &Request<Body>) -> bool {
+    accepts(req, MIME_LINE_PROTOCOL)
+}
+
+fn wants_events(req: &Request<Body>) -> bool {
+    accepts(req, MIME_EVENT_STREAM)
+}
+
+// How a request which uses a bus queues for it
+struct Queued {
+    device: String,
+    priority: Priority,
+    wait: Option<Duration>,
+}
+
+fn queue_options(req: &Request<Body>) -> Option<Result<Queued, String>> {
+    let segments: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+    let device = match (req.method(), segments.as_slice()) {
+        (&Method::POST, ["mbus", operation, device, ..]) if BUS_OPERATIONS.contains(operation) => {
+            decode(device)
+        }
+        (&Method::GET, ["mbus", "meters", name, "reading"]) => registry::get(&decode(name))?.device,
+        _ => return None,
+    };
+
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .map(|v| v.to_str().map_err(|_| format!("Invalid {} header", name)))
+    };
+    let priority = match header(HEADER_PRIORITY) {
+        Some(Ok(v)) => match v.trim().parse::<Priority>() {
+            Ok(p) => p,
+            Err(e) => return Some(Err(e)),
+        },
+        Some(Err(e)) => return Some(Err(e)),
+        None => Priority::Interactive,
+    };
+    let wait = match header(HEADER_WAIT) {
+        Some(Ok(v)) => match v.trim().parse::<u64>() {
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => return Some(Err(format!("Invalid {} header: {}", HEADER_WAIT, v))),
+        },
+        Some(Err(e)) => return Some(Err(e)),
+        None => None,
+    };
+    Some(Ok(Queued {
+        device,
+        priority,
+        wait,
+    }))
+}
+
+fn decode(segment: &str) -> String {
+    percent_decode_str(segment).decode_utf8_lossy().to_string()
+}
+
+fn baudrate(segment: &str) -> Result<models::Baudrate, String> {
+    decode(segment)
+        .parse::<models::Baudrate>()
+        .map_err(|_| format!("Invalid baudrate: {}", segment))
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    from: Option<String>,
+    to: Option<String>,
+    record: Option<u32>,
+}
+
+// A request for the named meter's history, over the time range and for the
+// record the query asks for.  Defaults to all records, from the beginning
+// until now.
+fn history_route(name: String, query: Option<&str>) -> Result<Route, String> {
+    let query: HistoryQuery = serde_urlencoded::from_str(query.unwrap_or(""))
+        .map_err(|e| format!("Invalid history query: {}", e))?;
+    let time = |t: Option<String>, default: DateTime<Utc>| match t {
+        Some(t) => DateTime::parse_from_rfc3339(&t)
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(|_| format!("Invalid time: {}", t)),
+        None => Ok(default),
+    };
+    let from = time(query.from, Utc.timestamp_opt(0, 0).unwrap())?;
+    let to = time(query.to, Utc::now())?;
+    Ok(Route::History {
+        name,
+        from,
+        to,
+        record: query.record,
+    })
+}
+
+// Returns the route, and whether to respond with JSON
+fn route(req: &Request<Body>) -> Option<(Route, bool)> {
+    let segments: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+
+    // Jobs and meters are managed using JSON, although readings can be XML
+    match (req.method(), segments.as_slice()) {
+        (&Method::GET, ["mbus", "meters"]) => return Some((Route::ListMeters, true)),
+        (&Method::POST, ["mbus", "meters"]) => return Some((Route::CreateMeter, true)),
+        (&Method::GET, ["mbus", "meters", name]) => {
+            return Some((Route::GetMeter(decode(name)), true))
+        }
+        (&Method::PUT, ["mbus", "meters", name]) => {
+            return Some((Route::PutMeter(decode(name)), true))
+        }
+        (&Method::DELETE, ["mbus", "meters", name]) => {
+            return Some((Route::DeleteMeter(decode(name)), true))
+        }
+        (&Method::GET, ["mbus", "meters", name, "reading"]) => {
+            return Some((Route::Reading(decode(name)), wants_json(req)))
+        }
+        (&Method::GET, ["mbus", "meters", name, "history"]) => {
+            let route = history_route(decode(name), req.uri().query());
+            return Some((route.unwrap_or_else(Route::BadRequest), true));
+        }
+        (&Method::GET, ["mbus", "meters", name, "poll"]) => {
+            return Some((Route::PollStatus(decode(name)), true))
+        }
+        (&Method::POST, ["mbus", "jobs"]) => return Some((Route::CreateJob, true)),
+        (&Method::GET, ["metrics"]) => return Some((Route::Metrics, false)),
+        (&Method::POST, ["admin", "reload"]) => return Some((Route::Reload, true)),
+        (&Method::GET, ["mbus", "webhooks"]) => return Some((Route::ListWebhooks, true)),
+        (&Method::POST, ["mbus", "webhooks"]) => return Some((Route::CreateWebhook, true)),
+        (&Method::GET, ["mbus", "webhooks", "dead-letters"]) => {
+            return Some((Route::DeadLetters, true))
+        }
+        (&Method::DELETE, ["mbus", "webhooks", "dead-letters"]) => {
+            return Some((Route::ClearDeadLetters, true))
+        }
+        (&Method::GET, ["mbus", "webhooks", id]) => {
+            return Some((Route::GetWebhook(decode(id)), true))
+        }
+        (&Method::DELETE, ["mbus", "webhooks", id]) => {
+            return Some((Route::DeleteWebhook(decode(id)), true))
+        }
+        (&Method::GET, ["mbus", "jobs", id]) => return Some((Route::GetJob(decode(id)), true)),
+        (&Method::DELETE, ["mbus", "jobs", id]) => {
+            return Some((Route::DeleteJob(decode(id)), true))
+        }
+        _ => (),
+    }
+
+    if req.method() != Method::POST {
+        return None;
+    }
+    let json = wants_json(req);
+    let events = wants_events(req);
+    let lines = wants_lines(req);
+
+    // Operations the generated server supports are only handled here if
+    // they want JSON (or, for a get, line protocol).  XML (or no preference)
+    // goes to the generated server.
+    let route = match segments.as_slice() {
+        ["mbus", "get", device, rate, address] if json => match baudrate(rate) {
+            Ok(baudrate) => Route::Get {
+                device: decode(device),
+                baudrate,
+                address: decode(address),
+            },
+            Err(e) => Route::BadRequest(e),
+        },
+        ["mbus", "get", device, rate, address] if lines => match baudrate(rate) {
+            Ok(baudrate) => Route::GetLines {
+                device: decode(device),
+                baudrate,
+                address: decode(address),
+            },
+            Err(e) => Route::BadRequest(e),
+        },
+        ["mbus", "getMulti", device, rate, address, maxframes] if json => {
+            match (baudrate(rate), decode(maxframes).parse::<i32>()) {
+                (Ok(baudrate), Ok(maxframes)) => Route::GetMulti {
+                    device: decode(device),
+                    baudrate,
+                    address: decode(address),
+                    maxframes,
+                },
+                (Err(e), _) => Route::BadRequest(e),
+                (_, Err(_)) => Route::BadRequest(format!("Invalid maxframes: {}", maxframes)),
+            }
+        }
+        ["mbus", "scan", device, rate] if events => match baudrate(rate) {
+            Ok(baudrate) => Route::ScanEvents {
+                device: decode(device),
+                baudrate,
+                mask: None,
+            },
+            Err(e) => Route::BadRequest(e),
+        },
+        ["mbus", "scan", device, rate] if json => match baudrate(rate) {
+            Ok(baudrate) => Route::Scan {
+                device: decode(device),
+                baudrate,
+            },
+            Err(e) => Route::BadRequest(e),
+        },
+        ["mbus", "scan-secondary", device, rate, mask @ ..] if events && mask.len() <= 1 => {
+            match baudrate(rate) {
+                Ok(baudrate) => Route::ScanEvents {
+                    device: decode(device),
+                    baudrate,
+                    mask: Some(
+                        mask.first()
+                            .map(|m| decode(m))
+                            .unwrap_or_else(|| bus::SECONDARY_WILDCARD.to_string()),
+                    ),
+                },
+                Err(e) => Route::BadRequest(e),
+            }
+        }
+        ["mbus", "scan-secondary", device, rate, mask @ ..] if mask.len() <= 1 => {
+            match baudrate(rate) {
+                Ok(baudrate) => Route::ScanSecondary {
+                    device: decode(device),
+                    baudrate,
+                    mask: mask.first().map(|m| decode(m)),
+                },
+                Err(e) => Route::BadRequest(e),
+            }
+        }
+        ["mbus", "select", device, rate, address] => match baudrate(rate) {
+            Ok(baudrate) => Route::Select {
+                device: decode(device),
+                baudrate,
+                address: decode(address),
+            },
+            Err(e) => Route::BadRequest(e),
+        },
+        ["mbus", "set-address", device, rate, secondary, address] => match baudrate(rate) {
+            Ok(baudrate) => Route::SetAddress {
+                device: decode(device),
+                baudrate,
+                secondary: decode(secondary),
+                address: decode(address),
+            },
+            Err(e) => Route::BadRequest(e),
+        },
+        ["mbus", "switch-baudrate", device, rate, address, new_rate] => {
+            match (baudrate(rate), baudrate(new_rate)) {
+                (Ok(baudrate), Ok(new_baudrate)) => Route::SwitchBaudrate {
+                    device: decode(device),
+                    baudrate,
+                    address: decode(address),
+                    new_baudrate,
+                },
+                (Err(e), _) | (_, Err(e)) => Route::BadRequest(e),
+            }
+        }
+        _ => return None,
+    };
+    Some((route, json))
+}
+
+fn response(status: StatusCode, mime: &'static str, body: String) -> Response<Body> {
+    let mut rsp = Response::new(Body::from(body));
+    *rsp.status_mut() = status;
+    rsp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(mime));
+    rsp
+}
+
+fn busy_response(e: QueueError) -> Response<Body> {
+    info!("Response -> {:?}: {}", StatusCode::SERVICE_UNAVAILABLE, e);
+    let mut rsp = response(StatusCode::SERVICE_UNAVAILABLE, MIME_TEXT, e.to_string());
+    rsp.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(e.retry_after().as_secs()));
+    rsp
+}
+
+fn json_response(json: Result<String, String>) -> Response<Body> {
+    match json {
+        Ok(json) => response(StatusCode::OK, MIME_JSON, json),
+        Err(e) => response(StatusCode::INTERNAL_SERVER_ERROR, MIME_TEXT, e),
+    }
+}
+
+// Render a document as JSON or XML, as requested
+fn doc_response<T: Serialize>(json: bool, doc: &T, to_xml: fn(&T) -> String) -> Response<Body> {
+    if json {
+        json_response(data::to_json(doc))
+    } else {
+        response(StatusCode::OK, MIME_XML, to_xml(doc))
+    }
+}
+
+// Aborts a task when dropped
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// Render a scan event as a server-sent event
+fn sse(event: &ScanEvent) -> String {
+    let (name, data) = match event {
+        ScanEvent::Probe(probe) => ("probe", data::to_json(probe)),
+        ScanEvent::Device(slave) => ("device", data::to_json(slave)),
+        ScanEvent::Complete(scan) => ("complete", data::to_json(scan)),
+        ScanEvent::Error(e) => ("error", data::to_json(e)),
+    };
+    match data {
+        Ok(data) => format!("event: {}\ndata: {}\n\n", name, data),
+        Err(e) => format!(
+            "event: error\ndata: {}\n\n",
+            data::to_json(&e).unwrap_or_default()
+        ),
+    }
+}
+
+// Stream a scan as server-sent events: one for each address probed and each
+// slave found, then one with the complete scan.  The scan runs in its own
+// task, which holds this request's ticket for the bus, and is cancelled if
+// the client goes away.
+async fn scan_events(
+    device: String,
+    baudrate: models::Baudrate,
+    mask: Option<String>,
+) -> Response<Body> {
+    let mask = mask.map(|m| m.to_ascii_uppercase());
+    if let Some(Err(e)) = mask.as_ref().map(|m| bus::pack_secondary(m)) {
+        return response(StatusCode::BAD_REQUEST, MIME_TEXT, e);
+    }
+    let ticket = match queue::ticket(&http::queue(&device)).await {
+        Ok(ticket) => ticket,
+        Err(e) => return busy_response(e),
+    };
+    info!("Streaming scan: {} at {} for {:?}", device, baudrate, mask);
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let progress = Progress::with_events(tx);
+    let scan = async move {
+        let result = match mask {
+            Some(mask) => {
+                http::scan_secondary_doc(&device, &baudrate, &mask, progress.clone()).await
+            }
+            None => http::scan_doc(&device, &baudrate, progress.clone()).await,
+        };
+        progress.send(match result {
+            Ok(scan) => ScanEvent::Complete(scan),
+            Err(e) => ScanEvent::Error(e),
+        });
+    };
+    let scan = queue::REQUEST_TICKET.scope(RefCell::new(Some(ticket)), scan);
+    let (scan, abort) = future::abortable(scan);
+    tokio::spawn(scan);
+
+    // The stream ends once the scan has finished and dropped its sender
+    let cancel = AbortOnDrop(abort);
+    let events = rx.map(move |event| {
+        let _ = &cancel;
+        Ok::<_, Infallible>(sse(&event))
+    });
+    let mut rsp = Response::new(Body::wrap_stream(events));
+    rsp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(MIME_EVENT_STREAM));
+    rsp.headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    rsp
+}
+
+// Read a JSON request body
+async fn read_body<T>(body: Body, parse: fn(&[u8]) -> Result<T, String>) -> Result<T, String> {
+    match hyper::body::to_bytes(body).await {
+        Ok(bytes) => parse(&bytes),
+        Err(e) => Err(format!("Failed to read request: {}", e)),
+    }
+}
+
+// Respond with a newly created document, and where to find it
+fn created_response<T: Serialize>(status: StatusCode, doc: &T, location: &str) -> Response<Body> {
+    match data::to_json(doc) {
+        Ok(json) => {
+            let mut rsp = response(status, MIME_JSON, json);
+            if let Ok(location) = HeaderValue::from_str(location) {
+                rsp.headers_mut().insert(LOCATION, location);
+            }
+            rsp
+        }
+        Err(e) => response(StatusCode::INTERNAL_SERVER_ERROR, MIME_TEXT, e),
+    }
+}
+
+async fn create_job(body: Body) -> Response<Body> {
+    let request = read_body(body, data::job_request_from_json).await;
+    match request.and_then(jobs::create) {
+        Ok(job) => created_response(
+            StatusCode::ACCEPTED,
+            &job,
+            &format!("/mbus/jobs/{}", job.id),
+        ),
+        Err(e) => response(StatusCode::BAD_REQUEST, MIME_TEXT, e),
+    }
+}
+
+fn registry_error_response(e: RegistryError) -> Response<Body> {
+    let status = match e {
+        RegistryError::Invalid(_) => StatusCode::BAD_REQUEST,
+        RegistryError::Exists(_) => StatusCode::CONFLICT,
+        RegistryError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    response(status, MIME_TEXT, e.to_string())
+}
+
+fn meter_response(meter: Option<data::Meter>, name: &str) -> Response<Body> {
+    match meter {
+        Some(meter) => json_response(data::to_json(&meter.redacted())),
+        None => response(
+            StatusCode::NOT_FOUND,
+            MIME_TEXT,
+            format!("No meter {}", name),
+        ),
+    }
+}
+
+async fn create_meter(body: Body) -> Response<Body> {
+    let meter = match read_body(body, data::meter_from_json).await {
+        Ok(meter) => meter,
+        Err(e) => return response(StatusCode::BAD_REQUEST, MIME_TEXT, e),
+    };
+    match registry::create(meter) {
+        Ok(meter) => created_response(
+            StatusCode::CREATED,
+            &meter.redacted(),
+            &format!("/mbus/meters/{}", meter.name),
+        ),
+        Err(e) => registry_error_response(e),
+    }
+}
+
+async fn put_meter(name: String, body: Body) -> Response<Body> {
+    let meter = match read_body(body, data::meter_from_json).await {
+        Ok(meter) => meter,
+        Err(e) => return response(StatusCode::BAD_REQUEST, MIME_TEXT, e),
+    };
+    match registry::put(&name, meter) {
+        Ok((meter, true)) => created_response(
+            StatusCode::CREATED,
+            &meter.redacted(),
+            &format!("/mbus/meters/{}", meter.name),
+        ),
+        Ok((meter, false)) => json_response(data::to_json(&meter.redacted())),
+        Err(e) => registry_error_response(e),
+    }
+}
+
+fn webhook_error_response(e: WebhookError) -> Response<Body> {
+    let status = match e {
+        WebhookError::Invalid(_) => StatusCode::BAD_REQUEST,
+        WebhookError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    response(status, MIME_TEXT, e.to_string())
+}
+
+fn webhook_response(webhook: Option<data::Webhook>, id: &str) -> Response<Body> {
+    match webhook {
+        Some(webhook) => json_response(data::to_json(&webhook.redacted())),
+        None => response(
+            StatusCode::NOT_FOUND,
+            MIME_TEXT,
+            format!("No webhook {}", id),
+        ),
+    }
+}
+
+async fn create_webhook(body: Body) -> Response<Body> {
+    let webhook = match read_body(body, data::webhook_from_json).await {
+        Ok(webhook) => webhook,
+        Err(e) => return response(StatusCode::BAD_REQUEST, MIME_TEXT, e),
+    };
+    match webhooks::create(webhook) {
+        Ok(webhook) => created_response(
+            StatusCode::CREATED,
+            &webhook.redacted(),
+            &format!("/mbus/webhooks/{}", webhook.id),
+        ),
+        Err(e) => webhook_error_response(e),
+    }
+}
+
+// Render the response to a get as JSON or XML, as requested
+fn get_response(rsp: GetResponse, json: bool) -> Response<Body> {
+    match rsp {
+        GetResponse::OK(xml) if json => {
+            json_response(data::from_xml(&xml).and_then(|d| data::to_json(&d)))
+        }
+        GetResponse::OK(xml) => response(StatusCode::OK, MIME_XML, xml),
+        GetResponse::BadRequest(e) => response(StatusCode::BAD_REQUEST, MIME_TEXT, e),
+        GetResponse::NotFound(e) => response(StatusCode::NOT_FOUND, MIME_TEXT, e),
+    }
+}
+
+// Render the response to a get as InfluxDB line protocol
+fn lines_response(rsp: GetResponse, device: &str, address: &str) -> Response<Body> {
+    match rsp {
+        GetResponse::OK(xml) => match data::from_xml(&xml) {
+            Ok(reading) => {
+                let mut lines = influx::lines(device, address, &reading, Utc::now()).join("\n");
+                lines.push('\n');
+                response(StatusCode::OK, MIME_LINE_PROTOCOL, lines)
+            }
+            Err(e) => response(StatusCode::INTERNAL_SERVER_ERROR, MIME_TEXT, e),
+        },
+        GetResponse::BadRequest(e) => response(StatusCode::BAD_REQUEST, MIME_TEXT, e),
+        GetResponse::NotFound(e) => response(StatusCode::NOT_FOUND, MIME_TEXT, e),
+    }
+}
+
+async fn reading(name: String, json: bool) -> Response<Body> {
+    let meter = match registry::get(&name) {
+        Some(meter) => meter,
+        None => return meter_response(None, &name),
+    };
+    let baudrate = match baudrate(&meter.baudrate) {
+        Ok(baudrate) => baudrate,
+        Err(e) => return response(StatusCode::INTERNAL_SERVER_ERROR, MIME_TEXT, e),
+    };
+    let rsp = http::get(&meter.device, &baudrate, &meter.address).await;
+    if let GetResponse::OK(ref xml) = rsp {
+        if let Ok(reading) = data::from_xml(xml) {
+            history::record(&name, &reading).await;
+        }
+    }
+    get_response(rsp, json)
+}
+
+fn job_response(job: Option<data::MBusJob>, id: &str) -> Response<Body> {
+    match job {
+        Some(job) => json_response(data::to_json(&job)),
+        None => response(StatusCode::NOT_FOUND, MIME_TEXT, format!("No job {}", id)),
+    }
+}
+
+async fn handle(route: Route, json: bool, body: Body) -> Response<Body> {
+    let rsp = match route {
+        Route::Get {
+            device,
+            baudrate,
+            address,
+        } => get_response(http::get(&device, &baudrate, &address).await, json),
+        Route::GetLines {
+            device,
+            baudrate,
+            address,
+        } => lines_response(
+            http::get(&device, &baudrate, &address).await,
+            &device,
+            &address,
+        ),
+        Route::GetMulti {
+            device,
+            baudrate,
+            address,
+            maxframes,
+        } => match http::get_multi(&device, &baudrate, &address, &maxframes).await {
+            GetMultiResponse::OK(xml) => {
+                json_response(data::multi_reply_from_xml(&xml).and_then(|m| data::to_json(&m)))
+            }
+            GetMultiResponse::BadRequest(e) => response(StatusCode::BAD_REQUEST, MIME_TEXT, e),
+            GetMultiResponse::NotFound(e) => response(StatusCode::NOT_FOUND, MIME_TEXT, e),
+        },
+        Route::Scan { device, baudrate } => match http::scan(&device, &baudrate).await {
+            ScanResponse::OK(xml) => {
+                json_response(data::scan_from_xml(&xml).and_then(|s| data::to_json(&s)))
+            }
+            ScanResponse::BadRequest(e) => response(StatusCode::BAD_REQUEST, MIME_TEXT, e),
+            ScanResponse::NotFound(e) => response(StatusCode::NOT_FOUND, MIME_TEXT, e),
+        },
+        Route::ScanSecondary {
+            device,
+            baudrate,
+            mask,
+        } => match http::scan_secondary(&device, &baudrate, &mask).await {
+            ScanSecondaryResponse::OK(scan) => doc_response(json, &scan, data::scan_to_xml),
+            ScanSecondaryResponse::BadRequest(e) => response(StatusCode::BAD_REQUEST, MIME_TEXT, e),
+            ScanSecondaryResponse::NotFound(e) => response(StatusCode::NOT_FOUND, MIME_TEXT, e),
+        },
+        Route::ScanEvents {
+            device,
+            baudrate,
+            mask,
+        } => scan_events(device, baudrate, mask).await,
+        Route::Select {
+            device,
+            baudrate,
+            address,
+        } => match http::select(&device, &baudrate, &address).await {
+            SelectResponse::OK(select) => doc_response(json, &select, data::select_to_xml),
+            SelectResponse::BadRequest(e) => response(StatusCode::BAD_REQUEST, MIME_TEXT, e),
+            SelectResponse::NotFound(e) => response(StatusCode::NOT_FOUND, MIME_TEXT, e),
+        },
+        Route::SetAddress {
+            device,
+            baudrate,
+            secondary,
+            address,
+        } => match http::set_address(&device, &baudrate, &secondary, &address).await {
+            SetAddressResponse::OK(set) => doc_response(json, &set, data::set_address_to_xml),
+            SetAddressResponse::BadRequest(e) => response(StatusCode::BAD_REQUEST, MIME_TEXT, e),
+            SetAddressResponse::NotFound(e) => response(StatusCode::NOT_FOUND, MIME_TEXT, e),
+        },
+        Route::SwitchBaudrate {
+            device,
+            baudrate,
+            address,
+            new_baudrate,
+        } => match http::switch_baudrate(&device, &baudrate, &address, &new_baudrate).await {
+            SwitchBaudrateResponse::OK(switch) => {
+                doc_response(json, &switch, data::switch_baudrate_to_xml)
+            }
+            SwitchBaudrateResponse::BadRequest(e) => {
+                response(StatusCode::BAD_REQUEST, MIME_TEXT, e)
+            }
+            SwitchBaudrateResponse::NotFound(e) => response(StatusCode::NOT_FOUND, MIME_TEXT, e),
+        },
+        Route::ListMeters => {
+            let meters = data::MBusMeters {
+                meters: registry::list().iter().map(data::Meter::redacted).collect(),
+            };
+            json_response(data::to_json(&meters))
+        }
+        Route::CreateMeter => create_meter(body).await,
+        Route::GetMeter(name) => meter_response(registry::get(&name), &name),
+        Route::PutMeter(name) => put_meter(name, body).await,
+        Route::DeleteMeter(name) => match registry::delete(&name) {
+            Ok(meter) => meter_response(meter, &name),
+            Err(e) => registry_error_response(e),
+        },
+        Route::Reading(name) => reading(name, json).await,
+        Route::History {
+            name,
+            from,
+            to,
+            record,
+        } => match registry::get(&name) {
+            Some(_) => match history::query(&name, from, to, record).await {
+                Ok(history) => json_response(data::to_json(&history)),
+                Err(e) => response(StatusCode::INTERNAL_SERVER_ERROR, MIME_TEXT, e),
+            },
+            None => meter_response(None, &name),
+        },
+        Route::Metrics => match metrics::gather() {
+            Ok(metrics) => response(StatusCode::OK, MIME_METRICS, metrics),
+            Err(e) => response(StatusCode::INTERNAL_SERVER_ERROR, MIME_TEXT, e),
+        },
+        Route::PollStatus(name) => match registry::get(&name) {
+            Some(_) => json_response(data::to_json(&poller::status(&name))),
+            None => meter_response(None, &name),
+        },
+        Route::ListWebhooks => {
+            let webhooks = data::MBusWebhooks {
+                webhooks: webhooks::list()
+                    .iter()
+                    .map(data::Webhook::redacted)
+                    .collect(),
+            };
+            json_response(data::to_json(&webhooks))
+        }
+        Route::CreateWebhook => create_webhook(body).await,
+        Route::GetWebhook(id) => webhook_response(webhooks::get(&id), &id),
+        Route::DeleteWebhook(id) => match webhooks::delete(&id) {
+            Ok(webhook) => webhook_response(webhook, &id),
+            Err(e) => webhook_error_response(e),
+        },
+        Route::DeadLetters => json_response(data::to_json(&webhooks::dead_letters())),
+        Route::ClearDeadLetters => json_response(data::to_json(&webhooks::clear_dead_letters())),
+        Route::Reload => match config::reload() {
+            Ok(reload) => json_response(data::to_json(&reload)),
+            Err(e) => response(StatusCode::BAD_REQUEST, MIME_TEXT, e),
+        },
+        Route::CreateJob => create_job(body).await,
+        Route::GetJob(id) => job_response(jobs::get(&id), &id),
+        Route::DeleteJob(id) => job_response(jobs::delete(&id), &id),
+        Route::BadRequest(e) => response(StatusCode::BAD_REQUEST, MIME_TEXT, e),
+    };
+    info!("Response -> {:?}", rsp.status());
+    rsp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(accept: &[&str]) -> Request<Body> {
+        let mut req = Request::post("/mbus/get/ttyAMA0/2400/48");
+        for accept in accept {
+            req = req.header(ACCEPT, *accept);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn json(accept: &str) -> bool {
+        wants_json(&request(&[accept]))
+    }
+
+    #[test]
+    fn xml_by_default() {
+        assert!(!wants_json(&request(&[])));
+        assert!(!json("*/*"));
+        assert!(!json("application/*"));
+        assert!(!json("application/xml"));
+        assert!(!json("application/xml, application/json"));
+    }
+
+    #[test]
+    fn json_when_preferred() {
+        assert!(json("application/json"));
+        assert!(json("APPLICATION/JSON"));
+        assert!(json("text/plain, application/json"));
+        assert!(json("application/json; charset=utf-8"));
+        assert!(json("application/xml;q=0.4, application/json;q=0.5"));
+        assert!(json("application/*;q=0.2, application/json"));
+        assert!(wants_json(&request(&["text/plain", "application/json"])));
+    }
+
+    #[test]
+    fn q_values() {
+        assert!(!json("application/xml, application/json;q=0.1"));
+        assert!(!json("application/json;q=0"));
+        assert!(!json("application/json;q=0, */*"));
+        assert!(!json("application/json;q=0.5, */*"));
+        assert!(json(
+            "application/json;q=0.5, application/xml;q=0.1, */*;q=0.9"
+        ));
+    }
+
+    #[test]
+    fn whole_types_only() {
+        assert!(!json("application/jsonfoo"));
+        assert!(!json("application/json-seq"));
+        assert!(!json("application"));
+        assert!(!json(",;q=1"));
+    }
+
+    #[test]
+    fn other_types() {
+        let req = request(&["text/event-stream"]);
+        assert!(wants_events(&req));
+        assert!(!wants_json(&req));
+
+        let req = request(&["application/xml, text/plain"]);
+        assert!(!wants_lines(&req));
+        assert!(!wants_json(&req));
+        assert!(!wants_events(&req));
+
+        assert!(wants_lines(&request(&[MIME_LINE_PROTOCOL])));
+    }
+}
@@ -0,0 +1,337 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! M-Bus serial link layer, talking to a tty device via termios.
+
+use mbus_api::models;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::termios::{self, BaudRate, ControlFlags, FlushArg, SetArg, SpecialCharacterIndices};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::frame::{self, Frame};
+
+// EN 13757-2 character: start bit, 8 data bits, even parity, stop bit
+const BITS_PER_CHAR: u32 = 11;
+
+// A slave must start responding within 330 bit times, plus 50ms
+const RESPONSE_BITS: u32 = 330;
+const RESPONSE_EXTRA_MS: u64 = 50;
+
+// Within a frame, a gap of more than 11 bit times ends the frame.  The
+// same 50ms margin is allowed to cover USB adapter and scheduler latency.
+const INTER_BYTE_BITS: u32 = BITS_PER_CHAR;
+const INTER_BYTE_EXTRA_MS: u64 = 50;
+
+// VTIME is in tenths of a second - the granularity of our reads
+const READ_VTIME: u8 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    /// No (complete) frame was received within the allowed time
+    Timeout,
+    /// Bytes were received but did not form a valid frame
+    Frame(String),
+    /// The device could not be opened, configured, read or written
+    Io(String),
+    /// The operation was cancelled before the frame was sent
+    Cancelled,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Timeout => write!(f, "Timed out waiting for response"),
+            Error::Frame(e) => write!(f, "Invalid frame received: {}", e),
+            Error::Io(e) => write!(f, "Serial error: {}", e),
+            Error::Cancelled => write!(f, "Operation cancelled"),
+        }
+    }
+}
+
+/// Convert an API baudrate into bits per second.
+pub fn baudrate_bps(baudrate: &models::Baudrate) -> Result<u32, String> {
+    baudrate
+        .to_string()
+        .parse::<u32>()
+        .map_err(|e| format!("Invalid baudrate {}: {}", baudrate, e))
+}
+
+fn termios_baudrate(bps: u32) -> Result<BaudRate, String> {
+    match bps {
+        300 => Ok(BaudRate::B300),
+        600 => Ok(BaudRate::B600),
+        1200 => Ok(BaudRate::B1200),
+        2400 => Ok(BaudRate::B2400),
+        4800 => Ok(BaudRate::B4800),
+        9600 => Ok(BaudRate::B9600),
+        19200 => Ok(BaudRate::B19200),
+        38400 => Ok(BaudRate::B38400),
+        _ => Err(format!("Unsupported baudrate: {}", bps)),
+    }
+}
+
+fn bit_times(bps: u32, bits: u32, extra_ms: u64) -> Duration {
+    Duration::from_micros(bits as u64 * 1_000_000 / bps as u64) + Duration::from_millis(extra_ms)
+}
+
+/// An open serial connection to an M-Bus master (level converter).
+pub struct Serial {
+    file: File,
+    response_timeout: Duration,
+    inter_byte_timeout: Duration,
+}
+
+impl Serial {
+    /// Open the serial device at `path`, configured for M-Bus (8E1) at the
+    /// requested baudrate.
+    pub fn open<P: AsRef<Path>>(path: P, baudrate: &models::Baudrate) -> Result<Serial, Error> {
+        let bps = baudrate_bps(baudrate).map_err(Error::Io)?;
+        Serial::open_bps(path, bps)
+    }
+
+    /// As open(), but with the baudrate in bits per second.
+    pub fn open_bps<P: AsRef<Path>>(path: P, bps: u32) -> Result<Serial, Error> {
+        let path = path.as_ref();
+        debug!("Open serial device {:?} at {} baud", path, bps);
+        // Opened non-blocking so open() doesn't wait for carrier before
+        // CLOCAL is set
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(nix::libc::O_NOCTTY | nix::libc::O_NONBLOCK)
+            .open(path)
+            .map_err(|e| Error::Io(format!("Failed to open {:?}: {}", path, e)))?;
+        let mut serial = Serial {
+            file,
+            response_timeout: Duration::default(),
+            inter_byte_timeout: Duration::default(),
+        };
+        serial.set_bps(bps)?;
+
+        // Reads rely on VTIME to wait for bytes
+        let fd = serial.file.as_raw_fd();
+        let flags = fcntl(fd, FcntlArg::F_GETFL)
+            .map_err(|e| Error::Io(format!("Failed to get file flags: {}", e)))?;
+        let flags = OFlag::from_bits_truncate(flags) - OFlag::O_NONBLOCK;
+        fcntl(fd, FcntlArg::F_SETFL(flags))
+            .map_err(|e| Error::Io(format!("Failed to set file flags: {}", e)))?;
+        Ok(serial)
+    }
+
+    /// Reconfigure the port to a new baudrate, for example after asking a
+    /// slave to switch speed.
+    pub fn set_bps(&mut self, bps: u32) -> Result<(), Error> {
+        let rate = termios_baudrate(bps).map_err(Error::Io)?;
+        let fd = self.file.as_raw_fd();
+        let mut tio = termios::tcgetattr(fd)
+            .map_err(|e| Error::Io(format!("Failed to get terminal attributes: {}", e)))?;
+
+        termios::cfmakeraw(&mut tio);
+        // 8 data bits, even parity, 1 stop bit, no modem control lines
+        tio.control_flags
+            .remove(ControlFlags::CSIZE | ControlFlags::PARODD | ControlFlags::CSTOPB);
+        tio.control_flags.insert(
+            ControlFlags::CS8 | ControlFlags::PARENB | ControlFlags::CREAD | ControlFlags::CLOCAL,
+        );
+        // Reads return as soon as a byte is available, or after VTIME
+        tio.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+        tio.control_chars[SpecialCharacterIndices::VTIME as usize] = READ_VTIME;
+        termios::cfsetspeed(&mut tio, rate)
+            .map_err(|e| Error::Io(format!("Failed to set baudrate {}: {}", bps, e)))?;
+        termios::tcsetattr(fd, SetArg::TCSANOW, &tio)
+            .map_err(|e| Error::Io(format!("Failed to set terminal attributes: {}", e)))?;
+
+        self.response_timeout = bit_times(bps, RESPONSE_BITS, RESPONSE_EXTRA_MS);
+        self.inter_byte_timeout = bit_times(bps, INTER_BYTE_BITS, INTER_BYTE_EXTRA_MS);
+        Ok(())
+    }
+
+    /// Send a frame, discarding anything left unread from earlier exchanges.
+    pub fn send(&mut self, frame: &Frame) -> Result<(), Error> {
+        let bytes = frame.encode().map_err(Error::Frame)?;
+        debug!("Send: {:02X?}", bytes);
+        let fd = self.file.as_raw_fd();
+        termios::tcflush(fd, FlushArg::TCIFLUSH)
+            .map_err(|e| Error::Io(format!("Failed to flush input: {}", e)))?;
+        self.file
+            .write_all(&bytes)
+            .map_err(|e| Error::Io(format!("Failed to write frame: {}", e)))?;
+        termios::tcdrain(fd).map_err(|e| Error::Io(format!("Failed to drain output: {}", e)))
+    }
+
+    /// Wait for a frame from a slave, applying the EN 13757-2 response and
+    /// inter-byte timeouts.
+    pub fn recv(&mut self) -> Result<Frame, Error> {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut deadline = Instant::now() + self.response_timeout;
+        loop {
+            let mut byte = [0u8; 1];
+            let read = self
+                .file
+                .read(&mut byte)
+                .map_err(|e| Error::Io(format!("Failed to read: {}", e)))?;
+            if read == 0 {
+                if Instant::now() < deadline {
+                    continue;
+                }
+                if buf.is_empty() {
+                    return Err(Error::Timeout);
+                }
+                // Frame truncated part way through
+                debug!("Recv (truncated): {:02X?}", buf);
+                return Err(Error::Frame(format!("Incomplete frame: {:02X?}", buf)));
+            }
+
+            buf.push(byte[0]);
+            deadline = Instant::now() + self.inter_byte_timeout;
+            match frame::length(&buf) {
+                Ok(Some(len)) if buf.len() >= len => break,
+                Ok(_) => (),
+                Err(e) => {
+                    debug!("Recv (invalid): {:02X?}", buf);
+                    self.discard();
+                    return Err(Error::Frame(e));
+                }
+            }
+        }
+        debug!("Recv: {:02X?}", buf);
+        Frame::decode(&buf).map_err(Error::Frame)
+    }
+
+    /// Send a frame and wait for the response.
+    pub fn send_recv(&mut self, frame: &Frame) -> Result<Frame, Error> {
+        self.send(frame)?;
+        self.recv()
+    }
+
+    /// Read and throw away bytes until the line goes quiet, returning
+    /// whether anything was received.  This stops the remains of a corrupt
+    /// frame (e.g. from a collision) being mistaken for the start of the
+    /// next one, and can detect extra responses after an apparently valid
+    /// one.
+    pub fn discard(&mut self) -> bool {
+        let mut deadline = Instant::now() + self.inter_byte_timeout;
+        let mut byte = [0u8; 1];
+        let mut received = false;
+        while Instant::now() < deadline {
+            match self.file.read(&mut byte) {
+                Ok(0) => (),
+                Ok(_) => {
+                    received = true;
+                    deadline = Instant::now() + self.inter_byte_timeout;
+                }
+                Err(_) => break,
+            }
+        }
+        received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::pty::openpty;
+    use nix::unistd;
+    use std::fs;
+    use std::os::unix::io::RawFd;
+
+    // A serial port on the slave side of a pseudo-terminal, and the master
+    // side, standing in for the meters
+    fn pty() -> (Serial, RawFd) {
+        let pty = openpty(None, None).unwrap();
+        let path = fs::read_link(format!("/proc/self/fd/{}", pty.slave)).unwrap();
+        let serial = Serial::open_bps(&path, 2400).unwrap();
+        unistd::close(pty.slave).unwrap();
+        (serial, pty.master)
+    }
+
+    fn read_exact(fd: RawFd, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        let mut read = 0;
+        while read < len {
+            read += unistd::read(fd, &mut buf[read..]).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn send_recv() {
+        let (mut serial, master) = pty();
+        let req = Frame::Short {
+            control: frame::C_REQ_UD2,
+            address: 5,
+        };
+        serial.send(&req).unwrap();
+        assert_eq!(read_exact(master, 5), req.encode().unwrap());
+
+        let rsp = Frame::Long {
+            control: frame::C_RSP_UD,
+            address: 5,
+            ci: 0x72,
+            data: vec![0x78, 0x56, 0x34, 0x12, 0x24, 0x40, 0x01, 0x07],
+        };
+        unistd::write(master, &rsp.encode().unwrap()).unwrap();
+        assert_eq!(serial.recv().unwrap(), rsp);
+
+        unistd::write(master, &[frame::ACK]).unwrap();
+        assert_eq!(serial.recv().unwrap(), Frame::Ack);
+        unistd::close(master).unwrap();
+    }
+
+    #[test]
+    fn response_timeout() {
+        let (mut serial, master) = pty();
+        let start = Instant::now();
+        match serial.recv() {
+            Err(Error::Timeout) => (),
+            other => panic!("Expected timeout, got {:?}", other),
+        }
+        assert!(start.elapsed() >= serial.response_timeout);
+        unistd::close(master).unwrap();
+    }
+
+    #[test]
+    fn inter_byte_timeout() {
+        let (mut serial, master) = pty();
+        let bytes = Frame::Control {
+            control: frame::C_SND_UD,
+            address: 5,
+            ci: 0x50,
+        }
+        .encode()
+        .unwrap();
+        unistd::write(master, &bytes[..bytes.len() - 2]).unwrap();
+        let start = Instant::now();
+        match serial.recv() {
+            Err(Error::Frame(e)) => assert!(e.starts_with("Incomplete frame"), "{}", e),
+            other => panic!("Expected incomplete frame, got {:?}", other),
+        }
+        // Gave up once the line went quiet, not at the response timeout
+        assert!(start.elapsed() >= serial.inter_byte_timeout);
+        assert!(start.elapsed() < serial.response_timeout);
+        unistd::close(master).unwrap();
+    }
+}
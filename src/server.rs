@@ -52,7 +52,9 @@ pub async fn create(addr: &str, ssl: Option<SslAcceptorBuilder>) {
 
     let service = MakeAllowAllAuthenticator::new(service, "cosmo");
 
-    let mut service = mbus_api::server::context::MakeAddContext::<_, EmptyContext>::new(service);
+    let service = mbus_api::server::context::MakeAddContext::<_, EmptyContext>::new(service);
+
+    let mut service = MakeRouter::new(service);
 
     match ssl {
         Some(ssl) => {
@@ -111,7 +113,8 @@ use mbus_api::{
 use std::error::Error;
 use swagger::ApiError;
 
-mod http;
+use crate::http;
+use crate::router::MakeRouter;
 
 #[async_trait]
 impl<C> Api<C> for Server<C>
@@ -125,7 +128,7 @@ where
         address: String,
         _context: &C,
     ) -> Result<GetResponse, ApiError> {
-        Ok(http::get(&device, &baudrate, &address))
+        Ok(http::get(&device, &baudrate, &address).await)
     }
 
     async fn get_multi(
@@ -136,7 +139,7 @@ where
         maxframes: i32,
         _context: &C,
     ) -> Result<GetMultiResponse, ApiError> {
-        Ok(http::get_multi(&device, &baudrate, &address, &maxframes))
+        Ok(http::get_multi(&device, &baudrate, &address, &maxframes).await)
     }
 
     async fn hat(&self, _context: &C) -> Result<HatResponse, ApiError> {
@@ -161,6 +164,6 @@ where
         baudrate: models::Baudrate,
         _context: &C,
     ) -> Result<ScanResponse, ApiError> {
-        Ok(http::scan(&device, &baudrate))
+        Ok(http::scan(&device, &baudrate).await)
     }
 }
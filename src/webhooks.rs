@@ -0,0 +1,518 @@
+//
+//  mbus-httpd - An HTTP microservice exposing M-Bus Functionality
+//  Copyright (C) 2019-2020 packom.net
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+//! Webhooks - URLs which are sent a signed JSON POST when a meter is
+//! polled, a scan finds a slave has come or gone, a bus operation fails or
+//! the M-Bus Master Hat is switched on or off.  Webhooks are persisted as
+//! JSON in WEBHOOKS_FILE.  Failed deliveries are retried with exponential
+//! backoff, and then kept in a dead-letter list.
+
+use chrono::{SecondsFormat, Utc};
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request, Uri};
+use hyper_tls::HttpsConnector;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use serde_json::json;
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio::time;
+use uuid::Uuid;
+
+use crate::data::{self, DeadLetter, Meter, Webhook, WebhookEvent};
+use crate::http;
+
+/// A meter has been polled
+pub const EVENT_READING: &str = "reading";
+/// A scan found a slave the last scan didn't
+pub const EVENT_SLAVE_FOUND: &str = "slave_found";
+/// A scan didn't find a slave the last scan did
+pub const EVENT_SLAVE_MISSING: &str = "slave_missing";
+/// A bus operation failed
+pub const EVENT_BUS_ERROR: &str = "bus_error";
+/// The M-Bus Master Hat's power was switched on or off
+pub const EVENT_HAT_POWER: &str = "hat_power";
+
+const EVENTS: [&str; 5] = [
+    EVENT_READING,
+    EVENT_SLAVE_FOUND,
+    EVENT_SLAVE_MISSING,
+    EVENT_BUS_ERROR,
+    EVENT_HAT_POWER,
+];
+
+const HEADER_EVENT: &str = "X-MBus-Event";
+const HEADER_SIGNATURE: &str = "X-MBus-Signature";
+const HEADER_TIMESTAMP: &str = "X-MBus-Timestamp";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WebhookError {
+    /// The webhook is missing something, or has invalid settings
+    Invalid(String),
+    /// The webhooks couldn't be saved
+    Storage(String),
+}
+
+impl fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebhookError::Invalid(e) => write!(f, "{}", e),
+            WebhookError::Storage(e) => write!(f, "Failed to save webhooks: {}", e),
+        }
+    }
+}
+
+lazy_static! {
+    /// Registered webhooks, by ID
+    static ref WEBHOOKS: Mutex<BTreeMap<String, Webhook>> = Mutex::new(BTreeMap::new());
+    /// Deliveries waiting to be made, once started
+    static ref DELIVERIES: Mutex<Option<mpsc::UnboundedSender<(Webhook, WebhookEvent)>>> =
+        Mutex::new(None);
+    /// Deliveries which failed, oldest first
+    static ref DEAD_LETTERS: Mutex<VecDeque<DeadLetter>> = Mutex::new(VecDeque::new());
+    /// The slaves each scan last found, by device and scan, and then by
+    /// address
+    static ref SCANS: Mutex<HashMap<String, BTreeMap<String, data::ScanSlave>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn check(webhook: &Webhook) -> Result<(), String> {
+    let url = webhook
+        .url
+        .parse::<Uri>()
+        .map_err(|e| format!("Invalid URL {}: {}", webhook.url, e))?;
+    match url.scheme_str() {
+        Some("http") | Some("https") if url.host().is_some() => (),
+        _ => return Err(format!("URL must be http or https: {}", webhook.url)),
+    }
+    if webhook.secret.is_empty() {
+        return Err("No secret".to_string());
+    }
+    match webhook
+        .events
+        .iter()
+        .find(|e| !EVENTS.contains(&e.as_str()))
+    {
+        Some(event) => Err(format!(
+            "Invalid event {}, must be one of {}",
+            event,
+            EVENTS.join(", ")
+        )),
+        None => Ok(()),
+    }
+}
+
+fn save(webhooks: &BTreeMap<String, Webhook>) -> Result<(), WebhookError> {
+    let doc = data::MBusWebhooks {
+        webhooks: webhooks.values().cloned().collect(),
+    };
+    let json =
+        serde_json::to_string_pretty(&doc).map_err(|e| WebhookError::Storage(format!("{}", e)))?;
+
+    // Write a new file and move it into place, as the meter registry does
+    let path = Path::new(&*http::WEBHOOKS_FILE);
+    let tmp = path.with_extension("tmp");
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| WebhookError::Storage(format!("{}", e)))?;
+    }
+    fs::write(&tmp, json)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| WebhookError::Storage(format!("{}: {}", path.display(), e)))
+}
+
+/// Read the webhooks from WEBHOOKS_FILE, if there is one.  Keeps the
+/// webhooks already loaded if the file can't be read.
+pub fn load() -> Result<usize, String> {
+    let path = &*http::WEBHOOKS_FILE;
+    let json = match fs::read(path) {
+        Ok(json) => json,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+    };
+    let doc: data::MBusWebhooks =
+        serde_json::from_slice(&json).map_err(|e| format!("Failed to parse {}: {}", path, e))?;
+    let mut webhooks = BTreeMap::new();
+    for webhook in doc.webhooks {
+        check(&webhook).map_err(|e| format!("Invalid webhook in {}: {}", path, e))?;
+        webhooks.insert(webhook.id.clone(), webhook);
+    }
+    info!("Loaded {} webhooks from {}", webhooks.len(), path);
+    let count = webhooks.len();
+    *WEBHOOKS.lock().unwrap() = webhooks;
+    Ok(count)
+}
+
+/// Load the webhooks from WEBHOOKS_FILE, and start delivering events to
+/// them.  Fails if the file can't be read or contains invalid webhooks,
+/// rather than risk overwriting it.
+pub fn start() -> Result<(), String> {
+    load()?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    *DELIVERIES.lock().unwrap() = Some(tx);
+    tokio::spawn(deliveries(rx));
+    Ok(())
+}
+
+/// All the registered webhooks.
+pub fn list() -> Vec<Webhook> {
+    WEBHOOKS.lock().unwrap().values().cloned().collect()
+}
+
+pub fn get(id: &str) -> Option<Webhook> {
+    WEBHOOKS.lock().unwrap().get(id).cloned()
+}
+
+/// Register a new webhook, giving it an ID.
+pub fn create(mut webhook: Webhook) -> Result<Webhook, WebhookError> {
+    check(&webhook).map_err(WebhookError::Invalid)?;
+    webhook.id = Uuid::new_v4().to_string();
+    let mut webhooks = WEBHOOKS.lock().unwrap();
+    webhooks.insert(webhook.id.clone(), webhook.clone());
+    if let Err(e) = save(&webhooks) {
+        webhooks.remove(&webhook.id);
+        return Err(e);
+    }
+    info!("Webhook {} created for {}", webhook.id, webhook.url);
+    Ok(webhook)
+}
+
+/// Remove a webhook, returning it if it existed.
+pub fn delete(id: &str) -> Result<Option<Webhook>, WebhookError> {
+    let mut webhooks = WEBHOOKS.lock().unwrap();
+    let webhook = match webhooks.remove(id) {
+        Some(webhook) => webhook,
+        None => return Ok(None),
+    };
+    if let Err(e) = save(&webhooks) {
+        webhooks.insert(webhook.id.clone(), webhook);
+        return Err(e);
+    }
+    info!("Webhook {} deleted", id);
+    Ok(Some(webhook))
+}
+
+/// Deliveries which failed, oldest first.
+pub fn dead_letters() -> data::MBusDeadLetters {
+    data::MBusDeadLetters {
+        dead_letters: DEAD_LETTERS.lock().unwrap().iter().cloned().collect(),
+    }
+}
+
+/// Empty the dead-letter list, returning what was in it.
+pub fn clear_dead_letters() -> data::MBusDeadLetters {
+    data::MBusDeadLetters {
+        dead_letters: DEAD_LETTERS.lock().unwrap().drain(..).collect(),
+    }
+}
+
+// Send an event to each webhook which wants it
+fn emit(event: &str, data: serde_json::Value) {
+    let deliveries = DELIVERIES.lock().unwrap();
+    let deliveries = match *deliveries {
+        Some(ref deliveries) => deliveries,
+        None => return,
+    };
+    let event = WebhookEvent {
+        id: Uuid::new_v4().to_string(),
+        event: event.to_string(),
+        time: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        data,
+    };
+    for webhook in WEBHOOKS.lock().unwrap().values() {
+        if webhook.events.is_empty() || webhook.events.contains(&event.event) {
+            let _ = deliveries.send((webhook.clone(), event.clone()));
+        }
+    }
+}
+
+/// A meter has been polled.
+pub fn reading(meter: &Meter, reading: &data::MBusData) {
+    emit(
+        EVENT_READING,
+        json!({
+            "Meter": meter.name,
+            "Device": meter.device,
+            "Address": meter.address,
+            "Reading": reading,
+        }),
+    );
+}
+
+/// A bus operation on `device` failed.
+pub fn bus_error(device: &str, operation: &str, error: &str) {
+    emit(
+        EVENT_BUS_ERROR,
+        json!({
+            "Device": device,
+            "Operation": operation,
+            "Error": error,
+        }),
+    );
+}
+
+pub fn hat_power(on: bool) {
+    emit(
+        EVENT_HAT_POWER,
+        json!({ "Power": if on { "on" } else { "off" } }),
+    );
+}
+
+/// A scan of `device` has finished - a primary scan, or a secondary one if
+/// there is a mask.  Slaves which have come or gone since the last scan of
+/// the same kind are sent as events.
+pub fn scan(device: &str, mask: Option<&str>, scan: &data::MBusScan) {
+    for (event, slave) in scan_changes(device, mask, scan) {
+        emit(event, json!({ "Device": device, "Slave": slave }));
+    }
+}
+
+// Remember the slaves a scan found, returning those found or missing since
+// the last scan of the same kind, with their events
+fn scan_changes(
+    device: &str,
+    mask: Option<&str>,
+    scan: &data::MBusScan,
+) -> Vec<(&'static str, data::ScanSlave)> {
+    // Slaves found by primary scans are known by their primary address, and
+    // by secondary scans by their secondary address
+    let slaves = scan
+        .slaves
+        .iter()
+        .filter_map(|slave| {
+            let address = match mask {
+                Some(_) => slave.secondary_address.clone(),
+                None => slave.address.map(|a| a.to_string()),
+            };
+            address.map(|address| (address, slave.clone()))
+        })
+        .collect::<BTreeMap<_, _>>();
+    let key = format!("{} {}", device, mask.unwrap_or("primary"));
+    let last = match SCANS.lock().unwrap().insert(key, slaves.clone()) {
+        Some(last) => last,
+        // Nothing to compare the first scan with
+        None => return Vec::new(),
+    };
+    let found = slaves
+        .iter()
+        .filter(|(address, _)| !last.contains_key(*address))
+        .map(|(_, slave)| (EVENT_SLAVE_FOUND, slave.clone()));
+    let missing = last
+        .iter()
+        .filter(|(address, _)| !slaves.contains_key(*address))
+        .map(|(_, slave)| (EVENT_SLAVE_MISSING, slave.clone()));
+    found.chain(missing).collect()
+}
+
+// The HMAC-SHA256 of a payload sent at `timestamp`, in hex.  The timestamp
+// is signed too, so a captured delivery can't be replayed later with a new
+// one.
+fn sign(secret: &str, timestamp: i64, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+async fn deliveries(mut rx: mpsc::UnboundedReceiver<(Webhook, WebhookEvent)>) {
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    while let Some((webhook, event)) = rx.recv().await {
+        tokio::spawn(deliver(client.clone(), webhook, event));
+    }
+}
+
+// Post an event to a webhook, retrying with exponential backoff if it
+// fails, and adding it to the dead-letter list if it never succeeds
+async fn deliver(
+    client: Client<HttpsConnector<HttpConnector>>,
+    webhook: Webhook,
+    event: WebhookEvent,
+) {
+    let payload = match data::to_json(&event) {
+        Ok(payload) => payload,
+        Err(e) => {
+            warn!(
+                "Failed to deliver {} to {}: {}",
+                event.event, webhook.url, e
+            );
+            return;
+        }
+    };
+    let mut delay = http::WEBHOOK_RETRY_DELAY.get();
+    let mut attempts = 0;
+    let error = loop {
+        attempts += 1;
+        // Each attempt is signed afresh, so retries aren't taken for replays
+        let timestamp = Utc::now().timestamp();
+        let signature = format!("sha256={}", sign(&webhook.secret, timestamp, &payload));
+        let req = Request::post(&webhook.url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(HEADER_EVENT, &event.event)
+            .header(HEADER_TIMESTAMP, timestamp)
+            .header(HEADER_SIGNATURE, &signature)
+            .body(Body::from(payload.clone()));
+        let req = match req {
+            Ok(req) => req,
+            Err(e) => break e.to_string(),
+        };
+        let error = match time::timeout(http::WEBHOOK_TIMEOUT.get(), client.request(req)).await {
+            Ok(Ok(rsp)) if rsp.status().is_success() => {
+                debug!("Delivered {} {} to {}", event.event, event.id, webhook.url);
+                return;
+            }
+            Ok(Ok(rsp)) => format!("Webhook returned {}", rsp.status()),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => format!("Timed out after {}s", http::WEBHOOK_TIMEOUT.get().as_secs()),
+        };
+        if attempts > http::WEBHOOK_RETRIES.get() {
+            break error;
+        }
+        info!(
+            "Delivery of {} to {} failed, retrying in {}s: {}",
+            event.event,
+            webhook.url,
+            delay.as_secs(),
+            error
+        );
+        time::delay_for(delay).await;
+        delay *= 2;
+    };
+
+    warn!(
+        "Failed to deliver {} {} to {} after {} attempts: {}",
+        event.event, event.id, webhook.url, attempts, error
+    );
+    let mut dead_letters = DEAD_LETTERS.lock().unwrap();
+    dead_letters.push_back(DeadLetter {
+        webhook: webhook.id,
+        url: webhook.url,
+        attempts,
+        error,
+        failed: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        payload: event,
+    });
+    while dead_letters.len() > http::WEBHOOK_DEAD_LETTERS.get() {
+        dead_letters.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhook(url: &str, secret: &str, events: &[&str]) -> Webhook {
+        Webhook {
+            url: url.to_string(),
+            secret: secret.to_string(),
+            events: events.iter().map(|e| e.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn slave(address: u8, secondary_address: &str) -> data::ScanSlave {
+        data::ScanSlave {
+            address: Some(address),
+            secondary_address: Some(secondary_address.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn scan(slaves: Vec<data::ScanSlave>) -> data::MBusScan {
+        data::MBusScan { slaves }
+    }
+
+    #[test]
+    fn signature() {
+        assert_eq!(
+            sign("It's a Secret to Everybody", 1700000000, "Hello, World!"),
+            "76c83fd0acdf22faed320674fe8e04d528cfe8a17905e720a9611e40677c03b7"
+        );
+        assert_eq!(
+            sign("s3cret", 1600000000, r#"{"Event":"hat_power"}"#),
+            "1ebb4b11649afaccbf4449d83b7c307916abc3cd7175ca69d51a207d8db674ea"
+        );
+        assert_ne!(
+            sign("s3cret", 1600000001, r#"{"Event":"hat_power"}"#),
+            sign("s3cret", 1600000000, r#"{"Event":"hat_power"}"#)
+        );
+    }
+
+    #[test]
+    fn checks() {
+        assert!(check(&webhook("http://example.com/hook", "s", &[])).is_ok());
+        assert!(check(&webhook("https://example.com/hook", "s", &EVENTS)).is_ok());
+        assert!(check(&webhook("ftp://example.com/hook", "s", &[])).is_err());
+        assert!(check(&webhook("/hook", "s", &[])).is_err());
+        assert!(check(&webhook("not a url", "s", &[])).is_err());
+        assert!(check(&webhook("http://example.com/hook", "", &[])).is_err());
+        assert!(check(&webhook(
+            "http://example.com/hook",
+            "s",
+            &["reading", "boot"]
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn primary_scan_changes() {
+        let (a, b, c) = (
+            slave(1, "1111111124400107"),
+            slave(2, "2222222224400107"),
+            slave(3, "3333333324400107"),
+        );
+        // The first scan has nothing to compare with
+        assert!(scan_changes("test-primary", None, &scan(vec![a.clone(), b.clone()])).is_empty());
+        assert!(scan_changes("test-primary", None, &scan(vec![a.clone(), b.clone()])).is_empty());
+        assert_eq!(
+            scan_changes("test-primary", None, &scan(vec![a.clone(), c.clone()])),
+            vec![(EVENT_SLAVE_FOUND, c), (EVENT_SLAVE_MISSING, b)]
+        );
+
+        // A slave at the same primary address is the same slave
+        let replaced = slave(1, "9999999924400107");
+        assert!(scan_changes("test-primary", None, &scan(vec![replaced, slave(3, "")])).is_empty());
+    }
+
+    #[test]
+    fn secondary_scan_changes() {
+        let mask = Some("FFFFFFFFFFFFFFFF");
+        let (a, b) = (slave(1, "1111111124400107"), slave(2, "2222222224400107"));
+        assert!(scan_changes("test-secondary", mask, &scan(vec![a])).is_empty());
+
+        // Slaves are known by secondary address, whatever their primary one
+        let moved = slave(5, "1111111124400107");
+        assert!(scan_changes("test-secondary", mask, &scan(vec![moved.clone()])).is_empty());
+        assert_eq!(
+            scan_changes("test-secondary", mask, &scan(vec![moved, b.clone()])),
+            vec![(EVENT_SLAVE_FOUND, b)]
+        );
+
+        // Primary and secondary scans, and scans with different masks, are
+        // compared separately
+        assert!(scan_changes("test-secondary", None, &scan(vec![])).is_empty());
+        assert!(scan_changes("test-secondary", Some("12FFFFFFFFFFFFFF"), &scan(vec![])).is_empty());
+        assert_eq!(scan_changes("test-secondary", mask, &scan(vec![])).len(), 2);
+    }
+}